@@ -1,4 +1,4 @@
-use base64ct::{Base64Unpadded, Encoding};
+use base64ct::{Base64, Base64Unpadded, Encoding};
 
 pub(crate) fn decode_base64(input: &str) -> crate::result::Result<Vec<u8>> {
     // Apple sometimes uses padded Base64 (e.g. Music App on iOS)
@@ -16,3 +16,7 @@ pub(crate) fn decode_base64(input: &str) -> crate::result::Result<Vec<u8>> {
 pub(crate) fn encode_base64(input: &[u8]) -> String {
     Base64Unpadded::encode_string(input)
 }
+
+pub(crate) fn encode_base64_padded(input: &[u8]) -> String {
+    Base64::encode_string(input)
+}
@@ -0,0 +1,1322 @@
+use crate::{
+    device_id::DeviceId,
+    hooks::HookConfig,
+    logging::{LogConfig, LogFormat, LogHandle},
+    mqtt::MqttConfig,
+    network::{NetworkConfig, PortRange},
+    output::SampleFormat,
+    player::{self, ChannelMode, Filter, VolumePolicy},
+    CliOpts, Configuration,
+};
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+use tracing_subscriber::EnvFilter;
+
+/// Contents of the configuration file, every setting is optional.
+///
+/// ```toml
+/// name = "Living Room"
+/// password = "secret"
+/// device_id = "interface:eth0"
+/// state_dir = "/var/lib/airguitar"
+/// port = 5000
+/// metadata_pipe = "/tmp/shairport-sync-metadata"
+/// mpris = true
+/// now_playing_file = "/run/user/1000/airguitar.json"
+/// stats_interval = 60
+///
+/// [output]
+/// device = "default"
+/// sample_rate = 48000
+/// format = "s16"
+/// channel_map = "stereo"
+/// channels = 2
+/// filters = ["type=lowshelf,freq=100,gain=3"]
+///
+/// [playback]
+/// latency = 11025
+/// latency_offset = -50
+/// max_buffered_packets = 1024
+///
+/// [volume]
+/// max = 0.8
+/// ignore_sender = false
+///
+/// [network]
+/// interfaces = ["eth0"]
+/// addresses = ["192.168.1.10", "fd00::10"]
+/// udp_ports = "6000-6009"
+///
+/// [mdns]
+/// enabled = true
+///
+/// [mdns.txt]
+/// am = "Airguitar"
+///
+/// [http]
+/// address = "127.0.0.1:8080"
+///
+/// [mqtt]
+/// host = "localhost"
+/// port = 1883
+/// username = "airguitar"
+/// password = "secret"
+/// topic = "airguitar"
+///
+/// [hooks]
+/// session_start = "amp on"
+/// playback_start = "..."
+/// flush = "..."
+/// session_end = "amp off"
+/// volume_change = "..."
+/// timeout = 10
+/// wait_for_playback_start = false
+///
+/// [log]
+/// format = "json"
+/// level = "info"
+/// file = "/var/log/airguitar.log"
+///
+/// [[receivers]]
+/// name = "Kitchen"
+/// port = 5001
+/// device_id = "random"
+/// state_key = "kitchen"
+/// metadata_pipe = "/tmp/kitchen-metadata"
+/// mpris = true
+/// now_playing_file = "/run/user/1000/kitchen.json"
+///
+/// [receivers.output]
+/// device = "hw:1"
+///
+/// [receivers.volume]
+/// max = 0.5
+///
+/// [receivers.http]
+/// address = "127.0.0.1:8081"
+///
+/// [receivers.mqtt]
+/// topic = "airguitar/kitchen"
+/// ```
+///
+/// See `CliOpts` for the meaning and the defaults of the settings.
+///
+/// Each entry of `receivers` is a player of its own, hosted in the same
+/// process. Its name, port, device id, output, volume policy, HTTP API, MQTT,
+/// MPRIS, metadata pipe and now playing file settings take precedence over the
+/// settings above, which apply to all receivers. Like everywhere else, the
+/// command line takes precedence over the file. The port defaults to the next
+/// one after the first receiver. Receivers must not share a name, port, device
+/// id, HTTP API address, MQTT topic, metadata pipe or now playing file, so
+/// `--name`, `--port`, `--device-id`, `--http-address`, `--mqtt-topic`,
+/// `--metadata-pipe` and `--now-playing-file` only work with a single receiver.
+///
+/// Generated device ids of the `receivers` are stored under their `state_key`,
+/// which defaults to their name. Setting it keeps the id when renaming one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    name: Option<String>,
+    password: Option<String>,
+    device_id: Option<String>,
+    state_dir: Option<PathBuf>,
+    port: Option<u16>,
+    metadata_pipe: Option<PathBuf>,
+    mpris: Option<bool>,
+    now_playing_file: Option<PathBuf>,
+    stats_interval: Option<u64>,
+    output: OutputSection,
+    playback: PlaybackSection,
+    volume: VolumeSection,
+    network: NetworkSection,
+    mdns: MdnsSection,
+    http: HttpSection,
+    mqtt: MqttSection,
+    hooks: HooksSection,
+    log: LogSection,
+    receivers: Vec<ReceiverSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct OutputSection {
+    device: Option<String>,
+    sample_rate: Option<u32>,
+    format: Option<String>,
+    channel_map: Option<String>,
+    channels: Option<u16>,
+    filters: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PlaybackSection {
+    latency: Option<u32>,
+    latency_offset: Option<i32>,
+    max_buffered_packets: Option<u16>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct VolumeSection {
+    max: Option<f32>,
+    ignore_sender: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct NetworkSection {
+    interfaces: Vec<String>,
+    addresses: Vec<IpAddr>,
+    udp_ports: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MdnsSection {
+    enabled: Option<bool>,
+    txt: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HttpSection {
+    address: Option<SocketAddr>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MqttSection {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+    topic: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HooksSection {
+    session_start: Option<String>,
+    playback_start: Option<String>,
+    flush: Option<String>,
+    session_end: Option<String>,
+    volume_change: Option<String>,
+    timeout: Option<u64>,
+    wait_for_playback_start: Option<bool>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    format: Option<String>,
+    level: Option<String>,
+    file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ReceiverSection {
+    name: Option<String>,
+    port: Option<u16>,
+    device_id: Option<String>,
+    state_key: Option<String>,
+    metadata_pipe: Option<PathBuf>,
+    mpris: Option<bool>,
+    now_playing_file: Option<PathBuf>,
+    output: OutputSection,
+    volume: VolumeSection,
+    http: HttpSection,
+    mqtt: MqttSection,
+}
+
+/// Values of the file after validating the ones `serde` could not.
+#[derive(Debug, Default)]
+struct Validated {
+    device_id: Option<DeviceId>,
+    sample_format: Option<SampleFormat>,
+    channel_mode: Option<ChannelMode>,
+    filters: Vec<Filter>,
+    udp_ports: Option<PortRange>,
+    log_format: Option<LogFormat>,
+    receivers: Vec<ValidatedReceiver>,
+}
+
+/// Values of a `receivers` entry after validating them.
+#[derive(Debug)]
+struct ValidatedReceiver {
+    name: String,
+    device_id: Option<DeviceId>,
+    state_key: Option<String>,
+    sample_format: Option<SampleFormat>,
+    channel_mode: Option<ChannelMode>,
+    filters: Vec<Filter>,
+}
+
+impl Configuration {
+    /// Builds the configuration from the command line options, falling back to
+    /// the configuration file and the defaults.
+    ///
+    /// The file given by `--config` has to exist, otherwise the first existing
+    /// file of the search paths is used, if any.
+    ///
+    /// Returns a configuration per receiver, at least one.
+    pub(crate) fn load(cli: &CliOpts) -> crate::result::Result<Vec<Configuration>> {
+        let path = match cli.config {
+            Some(ref path) => Some(path.clone()),
+            None => search_paths().into_iter().find(|path| path.is_file()),
+        };
+        let (file, validated) = match path {
+            Some(ref path) => {
+                let contents = fs::read_to_string(path)
+                    .map_err(|err| format!("failed to read {}: {}", path.display(), err))?;
+                let file: FileConfig = toml::from_str(&contents)
+                    .map_err(|err| format!("{}: {}", path.display(), err))?;
+                let validated = file
+                    .validate()
+                    .map_err(|err| format!("{}: {}", path.display(), err))?;
+                (file, validated)
+            }
+            None => Default::default(),
+        };
+
+        let name = cli
+            .name
+            .clone()
+            .or(file.name)
+            .unwrap_or_else(|| "Airguitar".into());
+        let device_id = cli
+            .device_id
+            .clone()
+            .or(validated.device_id)
+            .unwrap_or(DeviceId::Name);
+        let state_dir = cli.state_dir.clone().or(file.state_dir);
+
+        let mqtt_host = cli.mqtt_host.clone().or(file.mqtt.host);
+        let filters = match cli.filter.is_empty() {
+            true => validated.filters,
+            false => cli.filter.clone(),
+        };
+        let network = NetworkConfig {
+            interfaces: match cli.interface.is_empty() {
+                true => file.network.interfaces,
+                false => cli.interface.clone(),
+            },
+            addresses: match cli.listen_address.is_empty() {
+                true => file.network.addresses,
+                false => cli.listen_address.clone(),
+            },
+        };
+
+        let mut base = Configuration {
+            config_file: path,
+            port: cli.port.or(file.port).unwrap_or(5000),
+            network,
+            udp_ports: cli.udp_ports.or(validated.udp_ports),
+            name,
+            // Resolved for each receiver below.
+            hw_addr: [0; 6],
+            device: cli.device.clone().or(file.output.device),
+            sample_rate: cli.sample_rate.or(file.output.sample_rate),
+            sample_format: cli.format.or(validated.sample_format),
+            channel_mode: cli
+                .channel_map
+                .or(validated.channel_mode)
+                .unwrap_or_default(),
+            output_channels: cli.output_channels.or(file.output.channels),
+            filters,
+            latency: cli.latency.or(file.playback.latency).unwrap_or(11025),
+            latency_offset: cli
+                .latency_offset
+                .or(file.playback.latency_offset)
+                .unwrap_or(0),
+            max_buffered_packets: cli
+                .max_buffered_packets
+                .or(file.playback.max_buffered_packets)
+                .unwrap_or(1024),
+            volume: VolumePolicy {
+                max: cli.max_volume.or(file.volume.max).unwrap_or(1.0),
+                ignore_sender: cli
+                    .ignore_sender_volume
+                    .or(file.volume.ignore_sender)
+                    .unwrap_or(false),
+            },
+            password: cli
+                .password
+                .clone()
+                .or(file.password)
+                .filter(|password| !password.is_empty()),
+            mdns: cli
+                .no_mdns
+                .map(|no_mdns| !no_mdns)
+                .or(file.mdns.enabled)
+                .unwrap_or(true),
+            txt_records: file.mdns.txt,
+            http_address: cli.http_address.or(file.http.address),
+            metadata_pipe: cli.metadata_pipe.clone().or(file.metadata_pipe),
+            mpris: cli.mpris.or(file.mpris).unwrap_or(false),
+            now_playing_file: cli.now_playing_file.clone().or(file.now_playing_file),
+            mqtt: mqtt_host.map(|host| MqttConfig {
+                host,
+                port: cli.mqtt_port.or(file.mqtt.port).unwrap_or(1883),
+                username: cli.mqtt_username.clone().or(file.mqtt.username),
+                password: cli.mqtt_password.clone().or(file.mqtt.password),
+                topic: cli
+                    .mqtt_topic
+                    .clone()
+                    .or(file.mqtt.topic)
+                    .unwrap_or_else(|| "airguitar".into()),
+            }),
+            hooks: HookConfig {
+                session_start: cli.on_session_start.clone().or(file.hooks.session_start),
+                playback_start: cli.on_playback_start.clone().or(file.hooks.playback_start),
+                flush: cli.on_flush.clone().or(file.hooks.flush),
+                session_end: cli.on_session_end.clone().or(file.hooks.session_end),
+                volume_change: cli.on_volume_change.clone().or(file.hooks.volume_change),
+                timeout: Duration::from_secs(cli.hook_timeout.or(file.hooks.timeout).unwrap_or(10)),
+                wait_for_playback_start: cli
+                    .wait_for_playback_start_hook
+                    .or(file.hooks.wait_for_playback_start)
+                    .unwrap_or(false),
+            },
+            stats_interval: cli
+                .stats_interval
+                .or(file.stats_interval)
+                .map(Duration::from_secs),
+            log: LogConfig {
+                format: cli.log_format.or(validated.log_format).unwrap_or_default(),
+                level: cli.log_level.clone().or(file.log.level),
+                file: cli.log_file.clone().or(file.log.file),
+                stats: cli.stats_interval.or(file.stats_interval).is_some(),
+            },
+        };
+
+        if file.receivers.is_empty() {
+            check_filter_channels("output.filters", &base)?;
+            base.hw_addr = device_id.resolve(&base.name, state_dir, None)?;
+            return Ok(vec![base]);
+        }
+
+        let path = base.config_file.clone().unwrap_or_default();
+        if file.receivers.len() > 1 {
+            // Each receiver needs its own.
+            for (option, used) in [
+                ("--name", cli.name.is_some()),
+                ("--port", cli.port.is_some()),
+                ("--device-id", cli.device_id.is_some()),
+                ("--http-address", cli.http_address.is_some()),
+                ("--mqtt-topic", cli.mqtt_topic.is_some()),
+                ("--metadata-pipe", cli.metadata_pipe.is_some()),
+                ("--now-playing-file", cli.now_playing_file.is_some()),
+            ] {
+                if used {
+                    return Err(format!(
+                        "`{}` can only be used with a single receiver, set it in the `receivers` of {}",
+                        option,
+                        path.display()
+                    )
+                    .into());
+                }
+            }
+        }
+
+        let receivers = file
+            .receivers
+            .into_iter()
+            .zip(validated.receivers)
+            .enumerate()
+            .map(|(index, (receiver, validated))| {
+                base.receiver(
+                    cli,
+                    index,
+                    receiver,
+                    validated,
+                    &device_id,
+                    state_dir.clone(),
+                )
+            })
+            .collect::<crate::result::Result<Vec<_>>>()
+            .map_err(|err| format!("{}: {}", path.display(), err))?;
+        check_distinct(&receivers).map_err(|err| format!("{}: {}", path.display(), err))?;
+
+        Ok(receivers)
+    }
+
+    /// Configuration of the receiver at `index` of the `receivers` entries,
+    /// its own settings take precedence over the ones of `self` from the file,
+    /// the command line over both.
+    fn receiver(
+        &self,
+        cli: &CliOpts,
+        index: usize,
+        receiver: ReceiverSection,
+        validated: ValidatedReceiver,
+        device_id: &DeviceId,
+        state_dir: Option<PathBuf>,
+    ) -> crate::result::Result<Configuration> {
+        let port = match cli.port.or(receiver.port) {
+            Some(port) => port,
+            // Every receiver gets a random port.
+            None if self.port == 0 => 0,
+            None => u16::try_from(index)
+                .ok()
+                .and_then(|index| self.port.checked_add(index))
+                .ok_or_else(|| invalid(&format!("receivers[{}].port", index), "must be set"))?,
+        };
+        // Filters of the command line are the ones of `self` already.
+        let filters = match cli.filter.is_empty() && !receiver.output.filters.is_empty() {
+            true => validated.filters,
+            false => self.filters.clone(),
+        };
+        // Ids stay with the receiver when the list is reordered.
+        let state_key = validated
+            .state_key
+            .unwrap_or_else(|| validated.name.clone());
+        let name = cli.name.clone().unwrap_or(validated.name);
+        let device_id = cli
+            .device_id
+            .as_ref()
+            .or(validated.device_id.as_ref())
+            .unwrap_or(device_id);
+        let hw_addr = device_id.resolve(&name, state_dir, Some(&state_key))?;
+        // The broker of `self` is shared unless the receiver names its own.
+        let mqtt = self.mqtt.as_ref();
+        let mqtt = cli
+            .mqtt_host
+            .clone()
+            .or(receiver.mqtt.host)
+            .or_else(|| mqtt.map(|mqtt| mqtt.host.clone()))
+            .map(|host| MqttConfig {
+                host,
+                port: cli
+                    .mqtt_port
+                    .or(receiver.mqtt.port)
+                    .or(mqtt.map(|mqtt| mqtt.port))
+                    .unwrap_or(1883),
+                username: cli
+                    .mqtt_username
+                    .clone()
+                    .or(receiver.mqtt.username)
+                    .or_else(|| mqtt.and_then(|mqtt| mqtt.username.clone())),
+                password: cli
+                    .mqtt_password
+                    .clone()
+                    .or(receiver.mqtt.password)
+                    .or_else(|| mqtt.and_then(|mqtt| mqtt.password.clone())),
+                topic: cli
+                    .mqtt_topic
+                    .clone()
+                    .or(receiver.mqtt.topic)
+                    .or_else(|| mqtt.map(|mqtt| mqtt.topic.clone()))
+                    .unwrap_or_else(|| "airguitar".into()),
+            });
+
+        let config = Configuration {
+            port,
+            name,
+            hw_addr,
+            device: cli
+                .device
+                .clone()
+                .or(receiver.output.device)
+                .or_else(|| self.device.clone()),
+            sample_rate: cli
+                .sample_rate
+                .or(receiver.output.sample_rate)
+                .or(self.sample_rate),
+            sample_format: cli
+                .format
+                .or(validated.sample_format)
+                .or(self.sample_format),
+            channel_mode: cli
+                .channel_map
+                .or(validated.channel_mode)
+                .unwrap_or(self.channel_mode),
+            output_channels: cli
+                .output_channels
+                .or(receiver.output.channels)
+                .or(self.output_channels),
+            filters,
+            volume: VolumePolicy {
+                max: cli
+                    .max_volume
+                    .or(receiver.volume.max)
+                    .unwrap_or(self.volume.max),
+                ignore_sender: cli
+                    .ignore_sender_volume
+                    .or(receiver.volume.ignore_sender)
+                    .unwrap_or(self.volume.ignore_sender),
+            },
+            http_address: cli
+                .http_address
+                .or(receiver.http.address)
+                .or(self.http_address),
+            metadata_pipe: cli
+                .metadata_pipe
+                .clone()
+                .or(receiver.metadata_pipe)
+                .or_else(|| self.metadata_pipe.clone()),
+            mpris: cli.mpris.or(receiver.mpris).unwrap_or(self.mpris),
+            now_playing_file: cli
+                .now_playing_file
+                .clone()
+                .or(receiver.now_playing_file)
+                .or_else(|| self.now_playing_file.clone()),
+            mqtt,
+            ..self.clone()
+        };
+        check_filter_channels(&format!("receivers[{}].output.filters", index), &config)?;
+        Ok(config)
+    }
+
+    /// Takes the settings of a reloaded configuration which can change while
+    /// running.
+    ///
+    /// Returns the keys of the settings which changed but require a restart,
+    /// these keep their current values.
+    fn reloaded(&self, mut new: Configuration) -> (Configuration, Vec<&'static str>) {
+        let mut restart = Vec::new();
+        let mut keep = |key, changed| {
+            if changed {
+                restart.push(key);
+            }
+            changed
+        };
+
+        if keep("port", new.port != self.port) {
+            new.port = self.port;
+        }
+        if keep("device_id", new.hw_addr != self.hw_addr) {
+            new.hw_addr = self.hw_addr;
+        }
+        if keep(
+            "network.interfaces",
+            new.network.interfaces != self.network.interfaces,
+        ) {
+            new.network.interfaces = self.network.interfaces.clone();
+        }
+        if keep(
+            "network.addresses",
+            new.network.addresses != self.network.addresses,
+        ) {
+            new.network.addresses = self.network.addresses.clone();
+        }
+        if keep("mdns.enabled", new.mdns != self.mdns) {
+            new.mdns = self.mdns;
+        }
+        if keep("output.device", new.device != self.device) {
+            new.device = self.device.clone();
+        }
+        if keep("output.sample_rate", new.sample_rate != self.sample_rate) {
+            new.sample_rate = self.sample_rate;
+        }
+        if keep("output.format", new.sample_format != self.sample_format) {
+            new.sample_format = self.sample_format;
+        }
+        if keep("output.channel_map", new.channel_mode != self.channel_mode) {
+            new.channel_mode = self.channel_mode;
+        }
+        if keep(
+            "output.channels",
+            new.output_channels != self.output_channels,
+        ) {
+            new.output_channels = self.output_channels;
+        }
+        if keep("output.filters", new.filters != self.filters) {
+            new.filters = self.filters.clone();
+        }
+        if keep("http.address", new.http_address != self.http_address) {
+            new.http_address = self.http_address;
+        }
+        if keep("metadata_pipe", new.metadata_pipe != self.metadata_pipe) {
+            new.metadata_pipe = self.metadata_pipe.clone();
+        }
+        if keep("mpris", new.mpris != self.mpris) {
+            new.mpris = self.mpris;
+        }
+        if keep(
+            "now_playing_file",
+            new.now_playing_file != self.now_playing_file,
+        ) {
+            new.now_playing_file = self.now_playing_file.clone();
+        }
+        if keep("mqtt", new.mqtt != self.mqtt) {
+            new.mqtt = self.mqtt.clone();
+        }
+        if keep("stats_interval", new.stats_interval != self.stats_interval) {
+            new.stats_interval = self.stats_interval;
+        }
+        if keep("log.format", new.log.format != self.log.format) {
+            new.log.format = self.log.format;
+        }
+        if keep("log.file", new.log.file != self.log.file) {
+            new.log.file = self.log.file.clone();
+        }
+
+        (new, restart)
+    }
+}
+
+/// Reloads the configuration whenever the process receives `SIGHUP`.
+///
+/// The name and TXT records of the mDNS service, the hooks, the log level, the
+/// latency, the latency offset, the buffer size and the volume policy change
+/// immediately. RTSP connections keep the configuration they started with.
+/// Changes of other settings, including adding or removing receivers, are
+/// reported and ignored until restarting. Receivers are recognized by their
+/// name, or else by their port.
+#[cfg(unix)]
+pub(crate) async fn reload_on_hangup(
+    cli: CliOpts,
+    config_txs: Vec<watch::Sender<Arc<Configuration>>>,
+    log: LogHandle,
+) -> crate::result::Result<()> {
+    use std::collections::BTreeSet;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        info!("reloading configuration");
+        let receivers = match Configuration::load(&cli) {
+            Ok(receivers) => receivers,
+            Err(err) => {
+                error!(cause = %err, "failed to reload configuration, keeping the current one");
+                continue;
+            }
+        };
+
+        let current = config_txs
+            .iter()
+            .map(|config_tx| config_tx.borrow().clone())
+            .collect::<Vec<_>>();
+        let matches = match_receivers(&current, &receivers);
+        for (index, receiver) in current.iter().enumerate() {
+            if !matches.contains(&Some(index)) {
+                warn!(receiver = %receiver.name, "removing a receiver requires a restart");
+            }
+        }
+
+        let mut restart = BTreeSet::new();
+        for (new, index) in receivers.into_iter().zip(matches) {
+            let index = match index {
+                Some(index) => index,
+                None => {
+                    warn!(receiver = %new.name, "adding a receiver requires a restart");
+                    continue;
+                }
+            };
+            let (new, keys) = current[index].reloaded(new);
+            restart.extend(keys);
+            // All receivers share the log of the first one.
+            if index == 0 {
+                if let Err(err) = log.reload(&new.log) {
+                    error!(cause = %err, "failed to apply log level");
+                }
+            }
+
+            config_txs[index].send_replace(Arc::new(new));
+        }
+        for key in restart {
+            warn!(setting = key, "changing the setting requires a restart");
+        }
+    }
+
+    Ok(())
+}
+
+/// Pairs the reloaded receivers with the running ones by their name, or else
+/// by their port. A single receiver is always paired with the running one.
+///
+/// Returns the index within `current` for each of `new`, `None` if it is added.
+fn match_receivers(current: &[Arc<Configuration>], new: &[Configuration]) -> Vec<Option<usize>> {
+    if current.len() == 1 && new.len() == 1 {
+        return vec![Some(0)];
+    }
+
+    let mut matches = vec![None; new.len()];
+    let same: [fn(&Configuration, &Configuration) -> bool; 2] = [
+        |current, new| current.name == new.name,
+        |current, new| current.port == new.port && current.port != 0,
+    ];
+    for same in same {
+        for (i, new) in new.iter().enumerate() {
+            if matches[i].is_some() {
+                continue;
+            }
+            matches[i] = current
+                .iter()
+                .enumerate()
+                .position(|(index, current)| !matches.contains(&Some(index)) && same(current, new));
+        }
+    }
+
+    matches
+}
+
+impl FileConfig {
+    /// Checks the values which are not rejected while parsing already.
+    ///
+    /// Errors name the offending key.
+    fn validate(&self) -> crate::result::Result<Validated> {
+        if let Some(ref name) = self.name {
+            if name.is_empty() {
+                return Err(invalid("name", "must not be empty"));
+            }
+        }
+        for (key, value) in [
+            ("stats_interval", self.stats_interval),
+            ("output.channels", self.output.channels.map(u64::from)),
+            ("playback.latency", self.playback.latency.map(u64::from)),
+            (
+                "playback.max_buffered_packets",
+                self.playback.max_buffered_packets.map(u64::from),
+            ),
+            ("hooks.timeout", self.hooks.timeout),
+        ] {
+            if value == Some(0) {
+                return Err(invalid(key, "must be at least 1"));
+            }
+        }
+        check_volume("volume.max", self.volume.max)?;
+        for (key, value) in &self.mdns.txt {
+            if key.is_empty() || key.contains('=') {
+                return Err(invalid(
+                    &format!("mdns.txt.{}", key),
+                    "must not be empty or contain `=`",
+                ));
+            }
+            // Every record is prefixed with its length as a single byte.
+            if key.len() + value.len() + 1 > 255 {
+                return Err(invalid(&format!("mdns.txt.{}", key), "is too long"));
+            }
+        }
+        if let Some(ref level) = self.log.level {
+            EnvFilter::try_new(level).map_err(|err| invalid("log.level", err))?;
+        }
+
+        let filters = parse_filters("output.filters", &self.output.filters)?;
+        let udp_ports = self
+            .network
+            .udp_ports
+            .as_ref()
+            .map(|ports| ports.parse())
+            .transpose()
+            .map_err(|err| invalid("network.udp_ports", err))?;
+
+        let device_id = self
+            .device_id
+            .as_ref()
+            .map(|device_id| device_id.parse())
+            .transpose()
+            .map_err(|err| invalid("device_id", err))?;
+
+        let receivers = self
+            .receivers
+            .iter()
+            .enumerate()
+            .map(|(index, receiver)| receiver.validate(&format!("receivers[{}]", index)))
+            .collect::<crate::result::Result<_>>()?;
+
+        Ok(Validated {
+            device_id,
+            sample_format: value_enum("output.format", &self.output.format)?,
+            channel_mode: value_enum("output.channel_map", &self.output.channel_map)?,
+            filters,
+            udp_ports,
+            log_format: value_enum("log.format", &self.log.format)?,
+            receivers,
+        })
+    }
+}
+
+impl ReceiverSection {
+    /// Checks the values of the entry, errors name the offending key starting
+    /// with `prefix` (e.g. `receivers[1]`).
+    fn validate(&self, prefix: &str) -> crate::result::Result<ValidatedReceiver> {
+        let key = |key| format!("{}.{}", prefix, key);
+
+        let name = match self.name {
+            Some(ref name) if !name.is_empty() => name.clone(),
+            _ => return Err(invalid(&key("name"), "must be set")),
+        };
+        if self.output.channels == Some(0) {
+            return Err(invalid(&key("output.channels"), "must be at least 1"));
+        }
+        check_volume(&key("volume.max"), self.volume.max)?;
+        let device_id = self
+            .device_id
+            .as_ref()
+            .map(|device_id| device_id.parse())
+            .transpose()
+            .map_err(|err| invalid(&key("device_id"), err))?;
+        if self.state_key.as_deref() == Some("") {
+            return Err(invalid(&key("state_key"), "must not be empty"));
+        }
+
+        Ok(ValidatedReceiver {
+            name,
+            device_id,
+            state_key: self.state_key.clone(),
+            sample_format: value_enum(&key("output.format"), &self.output.format)?,
+            channel_mode: value_enum(&key("output.channel_map"), &self.output.channel_map)?,
+            filters: parse_filters(&key("output.filters"), &self.output.filters)?,
+        })
+    }
+}
+
+fn check_volume(key: &str, max: Option<f32>) -> crate::result::Result<()> {
+    match max {
+        Some(max) if !(0.0..=1.0).contains(&max) => {
+            Err(invalid(key, "must be between 0.0 and 1.0"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn parse_filters(key: &str, filters: &[String]) -> crate::result::Result<Vec<Filter>> {
+    filters
+        .iter()
+        .enumerate()
+        .map(|(i, filter)| {
+            filter
+                .parse()
+                .map_err(|err| invalid(&format!("{}[{}]", key, i), err))
+        })
+        .collect()
+}
+
+/// Checks that the filters of `config` only select existing output channels.
+fn check_filter_channels(key: &str, config: &Configuration) -> crate::result::Result<()> {
+    let channels = config.output_channels.unwrap_or(player::CHANNELS);
+    for (i, filter) in config.filters.iter().enumerate() {
+        match filter.channel {
+            Some(channel) if channel >= channels => {
+                return Err(invalid(
+                    &format!("{}[{}]", key, i),
+                    format!(
+                        "channel {} does not exist, the output has {} channels",
+                        channel, channels
+                    ),
+                ));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the receivers do not share a name, a port, a device id or
+/// where they publish their state.
+fn check_distinct(receivers: &[Configuration]) -> crate::result::Result<()> {
+    fn same<T: PartialEq>(a: &Option<T>, b: &Option<T>) -> bool {
+        a.is_some() && a == b
+    }
+
+    for (i, receiver) in receivers.iter().enumerate() {
+        for other in &receivers[..i] {
+            let shared = if receiver.name == other.name {
+                "name"
+            } else if receiver.port == other.port && receiver.port != 0 {
+                "port"
+            } else if receiver.hw_addr == other.hw_addr {
+                "device id"
+            } else if same(
+                &receiver.http_address.filter(|addr| addr.port() != 0),
+                &other.http_address,
+            ) {
+                "HTTP API address"
+            } else if same(
+                &receiver
+                    .mqtt
+                    .as_ref()
+                    .map(|mqtt| (&mqtt.host, mqtt.port, &mqtt.topic)),
+                &other
+                    .mqtt
+                    .as_ref()
+                    .map(|mqtt| (&mqtt.host, mqtt.port, &mqtt.topic)),
+            ) {
+                "MQTT topic"
+            } else if same(&receiver.metadata_pipe, &other.metadata_pipe) {
+                "metadata pipe"
+            } else if same(&receiver.now_playing_file, &other.now_playing_file) {
+                "now playing file"
+            } else {
+                continue;
+            };
+            return Err(format!(
+                "receivers {:?} and {:?} have the same {}",
+                other.name, receiver.name, shared
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Directories searched for `config.toml` without `--config`.
+fn search_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let user_config = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = user_config {
+        paths.push(dir.join("airguitar").join("config.toml"));
+    }
+    paths.push(PathBuf::from("/etc/airguitar/config.toml"));
+    paths
+}
+
+/// Parses a value of an option which is a `ValueEnum` on the command line.
+fn value_enum<T: ValueEnum>(key: &str, value: &Option<String>) -> crate::result::Result<Option<T>> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    T::from_str(value, true).map(Some).map_err(|_| {
+        let expected = T::value_variants()
+            .iter()
+            .filter_map(|variant| variant.to_possible_value())
+            .map(|value| value.get_name().to_string())
+            .collect::<Vec<_>>();
+        invalid(
+            key,
+            format!(
+                "unknown value {:?}, expected one of {}",
+                value,
+                expected.join(", ")
+            ),
+        )
+    })
+}
+
+fn invalid(key: &str, reason: impl std::fmt::Display) -> crate::error::Error {
+    format!("invalid value for `{}`: {}", key, reason).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    /// Loads `contents` as the configuration file with the options `args`.
+    fn load(contents: &str, args: &[&str]) -> crate::result::Result<Vec<Configuration>> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, contents).unwrap();
+
+        let mut cli = vec!["airguitar", "--config", path.to_str().unwrap()];
+        cli.extend(["--state-dir", dir.path().to_str().unwrap()]);
+        cli.extend(args);
+        Configuration::load(&CliOpts::parse_from(cli))
+    }
+
+    #[test]
+    fn overrides_the_file_with_flags() {
+        let file = r#"
+            mpris = true
+            [mdns]
+            enabled = false
+            [volume]
+            ignore_sender = true
+        "#;
+        let config = &load(file, &[]).unwrap()[0];
+        assert!(config.mpris && !config.mdns && config.volume.ignore_sender);
+
+        let args = [
+            "--mpris=false",
+            "--no-mdns=false",
+            "--ignore-sender-volume=no",
+        ];
+        let config = &load(file, &args).unwrap()[0];
+        assert!(!config.mpris && config.mdns && !config.volume.ignore_sender);
+
+        let args = ["--mpris", "--no-mdns", "--wait-for-playback-start-hook"];
+        let config = &load("", &args).unwrap()[0];
+        assert!(config.mpris && !config.mdns && config.hooks.wait_for_playback_start);
+    }
+
+    #[test]
+    fn overrides_receivers_with_flags() {
+        let file = r#"
+            [volume]
+            max = 0.8
+            [[receivers]]
+            name = "Kitchen"
+            port = 5001
+            output.device = "hw:1"
+            volume.max = 0.5
+            volume.ignore_sender = true
+            [[receivers]]
+            name = "Bath"
+            port = 5002
+        "#;
+        let configs = load(file, &[]).unwrap();
+        assert_eq!(configs[0].device.as_deref(), Some("hw:1"));
+        assert_eq!(configs[0].volume.max, 0.5);
+        assert!(configs[0].volume.ignore_sender);
+        assert_eq!(configs[1].device, None);
+        assert_eq!(configs[1].volume.max, 0.8);
+
+        let args = [
+            "--device",
+            "hw:2",
+            "--max-volume",
+            "0.3",
+            "--ignore-sender-volume=false",
+        ];
+        for config in load(file, &args).unwrap() {
+            assert_eq!(config.device.as_deref(), Some("hw:2"));
+            assert_eq!(config.volume.max, 0.3);
+            assert!(!config.volume.ignore_sender);
+        }
+
+        for args in [
+            ["--name", "Garden"],
+            ["--port", "6000"],
+            ["--device-id", "random"],
+        ] {
+            let err = load(file, &args).unwrap_err().to_string();
+            assert!(err.contains(args[0]), "{}", err);
+        }
+    }
+
+    #[test]
+    fn keeps_device_ids_of_reordered_receivers() {
+        let dir = tempfile::tempdir().unwrap();
+        let load = |contents: &str| {
+            let path = dir.path().join("config.toml");
+            fs::write(&path, contents).unwrap();
+            let mut cli = vec!["airguitar", "--config", path.to_str().unwrap()];
+            cli.extend(["--state-dir", dir.path().to_str().unwrap()]);
+            Configuration::load(&CliOpts::parse_from(cli)).unwrap()
+        };
+
+        let configs = load(
+            r#"
+            device_id = "random"
+            [[receivers]]
+            name = "Kitchen"
+            [[receivers]]
+            name = "Bath"
+            state_key = "bath"
+            "#,
+        );
+        let reordered = load(
+            r#"
+            device_id = "random"
+            [[receivers]]
+            name = "Bathroom"
+            state_key = "bath"
+            [[receivers]]
+            name = "Kitchen"
+            "#,
+        );
+        assert_eq!(reordered[0].hw_addr, configs[1].hw_addr);
+        assert_eq!(reordered[1].hw_addr, configs[0].hw_addr);
+    }
+
+    #[test]
+    fn configures_services_per_receiver() {
+        let file = r#"
+            [mqtt]
+            host = "broker"
+            [[receivers]]
+            name = "Kitchen"
+            port = 5001
+            mpris = true
+            metadata_pipe = "/tmp/kitchen-metadata"
+            http.address = "127.0.0.1:8081"
+            mqtt.topic = "airguitar/kitchen"
+            [[receivers]]
+            name = "Bath"
+            port = 5002
+            now_playing_file = "/tmp/bath.json"
+            http.address = "127.0.0.1:8082"
+            mqtt.topic = "airguitar/bath"
+        "#;
+        let configs = load(file, &[]).unwrap();
+        assert_eq!(configs[0].http_address, Some(([127, 0, 0, 1], 8081).into()));
+        assert_eq!(configs[1].http_address, Some(([127, 0, 0, 1], 8082).into()));
+        assert!(configs[0].mpris && !configs[1].mpris);
+        assert_eq!(
+            configs[0].metadata_pipe.as_deref(),
+            Some(Path::new("/tmp/kitchen-metadata"))
+        );
+        assert_eq!(configs[1].metadata_pipe, None);
+        assert_eq!(configs[0].now_playing_file, None);
+        assert_eq!(
+            configs[1].now_playing_file.as_deref(),
+            Some(Path::new("/tmp/bath.json"))
+        );
+        let mqtt = configs[0].mqtt.as_ref().unwrap();
+        assert_eq!((mqtt.host.as_str(), mqtt.port), ("broker", 1883));
+        assert_eq!(mqtt.topic, "airguitar/kitchen");
+        assert_eq!(configs[1].mqtt.as_ref().unwrap().topic, "airguitar/bath");
+
+        let shared = r#"
+            mqtt.host = "broker"
+            [http]
+            address = "127.0.0.1:8080"
+            [[receivers]]
+            name = "Kitchen"
+            mqtt.topic = "airguitar/kitchen"
+            [[receivers]]
+            name = "Bath"
+            mqtt.topic = "airguitar/bath"
+        "#;
+        let err = load(shared, &[]).unwrap_err().to_string();
+        assert!(err.contains("same HTTP API address"), "{}", err);
+        let err = load(
+            "mqtt.host = \"broker\"\n[[receivers]]\nname = \"A\"\n[[receivers]]\nname = \"B\"",
+            &[],
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("same MQTT topic"), "{}", err);
+
+        for args in [
+            ["--http-address", "127.0.0.1:8080"],
+            ["--mqtt-topic", "airguitar"],
+            ["--metadata-pipe", "/tmp/metadata"],
+            ["--now-playing-file", "/tmp/now-playing.json"],
+        ] {
+            let err = load(file, &args).unwrap_err().to_string();
+            assert!(err.contains(args[0]), "{}", err);
+        }
+    }
+
+    #[test]
+    fn validates_the_file() {
+        for (contents, key) in [
+            ("name = \"\"", "`name`"),
+            ("[output]\nchannels = 0", "`output.channels`"),
+            ("[volume]\nmax = 1.5", "`volume.max`"),
+            ("[output]\nformat = \"s8\"", "`output.format`"),
+            (
+                "[output]\nfilters = [\"type=notch,freq=1\"]",
+                "`output.filters[0]`",
+            ),
+            (
+                "[network]\nudp_ports = \"6010-6000\"",
+                "`network.udp_ports`",
+            ),
+            ("device_id = \"AA:BB\"", "`device_id`"),
+            ("[mdns.txt]\n\"a=b\" = \"c\"", "`mdns.txt.a=b`"),
+            ("[[receivers]]\nport = 5001", "`receivers[0].name`"),
+            (
+                "[[receivers]]\nname = \"A\"\nvolume.max = -1",
+                "`receivers[0].volume.max`",
+            ),
+            (
+                "[[receivers]]\nname = \"A\"\nstate_key = \"\"",
+                "`receivers[0].state_key`",
+            ),
+        ] {
+            let err = load(contents, &[]).unwrap_err().to_string();
+            assert!(err.contains(key), "{}: {}", contents, err);
+        }
+
+        let err = load(
+            "[[receivers]]\nname = \"A\"\n[[receivers]]\nname = \"A\"",
+            &[],
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("same name"), "{}", err);
+    }
+
+    #[test]
+    fn keeps_settings_requiring_a_restart() {
+        let current = load("port = 5000\nname = \"A\"\n[volume]\nmax = 0.5", &[]).unwrap();
+        let new = load(
+            "port = 5001\nname = \"B\"\ndevice_id = \"AA:BB:CC:DD:EE:FF\"\n[volume]\nmax = 0.8",
+            &[],
+        )
+        .unwrap();
+
+        let (reloaded, restart) = current[0].reloaded(new[0].clone());
+        assert_eq!(restart, ["port", "device_id"]);
+        assert_eq!(reloaded.port, 5000);
+        assert_eq!(reloaded.hw_addr, current[0].hw_addr);
+        assert_eq!(reloaded.name, "B");
+        assert_eq!(reloaded.volume.max, 0.8);
+    }
+
+    #[test]
+    fn matches_reloaded_receivers() {
+        let receivers = |receivers: &[(&str, u16)]| {
+            let contents = receivers
+                .iter()
+                .map(|(name, port)| {
+                    format!("[[receivers]]\nname = \"{}\"\nport = {}\n", name, port)
+                })
+                .collect::<String>();
+            load(&contents, &[]).unwrap()
+        };
+        let current = receivers(&[("A", 5000), ("B", 5001), ("C", 5002)])
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+
+        // Reordered, renamed and added.
+        let new = receivers(&[("B", 5001), ("D", 5000), ("A", 5010), ("E", 5011)]);
+        assert_eq!(
+            match_receivers(&current, &new),
+            [Some(1), None, Some(0), None]
+        );
+
+        // A single receiver is kept whatever changed.
+        let new = load("name = \"D\"\nport = 6000", &[]).unwrap();
+        assert_eq!(match_receivers(&current[..1], &new), [Some(0)]);
+    }
+
+    #[test]
+    fn rejects_filters_of_missing_channels() {
+        let filters = r#"
+            [output]
+            filters = ["type=peaking,freq=100,gain=3,channel=1"]
+        "#;
+        assert!(load(filters, &[]).is_ok());
+        let err = load(filters, &["--output-channels", "1"]).unwrap_err();
+        assert!(err.to_string().contains("output.filters[0]"), "{}", err);
+
+        let err = load("", &["--filter", "type=peaking,freq=100,channel=2"]).unwrap_err();
+        assert!(err.to_string().contains("output.filters[0]"), "{}", err);
+        assert!(load(
+            "",
+            &[
+                "--filter",
+                "type=peaking,freq=100,channel=3",
+                "--output-channels",
+                "4"
+            ]
+        )
+        .is_ok());
+
+        let receivers = r#"
+            [[receivers]]
+            name = "Kitchen"
+            [[receivers]]
+            name = "Garden"
+            output.channels = 1
+        "#;
+        let err = load(receivers, &["--filter", "type=peaking,freq=100,channel=1"]).unwrap_err();
+        assert!(
+            err.to_string().contains("receivers[1].output.filters[0]"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use crate::shutdown::Shutdown;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    future,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::{mpsc, oneshot},
+    time,
+};
+use tracing::{debug, error};
+
+/// Service type senders advertise their DACP server with.
+const DACP_SERVICE_TYPE: &str = "_dacp._tcp.local.";
+
+/// Prefix of the instance name, followed by the `DACP-ID` of the sender.
+const DACP_INSTANCE_PREFIX: &str = "iTunes_Ctrl_";
+
+/// Port of the DACP server of senders which are not found via mDNS, the one
+/// iTunes and the Music app listen on.
+const DACP_PORT: u16 = 3689;
+
+/// How long to wait for the sender to respond to a command.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Playback controls which can be sent to a sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RemoteCommand {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    VolumeUp,
+    VolumeDown,
+}
+
+impl RemoteCommand {
+    fn path(self) -> &'static str {
+        match self {
+            RemoteCommand::Play => "/ctrl-int/1/play",
+            RemoteCommand::Pause => "/ctrl-int/1/pause",
+            RemoteCommand::PlayPause => "/ctrl-int/1/playpause",
+            RemoteCommand::Stop => "/ctrl-int/1/stop",
+            RemoteCommand::Next => "/ctrl-int/1/nextitem",
+            RemoteCommand::Previous => "/ctrl-int/1/previtem",
+            RemoteCommand::VolumeUp => "/ctrl-int/1/volumeup",
+            RemoteCommand::VolumeDown => "/ctrl-int/1/volumedown",
+        }
+    }
+}
+
+impl FromStr for RemoteCommand {
+    type Err = crate::error::Error;
+
+    fn from_str(input: &str) -> crate::result::Result<RemoteCommand> {
+        match input {
+            "play" => Ok(RemoteCommand::Play),
+            "pause" => Ok(RemoteCommand::Pause),
+            "play_pause" => Ok(RemoteCommand::PlayPause),
+            "stop" => Ok(RemoteCommand::Stop),
+            "next" => Ok(RemoteCommand::Next),
+            "previous" => Ok(RemoteCommand::Previous),
+            "volume_up" => Ok(RemoteCommand::VolumeUp),
+            "volume_down" => Ok(RemoteCommand::VolumeDown),
+            _ => Err(format!("unknown remote command {:?}", input).into()),
+        }
+    }
+}
+
+/// Identifies the DACP server of a sender.
+///
+/// Taken from the `DACP-ID` and `Active-Remote` headers of its RTSP requests.
+#[derive(Debug, Clone)]
+pub(crate) struct Remote {
+    pub(crate) dacp_id: String,
+    pub(crate) active_remote: String,
+    /// Address the RTSP requests came from.
+    pub(crate) ip: IpAddr,
+}
+
+#[derive(Debug)]
+pub(crate) enum DacpCommand {
+    Send {
+        remote: Remote,
+        command: RemoteCommand,
+        resp: oneshot::Sender<crate::result::Result<()>>,
+    },
+}
+
+/// Remote controls senders through their Digital Audio Control Protocol
+/// (DACP) server.
+///
+/// Senders announce their DACP server via mDNS, which is browsed for as long
+/// as the task is running if a `daemon` is given. Senders which are not found
+/// are expected at `DACP_PORT` of the address their RTSP requests came from.
+///
+/// A single `Dacp` serves all receivers, the daemon only keeps one browser per
+/// service type.
+pub(crate) struct Dacp {
+    pub(crate) dacp_rx: mpsc::Receiver<DacpCommand>,
+
+    /// Browses for DACP servers, shared with `Mdns`. Only worth it if remote
+    /// controls are available and mDNS is enabled.
+    pub(crate) daemon: Option<ServiceDaemon>,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+}
+
+impl Dacp {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        let mut browser = self.daemon.as_ref().and_then(|daemon| {
+            daemon
+                .browse(DACP_SERVICE_TYPE)
+                .map_err(|err| error!(cause = %err, "failed to browse for dacp servers"))
+                .ok()
+        });
+
+        // Addresses of the known DACP servers by their DACP-ID.
+        let mut servers = HashMap::new();
+
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                event = async {
+                    match browser {
+                        Some(ref browser) => browser.recv_async().await,
+                        None => future::pending().await,
+                    }
+                } => match event {
+                    Ok(ServiceEvent::ServiceResolved(service)) => {
+                        if let Some(dacp_id) = dacp_id(&service.fullname) {
+                            let ip = service
+                                .addresses
+                                .iter()
+                                .map(|addr| addr.to_ip_addr())
+                                .min_by_key(|ip| ip.is_ipv6());
+                            if let Some(ip) = ip {
+                                let addr = SocketAddr::new(ip, service.port);
+                                debug!(dacp_id, %addr, "dacp server found");
+                                servers.insert(dacp_id, addr);
+                            }
+                        }
+                    }
+                    Ok(ServiceEvent::ServiceRemoved(_, fullname)) => {
+                        if let Some(dacp_id) = dacp_id(&fullname) {
+                            servers.remove(&dacp_id);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        // Senders are still expected at the default port.
+                        error!(cause = %err, "browsing for dacp servers failed");
+                        browser = None;
+                    }
+                },
+                Some(command) = self.dacp_rx.recv() => match command {
+                    DacpCommand::Send { remote, command, resp } => {
+                        let addr = servers
+                            .get(&remote.dacp_id.to_uppercase())
+                            .copied()
+                            .unwrap_or_else(|| SocketAddr::new(remote.ip, DACP_PORT));
+                        tokio::spawn(async move {
+                            let res = send(addr, &remote, command).await;
+                            if let Err(ref err) = res {
+                                error!(cause = %err, "dacp request failed");
+                            }
+                            let _ = resp.send(res);
+                        });
+                    }
+                },
+                _ = self.shutdown.recv() => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Extracts the DACP-ID from the full name of a DACP service instance.
+fn dacp_id(fullname: &str) -> Option<String> {
+    let instance = fullname.strip_suffix(DACP_SERVICE_TYPE)?;
+    let dacp_id = instance
+        .trim_end_matches('.')
+        .strip_prefix(DACP_INSTANCE_PREFIX)?;
+    Some(dacp_id.to_uppercase())
+}
+
+/// Sends a single command to a DACP server.
+async fn send(
+    addr: SocketAddr,
+    remote: &Remote,
+    command: RemoteCommand,
+) -> crate::result::Result<()> {
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nActive-Remote: {}\r\nConnection: close\r\n\r\n",
+        command.path(),
+        addr,
+        remote.active_remote
+    );
+
+    let response = time::timeout(REQUEST_TIMEOUT, async {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        crate::result::Result::Ok(response)
+    })
+    .await??;
+
+    let status_line = response
+        .split(|&b| b == b'\n')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    match status_line.split_whitespace().nth(1) {
+        Some("200") | Some("204") => Ok(()),
+        _ => Err(format!("unexpected dacp response {:?}", status_line.trim()).into()),
+    }
+}
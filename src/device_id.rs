@@ -0,0 +1,339 @@
+use md5::{Digest, Md5};
+use rand::Rng;
+use std::{
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::warn;
+
+/// Where the device id, a MAC address identifying the receiver in its mDNS
+/// service name and the Apple-Challenge response, comes from.
+///
+/// Senders remember receivers by their device id, so it has to stay the same
+/// across restarts and renames.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum DeviceId {
+    /// Given explicitly, e.g. `AA:BB:CC:DD:EE:FF`.
+    Address([u8; 6]),
+    /// MAC address of a network interface, e.g. `interface:eth0`.
+    Interface(String),
+    /// Derived from the name when first started, as earlier versions did, and
+    /// stored in the state directory to survive renames (`name`).
+    Name,
+    /// Generated once and stored in the state directory (`random`).
+    Random,
+}
+
+impl FromStr for DeviceId {
+    type Err = crate::error::Error;
+
+    fn from_str(input: &str) -> crate::result::Result<DeviceId> {
+        match input {
+            "name" => return Ok(DeviceId::Name),
+            "random" => return Ok(DeviceId::Random),
+            _ => {}
+        }
+        if let Some(interface) = input.strip_prefix("interface:") {
+            return Ok(DeviceId::Interface(interface.into()));
+        }
+        parse(input).map(DeviceId::Address).ok_or_else(|| {
+            format!(
+                "expected a MAC address, `interface:<name>`, `name` or `random`, got {:?}",
+                input
+            )
+            .into()
+        })
+    }
+}
+
+impl DeviceId {
+    /// Returns the MAC address of the receiver named `name`, generating and
+    /// storing one in `state_dir` (or the default state directory) if there is
+    /// none yet.
+    ///
+    /// Ids are stored per mode, as `device_id.name` or `device_id.random`, so
+    /// switching the mode does not keep the id of the other one. Receivers of
+    /// the `receivers` list pass their `key` to get ids of their own, stored as
+    /// e.g. `device_id.name.Kitchen`, which stay with the receiver when the list
+    /// is reordered. Without a usable state directory, ids derived from the
+    /// name are derived every time instead.
+    pub(crate) fn resolve(
+        &self,
+        name: &str,
+        state_dir: Option<PathBuf>,
+        key: Option<&str>,
+    ) -> crate::result::Result<[u8; 6]> {
+        match self {
+            DeviceId::Address(hw_addr) => return Ok(*hw_addr),
+            DeviceId::Interface(interface) => return interface_address(interface),
+            DeviceId::Name | DeviceId::Random => {}
+        }
+        let (mode, generate): (_, fn(&str) -> [u8; 6]) = match self {
+            DeviceId::Name => ("name", name_address),
+            _ => ("random", |_| random_address()),
+        };
+        let file = match key {
+            Some(key) => format!("device_id.{}.{}", mode, encode_key(key)),
+            None => format!("device_id.{}", mode),
+        };
+
+        let stored = state_dir
+            .map(Ok)
+            .unwrap_or_else(default_state_dir)
+            .and_then(|state_dir| stored(&state_dir, &file, || generate(name)));
+        match stored {
+            Err(err) if *self == DeviceId::Name => {
+                warn!(cause = %err, "device id not stored, deriving it from the name");
+                Ok(name_address(name))
+            }
+            stored => stored,
+        }
+    }
+}
+
+/// Reads the id stored as `file` in `state_dir`, storing the one returned by
+/// `generate` if there is none yet.
+fn stored(
+    state_dir: &Path,
+    file: &str,
+    generate: impl FnOnce() -> [u8; 6],
+) -> crate::result::Result<[u8; 6]> {
+    let path = state_dir.join(file);
+    match fs::read_to_string(&path) {
+        Ok(contents) => parse(contents.trim())
+            .ok_or_else(|| format!("{}: invalid device id {:?}", path.display(), contents).into()),
+        Err(err) if err.kind() == ErrorKind::NotFound => {
+            let hw_addr = generate();
+            fs::create_dir_all(state_dir)
+                .map_err(|err| format!("failed to create {}: {}", state_dir.display(), err))?;
+            fs::write(&path, format!("{}\n", format(&hw_addr)))
+                .map_err(|err| format!("failed to write {}: {}", path.display(), err))?;
+            Ok(hw_addr)
+        }
+        Err(err) => Err(format!("failed to read {}: {}", path.display(), err).into()),
+    }
+}
+
+/// Percent-encodes everything but ASCII letters, digits, `-` and `_`, keeping
+/// keys distinct and usable as part of a file name.
+fn encode_key(key: &str) -> String {
+    key.bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Locally administered unicast address.
+fn random_address() -> [u8; 6] {
+    let mut hw_addr: [u8; 6] = rand::thread_rng().gen();
+    hw_addr[0] = (hw_addr[0] & 0xfc) | 0x02;
+    hw_addr
+}
+
+/// Id derived from the name, as used before ids were stored.
+fn name_address(name: &str) -> [u8; 6] {
+    let digest = Md5::digest(name.as_bytes());
+    [
+        digest[0], digest[1], digest[2], digest[3], digest[4], digest[5],
+    ]
+}
+
+/// Formats a MAC address as `AA:BB:CC:DD:EE:FF`.
+pub(crate) fn format(hw_addr: &[u8; 6]) -> String {
+    hw_addr
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+fn parse(input: &str) -> Option<[u8; 6]> {
+    let mut hw_addr = [0; 6];
+    let mut parts = input.split([':', '-']);
+    for byte in hw_addr.iter_mut() {
+        let part = parts.next().filter(|part| part.len() == 2)?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(hw_addr)
+}
+
+#[cfg(target_os = "linux")]
+fn interface_address(interface: &str) -> crate::result::Result<[u8; 6]> {
+    let path = Path::new("/sys/class/net").join(interface).join("address");
+    let contents = fs::read_to_string(&path).map_err(|err| {
+        format!(
+            "no MAC address of network interface {:?}: {}",
+            interface, err
+        )
+    })?;
+    match parse(contents.trim()) {
+        Some(hw_addr) if hw_addr != [0; 6] => Ok(hw_addr),
+        _ => Err(format!("network interface {:?} has no MAC address", interface).into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_address(interface: &str) -> crate::result::Result<[u8; 6]> {
+    Err(format!(
+        "reading the MAC address of network interface {:?} is only supported on Linux",
+        interface
+    )
+    .into())
+}
+
+/// Default directory of persistent state, `$STATE_DIRECTORY` when run as a
+/// systemd service, otherwise `airguitar` in `$XDG_STATE_HOME` or
+/// `~/.local/state`.
+fn default_state_dir() -> crate::result::Result<PathBuf> {
+    if let Some(dir) = env::var_os("STATE_DIRECTORY").filter(|dir| !dir.is_empty()) {
+        // systemd passes a colon separated list for multiple directories.
+        let first = dir
+            .to_string_lossy()
+            .split(':')
+            .next()
+            .unwrap_or_default()
+            .to_string();
+        return Ok(PathBuf::from(first));
+    }
+    env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME")
+                .filter(|home| !home.is_empty())
+                .map(|home| Path::new(&home).join(".local").join("state"))
+        })
+        .map(|dir| dir.join("airguitar"))
+        .ok_or_else(|| "no state directory, set `--state-dir`".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_device_ids() {
+        assert_eq!(
+            "aa:BB:cc:dd:ee:0F".parse::<DeviceId>().unwrap(),
+            DeviceId::Address([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f])
+        );
+        assert_eq!(
+            "AA-BB-CC-DD-EE-FF".parse::<DeviceId>().unwrap(),
+            DeviceId::Address([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff])
+        );
+        assert_eq!(
+            "interface:eth0".parse::<DeviceId>().unwrap(),
+            DeviceId::Interface("eth0".into())
+        );
+        assert_eq!("name".parse::<DeviceId>().unwrap(), DeviceId::Name);
+        assert_eq!("random".parse::<DeviceId>().unwrap(), DeviceId::Random);
+
+        for id in [
+            "",
+            "AA:BB:CC:DD:EE",
+            "AA:BB:CC:DD:EE:FF:00",
+            "AA:BB:CC:DD:EE:GG",
+            "A:BB:CC:DD:EE:FFF",
+        ] {
+            assert!(id.parse::<DeviceId>().is_err(), "{:?}", id);
+        }
+    }
+
+    #[test]
+    fn stores_the_id_derived_from_the_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = Some(dir.path().to_path_buf());
+
+        let hw_addr = DeviceId::Name
+            .resolve("Kitchen", state_dir.clone(), None)
+            .unwrap();
+        assert_eq!(hw_addr, name_address("Kitchen"));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("device_id.name")).unwrap(),
+            format!("{}\n", format(&hw_addr))
+        );
+
+        // Kept when renamed.
+        let renamed = DeviceId::Name
+            .resolve("Garden", state_dir.clone(), None)
+            .unwrap();
+        assert_eq!(renamed, hw_addr);
+
+        // Switching the mode does not keep the id.
+        let random = DeviceId::Random
+            .resolve("Garden", state_dir.clone(), None)
+            .unwrap();
+        assert_ne!(random, hw_addr);
+        assert!(dir.path().join("device_id.random").is_file());
+    }
+
+    #[test]
+    fn stores_the_ids_of_receivers_by_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = Some(dir.path().to_path_buf());
+
+        let living_room = DeviceId::Random
+            .resolve("Living Room", state_dir.clone(), Some("Living Room"))
+            .unwrap();
+        let bath = DeviceId::Random
+            .resolve("Bath", state_dir.clone(), Some("Bath"))
+            .unwrap();
+        assert_ne!(living_room, bath);
+        assert!(dir.path().join("device_id.random.Living%20Room").is_file());
+
+        // Each key keeps its id, whatever the order of the receivers.
+        assert_eq!(
+            DeviceId::Random
+                .resolve("Bath", state_dir.clone(), Some("Bath"))
+                .unwrap(),
+            bath
+        );
+        assert_eq!(
+            DeviceId::Random
+                .resolve("Living Room", state_dir, Some("Living Room"))
+                .unwrap(),
+            living_room
+        );
+        assert_eq!(encode_key("a/b.c"), "a%2Fb%2Ec");
+    }
+
+    #[test]
+    fn stores_a_random_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = Some(dir.path().to_path_buf());
+
+        let hw_addr = DeviceId::Random
+            .resolve("Kitchen", state_dir.clone(), None)
+            .unwrap();
+        // Locally administered unicast address.
+        assert_eq!(hw_addr[0] & 0x03, 0x02);
+        assert_eq!(
+            DeviceId::Random
+                .resolve("Kitchen", state_dir, None)
+                .unwrap(),
+            hw_addr
+        );
+    }
+
+    #[test]
+    fn derives_the_id_without_a_state_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        // Not a directory.
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+
+        assert_eq!(
+            DeviceId::Name
+                .resolve("Kitchen", Some(file.clone()), None)
+                .unwrap(),
+            name_address("Kitchen")
+        );
+        assert!(DeviceId::Random
+            .resolve("Kitchen", Some(file), None)
+            .is_err());
+    }
+}
@@ -0,0 +1,119 @@
+use serde::Serialize;
+
+/// A single DMAP (Digital Media Access Protocol) item.
+///
+/// Items consist of a four character code, a 32 bit big endian length and the
+/// data. Containers (e.g. `mlit`) contain further items as their data.
+#[derive(Debug, Clone)]
+pub(crate) struct Item {
+    pub(crate) code: [u8; 4],
+    pub(crate) data: Vec<u8>,
+}
+
+/// Parses a list of DMAP items.
+pub(crate) fn parse(mut input: &[u8]) -> crate::result::Result<Vec<Item>> {
+    let mut items = Vec::new();
+
+    while !input.is_empty() {
+        if input.len() < 8 {
+            return Err("truncated dmap item header".into());
+        }
+
+        let code = input[..4].try_into()?;
+        let length = u32::from_be_bytes(input[4..8].try_into()?) as usize;
+        let data = input
+            .get(8..8 + length)
+            .ok_or("truncated dmap item data")?
+            .to_vec();
+
+        items.push(Item { code, data });
+        input = &input[8 + length..];
+    }
+
+    Ok(items)
+}
+
+/// Track information sent by the client.
+#[derive(Debug, Clone, Default, Serialize)]
+pub(crate) struct Metadata {
+    pub(crate) title: Option<String>,
+    pub(crate) artist: Option<String>,
+    pub(crate) album: Option<String>,
+    pub(crate) genre: Option<String>,
+    pub(crate) composer: Option<String>,
+
+    /// All items of the listing, including the ones not extracted above.
+    #[serde(skip)]
+    pub(crate) items: Vec<Item>,
+}
+
+impl Metadata {
+    /// Extracts track information from a DMAP `mlit` listing item.
+    pub(crate) fn parse(input: &[u8]) -> crate::result::Result<Metadata> {
+        let items = match parse(input)?.into_iter().next() {
+            Some(item) if &item.code == b"mlit" => parse(&item.data)?,
+            _ => return Err("missing dmap listing item".into()),
+        };
+
+        let mut metadata = Metadata::default();
+        for item in &items {
+            let value = String::from_utf8_lossy(&item.data).into_owned();
+            match &item.code {
+                b"minm" => metadata.title = Some(value),
+                b"asar" => metadata.artist = Some(value),
+                b"asal" => metadata.album = Some(value),
+                b"asgn" => metadata.genre = Some(value),
+                b"ascp" => metadata.composer = Some(value),
+                _ => {}
+            }
+        }
+        metadata.items = items;
+
+        Ok(metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(code: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut item = code.to_vec();
+        item.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        item.extend_from_slice(data);
+        item
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let items = [
+            item(b"minm", b"Title"),
+            item(b"asar", b"Artist"),
+            item(b"asyr", &[7, 230]),
+        ]
+        .concat();
+        let metadata = Metadata::parse(&item(b"mlit", &items)).unwrap();
+
+        assert_eq!(metadata.title.as_deref(), Some("Title"));
+        assert_eq!(metadata.artist.as_deref(), Some("Artist"));
+        assert_eq!(metadata.album, None);
+        assert_eq!(metadata.items.len(), 3);
+        assert_eq!(&metadata.items[2].code, b"asyr");
+        assert_eq!(metadata.items[2].data, [7, 230]);
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        let input = item(b"minm", b"Title");
+        for length in 1..input.len() {
+            assert!(parse(&input[..length]).is_err(), "{}", length);
+        }
+        assert!(parse(&[]).unwrap().is_empty());
+
+        // Truncated within the listing.
+        let listing = item(b"mlit", &input[..input.len() - 1]);
+        assert!(Metadata::parse(&listing).is_err());
+        assert!(Metadata::parse(&item(b"minm", b"Title")).is_err());
+        assert!(Metadata::parse(&[]).is_err());
+    }
+}
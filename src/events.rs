@@ -0,0 +1,201 @@
+use crate::{dmap::Metadata, player::Progress};
+use bytes::Bytes;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Number of past events kept for subscribers resuming after a disconnect.
+const HISTORY_SIZE: usize = 128;
+
+/// Something that happened within the receiver.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Event {
+    SessionStarted {
+        /// Id of the RTSP connection, logged as `session_id`.
+        session_id: u64,
+        client: SocketAddr,
+        client_name: Option<String>,
+        user_agent: Option<String>,
+        codec: String,
+    },
+    SessionEnded {
+        client: SocketAddr,
+    },
+    /// The sender started streaming audio (`RECORD`).
+    PlaybackStarted,
+    Flush {
+        seq: u16,
+    },
+    /// The sender changed its volume.
+    VolumeChanged {
+        /// Volume requested by the sender (AirPlay dB scale).
+        volume: f64,
+        /// Resulting gain of the output, see `LocalVolumeChanged`.
+        gain: f32,
+    },
+    /// The local volume or mute state was changed, e.g. through the HTTP API.
+    LocalVolumeChanged {
+        local_volume: f32,
+        muted: bool,
+    },
+    OutputPaused {
+        paused: bool,
+    },
+    MetadataUpdated {
+        metadata: Metadata,
+    },
+    ArtworkUpdated {
+        /// Content type of the new artwork, `None` if it was cleared.
+        content_type: Option<String>,
+        #[serde(skip)]
+        data: Bytes,
+    },
+    ProgressUpdated {
+        progress: Progress,
+    },
+    Underrun,
+}
+
+/// An `Event` together with its id.
+///
+/// Ids are increasing monotonically, allowing subscribers to resume after the
+/// last event they have seen.
+#[derive(Debug, Clone)]
+pub(crate) struct Notification {
+    pub(crate) id: u64,
+    pub(crate) event: Event,
+}
+
+/// Distributes `Event`s to any number of subscribers.
+///
+/// Cloning an `EventBus` is cheap, all clones publish to the same subscribers.
+#[derive(Debug, Clone)]
+pub(crate) struct EventBus {
+    inner: Arc<Mutex<Inner>>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    next_id: u64,
+    history: VecDeque<Notification>,
+    tx: broadcast::Sender<Notification>,
+}
+
+impl EventBus {
+    pub(crate) fn new() -> EventBus {
+        let (tx, _) = broadcast::channel(HISTORY_SIZE);
+
+        EventBus {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 1,
+                history: VecDeque::with_capacity(HISTORY_SIZE),
+                tx,
+            })),
+        }
+    }
+
+    /// Publishes an event to all current subscribers.
+    pub(crate) fn publish(&self, event: Event) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let notification = Notification {
+            id: inner.next_id,
+            event,
+        };
+        inner.next_id += 1;
+
+        if inner.history.len() == HISTORY_SIZE {
+            inner.history.pop_front();
+        }
+        inner.history.push_back(notification.clone());
+
+        // Sending only fails if there are no subscribers.
+        let _ = inner.tx.send(notification);
+    }
+
+    /// Subscribes to future events.
+    ///
+    /// If `last_id` is given, the still known events published after it are
+    /// returned as well.
+    pub(crate) fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Vec<Notification>, broadcast::Receiver<Notification>) {
+        let inner = self.inner.lock().unwrap();
+
+        let backlog = match last_id {
+            Some(last_id) => inner
+                .history
+                .iter()
+                .filter(|notification| notification.id > last_id)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+
+        (backlog, inner.tx.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flush_seq(notification: &Notification) -> u16 {
+        match notification.event {
+            Event::Flush { seq } => seq,
+            ref event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn resumes_after_the_last_event() {
+        let events = EventBus::new();
+        let (backlog, mut rx) = events.subscribe(None);
+        assert!(backlog.is_empty());
+
+        // Ids start at 1 and increase monotonically.
+        for seq in 0..3 {
+            events.publish(Event::Flush { seq });
+        }
+        for id in 1..=3 {
+            let notification = rx.try_recv().unwrap();
+            assert_eq!(notification.id, id);
+            assert_eq!(flush_seq(&notification) as u64, id - 1);
+        }
+
+        let published = HISTORY_SIZE as u64 + 10;
+        for seq in 3..published {
+            events.publish(Event::Flush { seq: seq as u16 });
+        }
+
+        // Only events after `last_id` are returned.
+        let (backlog, _) = events.subscribe(Some(published - 3));
+        let ids = backlog.iter().map(|n| n.id).collect::<Vec<_>>();
+        assert_eq!(ids, [published - 2, published - 1, published]);
+        let (backlog, _) = events.subscribe(Some(published));
+        assert!(backlog.is_empty());
+
+        // Events older than the history are gone, the backlog starts with the
+        // oldest one still known.
+        let (backlog, _) = events.subscribe(Some(1));
+        assert_eq!(backlog.len(), HISTORY_SIZE);
+        assert_eq!(backlog[0].id, published - HISTORY_SIZE as u64 + 1);
+        assert_eq!(
+            flush_seq(&backlog[0]) as u64,
+            published - HISTORY_SIZE as u64
+        );
+        assert!(backlog.windows(2).all(|pair| pair[1].id == pair[0].id + 1));
+        assert_eq!(backlog.last().unwrap().id, published);
+
+        // Future events continue with the next id.
+        let (_, mut rx) = events.subscribe(Some(published));
+        events.publish(Event::Underrun);
+        assert_eq!(rx.try_recv().unwrap().id, published + 1);
+    }
+}
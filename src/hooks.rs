@@ -0,0 +1,228 @@
+use crate::{
+    events::{Event, EventBus},
+    shutdown::Shutdown,
+    Configuration,
+};
+use std::{net::IpAddr, process::Stdio, sync::Arc, time::Duration};
+use tokio::{
+    process,
+    sync::{broadcast::error::RecvError, mpsc, watch},
+    time,
+};
+use tracing::{debug, error, info};
+
+/// Commands executed on session lifecycle events.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HookConfig {
+    pub(crate) session_start: Option<String>,
+    pub(crate) playback_start: Option<String>,
+    pub(crate) flush: Option<String>,
+    pub(crate) session_end: Option<String>,
+    pub(crate) volume_change: Option<String>,
+    /// Hooks running longer get killed.
+    pub(crate) timeout: Duration,
+    /// Delay the start of the playback until its hook completed.
+    pub(crate) wait_for_playback_start: bool,
+}
+
+/// Details about the session passed to hooks as environment variables.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HookEnv {
+    /// `AIRGUITAR_CLIENT_IP`
+    pub(crate) client_ip: Option<IpAddr>,
+    /// `AIRGUITAR_CLIENT_NAME`
+    pub(crate) client_name: Option<String>,
+    /// `AIRGUITAR_USER_AGENT`
+    pub(crate) user_agent: Option<String>,
+    /// `AIRGUITAR_VOLUME`, AirPlay volume requested by the sender.
+    pub(crate) volume: f64,
+}
+
+/// Runs a hook command through `sh -c`, killing it after `timeout`.
+///
+/// Failures are logged only, a broken hook must not break playback.
+pub(crate) async fn run_hook(event: &str, command: &str, env: &HookEnv, timeout: Duration) {
+    debug!(event, command, "running hook");
+
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c")
+        .arg(command)
+        .env("AIRGUITAR_EVENT", event)
+        .env(
+            "AIRGUITAR_CLIENT_IP",
+            env.client_ip.map(|ip| ip.to_string()).unwrap_or_default(),
+        )
+        .env(
+            "AIRGUITAR_CLIENT_NAME",
+            env.client_name.as_deref().unwrap_or_default(),
+        )
+        .env(
+            "AIRGUITAR_USER_AGENT",
+            env.user_agent.as_deref().unwrap_or_default(),
+        )
+        .env("AIRGUITAR_VOLUME", format!("{:.6}", env.volume))
+        .stdin(Stdio::null())
+        .kill_on_drop(true);
+
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(err) => {
+            error!(cause = %err, event, "failed to run hook");
+            return;
+        }
+    };
+
+    match time::timeout(timeout, child.wait()).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => error!(event, %status, "hook failed"),
+        Ok(Err(err)) => error!(cause = %err, event, "hook failed"),
+        Err(_) => {
+            error!(event, "hook timed out, killing it");
+            let _ = child.kill().await;
+        }
+    }
+}
+
+/// Runs the configured hooks on session lifecycle events.
+///
+/// Hooks are executed one after another in the order of the events. They see
+/// the details of the session as environment variables, see `HookEnv`, and
+/// the name of the event as `AIRGUITAR_EVENT`.
+pub(crate) struct Hooks {
+    /// App configuration, changed hooks apply to the next event.
+    pub(crate) config: watch::Receiver<Arc<Configuration>>,
+
+    /// Source of the lifecycle events.
+    pub(crate) events: EventBus,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `Hooks` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl Hooks {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        let (_, mut rx) = self.events.subscribe(None);
+        let mut env = HookEnv::default();
+        let mut session_active = false;
+
+        while !self.shutdown.is_shutdown() {
+            let notification = tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(notification) => notification,
+                    Err(RecvError::Lagged(skipped)) => {
+                        error!(skipped, "hooks lagging behind, skipped events");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = self.shutdown.recv() => break,
+            };
+
+            let config = self.config.borrow().hooks.clone();
+            let (event, command) = match notification.event {
+                Event::SessionStarted {
+                    client,
+                    client_name,
+                    user_agent,
+                    ..
+                } => {
+                    env.client_ip = Some(client.ip());
+                    env.client_name = client_name;
+                    env.user_agent = user_agent;
+                    session_active = true;
+                    ("session_start", &config.session_start)
+                }
+                // Waiting hooks are run by the RTSP handler before starting
+                // the playback.
+                Event::PlaybackStarted if !config.wait_for_playback_start => {
+                    ("playback_start", &config.playback_start)
+                }
+                Event::Flush { .. } => ("flush", &config.flush),
+                Event::SessionEnded { .. } => {
+                    session_active = false;
+                    ("session_end", &config.session_end)
+                }
+                Event::VolumeChanged { volume, .. } => {
+                    env.volume = volume;
+                    ("volume_change", &config.volume_change)
+                }
+                _ => continue,
+            };
+
+            if let Some(command) = command {
+                run_hook(event, command, &env, config.timeout).await;
+            }
+
+            if event == "session_end" {
+                env = HookEnv {
+                    volume: env.volume,
+                    ..HookEnv::default()
+                };
+            }
+        }
+
+        // Shutting down ends the current session as well, e.g. to switch off
+        // amplifiers.
+        let config = self.config.borrow().hooks.clone();
+        if let (true, Some(command)) = (session_active, &config.session_end) {
+            info!("running session end hook before shutting down");
+            run_hook("session_end", command, &env, config.timeout).await;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn passes_the_session_details() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("env");
+        let command = format!(
+            "printf '%s|%s|%s|%s|%s' \"$AIRGUITAR_EVENT\" \"$AIRGUITAR_CLIENT_IP\" \
+             \"$AIRGUITAR_CLIENT_NAME\" \"$AIRGUITAR_USER_AGENT\" \"$AIRGUITAR_VOLUME\" > {}",
+            path.display()
+        );
+        let env = HookEnv {
+            client_ip: Some("192.168.1.10".parse().unwrap()),
+            client_name: Some("Living Room".into()),
+            user_agent: Some("AirPlay/381.13".into()),
+            volume: -12.5,
+        };
+
+        run_hook("session_start", &command, &env, Duration::from_secs(5)).await;
+
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "session_start|192.168.1.10|Living Room|AirPlay/381.13|-12.500000"
+        );
+    }
+
+    #[tokio::test]
+    async fn kills_hooks_running_too_long() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("done");
+        let command = format!("sleep 2; touch {}", path.display());
+
+        let started = Instant::now();
+        run_hook(
+            "flush",
+            &command,
+            &HookEnv::default(),
+            Duration::from_millis(100),
+        )
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // The hook would have completed by now if it was still running.
+        time::sleep(Duration::from_secs(3)).await;
+        assert!(!path.exists());
+    }
+}
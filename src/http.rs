@@ -0,0 +1,366 @@
+use crate::{
+    dacp::RemoteCommand,
+    events::EventBus,
+    metrics::Metrics,
+    player::{Command, Status},
+    shutdown::Shutdown,
+};
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use futures_util::Stream;
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::{
+    net::TcpListener,
+    sync::{mpsc, oneshot, watch},
+};
+use tokio_stream::{wrappers::BroadcastStream, StreamExt};
+
+/// HTTP/JSON API exposing the player state and local controls.
+///
+/// Routes:
+///
+/// - `GET /status` current session, volume, buffer fill and metadata
+/// - `GET /artwork` artwork of the current track
+/// - `POST /volume` sets the local volume, e.g. `{"volume": 0.5}`
+/// - `POST /mute` mutes the output, e.g. `{"muted": true}`
+/// - `POST /pause` pauses the output, e.g. `{"paused": true}`
+/// - `POST /disconnect` disconnects the current sender
+/// - `POST /remote/{command}` remote controls the current sender, commands are
+///   `play`, `pause`, `play_pause`, `stop`, `next`, `previous`, `volume_up`
+///   and `volume_down`
+/// - `GET /events` server-sent events stream of receiver events, resumable
+///   via the `Last-Event-ID` header
+/// - `GET /metrics` receiver metrics in the Prometheus text format
+pub(crate) struct Http {
+    /// TCP listener supplied by the `run` caller.
+    pub(crate) listener: TcpListener,
+
+    /// Used to control our Player
+    pub(crate) player_tx: mpsc::Sender<Command>,
+
+    /// Source of the `/events` stream.
+    pub(crate) events: EventBus,
+
+    /// Served at `/metrics`.
+    pub(crate) metrics: Arc<Metrics>,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `Http` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl Http {
+    pub(crate) async fn run(self) -> crate::result::Result<()> {
+        let Http {
+            listener,
+            player_tx,
+            events,
+            metrics,
+            mut shutdown,
+            _shutdown_complete,
+        } = self;
+
+        // Event streams never end on their own, dropping `closing_tx` ends
+        // them to allow for a graceful shutdown.
+        let (closing_tx, closing) = watch::channel(());
+        let state = AppState {
+            player_tx,
+            events,
+            metrics,
+            closing,
+        };
+
+        let app = Router::new()
+            .route("/status", get(status))
+            .route("/artwork", get(artwork))
+            .route("/volume", post(set_volume))
+            .route("/mute", post(set_muted))
+            .route("/pause", post(set_paused))
+            .route("/disconnect", post(disconnect))
+            .route("/remote/:command", post(remote_control))
+            .route("/events", get(events_stream))
+            .route("/metrics", get(metrics_text))
+            .with_state(state);
+
+        axum::serve(listener, app)
+            .with_graceful_shutdown(async move {
+                shutdown.recv().await;
+                drop(closing_tx);
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct VolumeRequest {
+    volume: f32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MuteRequest {
+    muted: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct PauseRequest {
+    paused: bool,
+}
+
+#[derive(Clone)]
+struct AppState {
+    player_tx: mpsc::Sender<Command>,
+    events: EventBus,
+    metrics: Arc<Metrics>,
+    closing: watch::Receiver<()>,
+}
+
+async fn status(State(state): State<AppState>) -> Result<Json<Status>, StatusCode> {
+    let status = request(&state.player_tx, |resp| Command::GetStatus { resp }).await?;
+    Ok(Json(status))
+}
+
+async fn artwork(State(state): State<AppState>) -> Result<Response, StatusCode> {
+    let artwork = request(&state.player_tx, |resp| Command::GetArtwork { resp })
+        .await?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(([(header::CONTENT_TYPE, artwork.content_type)], artwork.data).into_response())
+}
+
+async fn set_volume(
+    State(state): State<AppState>,
+    Json(body): Json<VolumeRequest>,
+) -> Result<StatusCode, StatusCode> {
+    if !(0.0..=1.0).contains(&body.volume) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    request(&state.player_tx, |resp| Command::SetLocalVolume {
+        volume: body.volume,
+        resp,
+    })
+    .await?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_muted(
+    State(state): State<AppState>,
+    Json(body): Json<MuteRequest>,
+) -> Result<StatusCode, StatusCode> {
+    request(&state.player_tx, |resp| Command::SetMuted {
+        muted: body.muted,
+        resp,
+    })
+    .await?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_paused(
+    State(state): State<AppState>,
+    Json(body): Json<PauseRequest>,
+) -> Result<StatusCode, StatusCode> {
+    request(&state.player_tx, |resp| Command::SetPaused {
+        paused: body.paused,
+        resp,
+    })
+    .await?
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disconnect(State(state): State<AppState>) -> Result<StatusCode, StatusCode> {
+    match request(&state.player_tx, |resp| Command::Disconnect { resp }).await? {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(StatusCode::NOT_FOUND),
+    }
+}
+
+async fn remote_control(
+    State(state): State<AppState>,
+    Path(command): Path<RemoteCommand>,
+) -> Result<StatusCode, StatusCode> {
+    request(&state.player_tx, |resp| Command::RemoteControl {
+        command,
+        resp,
+    })
+    .await?
+    .map_err(|_| StatusCode::BAD_GATEWAY)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn events_stream(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Sse<impl Stream<Item = Result<sse::Event, axum::Error>>> {
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok());
+    let (backlog, rx) = state.events.subscribe(last_id);
+
+    // A lagging subscriber ends its stream, it resumes from the last event it
+    // received when reconnecting.
+    let live = BroadcastStream::new(rx).map_while(Result::ok);
+
+    let mut closing = state.closing;
+    let stream = tokio_stream::iter(backlog).chain(live).map(|notification| {
+        sse::Event::default()
+            .id(notification.id.to_string())
+            .json_data(&notification.event)
+    });
+    let stream = futures_util::StreamExt::take_until(stream, async move {
+        let _ = closing.changed().await;
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+async fn metrics_text(State(state): State<AppState>) -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+        .into_response()
+}
+
+/// Sends a command to the `Player` and waits for its response.
+async fn request<T>(
+    player_tx: &mpsc::Sender<Command>,
+    command: impl FnOnce(oneshot::Sender<T>) -> Command,
+) -> Result<T, StatusCode> {
+    let (tx, rx) = oneshot::channel();
+    player_tx
+        .send(command(tx))
+        .await
+        .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+    rx.await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dmap::Metadata;
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+        sync::broadcast,
+    };
+
+    /// Answers the commands of `Http` like the `Player` without a session.
+    async fn player(mut player_rx: mpsc::Receiver<Command>) {
+        let mut local_volume = 1.0;
+        while let Some(command) = player_rx.recv().await {
+            match command {
+                Command::GetStatus { resp } => {
+                    let _ = resp.send(Status {
+                        name: "Airguitar".into(),
+                        session: None,
+                        volume: 0.0,
+                        local_volume,
+                        muted: false,
+                        paused: false,
+                        metadata: Metadata::default(),
+                        progress: None,
+                        artwork: None,
+                    });
+                }
+                Command::GetArtwork { resp } => {
+                    let _ = resp.send(None);
+                }
+                Command::SetLocalVolume { volume, resp } => {
+                    local_volume = volume;
+                    let _ = resp.send(Ok(()));
+                }
+                Command::Disconnect { resp } => {
+                    let _ = resp.send(false);
+                }
+                command => panic!("unexpected command {:?}", command),
+            }
+        }
+    }
+
+    /// Sends a single request, returns the status code and body of the
+    /// response.
+    async fn send(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+             Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, body.to_string())
+    }
+
+    #[tokio::test]
+    async fn serves_status_and_controls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (player_tx, player_rx) = mpsc::channel(4);
+        tokio::spawn(player(player_rx));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let http = Http {
+            listener,
+            player_tx,
+            events: EventBus::new(),
+            metrics: Arc::new(Metrics::new()),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        let task = tokio::spawn(http.run());
+
+        let (status, body) = send(addr, "GET", "/status", "").await;
+        assert_eq!(status, 200);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["name"], "Airguitar");
+        assert_eq!(body["session"], serde_json::Value::Null);
+        assert_eq!(body["local_volume"], 1.0);
+
+        let (status, _) = send(addr, "POST", "/volume", r#"{"volume": 1.5}"#).await;
+        assert_eq!(status, 422);
+        let (status, _) = send(addr, "POST", "/volume", r#"{"volume": -0.5}"#).await;
+        assert_eq!(status, 422);
+        let (status, _) = send(addr, "POST", "/volume", r#"{"volume": 0.5}"#).await;
+        assert_eq!(status, 204);
+        let (_, body) = send(addr, "GET", "/status", "").await;
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["local_volume"], 0.5);
+
+        let (status, _) = send(addr, "POST", "/disconnect", "").await;
+        assert_eq!(status, 404);
+        let (status, _) = send(addr, "GET", "/artwork", "").await;
+        assert_eq!(status, 404);
+
+        notify_shutdown.send(()).unwrap();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,165 @@
+use crate::stats;
+use clap::ValueEnum;
+use std::{fs::OpenOptions, io, path::PathBuf, sync::Mutex};
+use tracing_subscriber::{
+    fmt,
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+/// Subscriber the output layer gets stacked upon.
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+/// Subscriber writing the filtered log lines.
+type Subscriber = Layered<Box<dyn Layer<Filtered> + Send + Sync>, Filtered>;
+
+/// Output format of log lines.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum LogFormat {
+    /// Human readable lines
+    #[default]
+    Human,
+    /// One JSON object per line, including the fields of all enclosing spans
+    Json,
+}
+
+/// Where and what to log.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct LogConfig {
+    pub(crate) format: LogFormat,
+    /// Level or filter directives in the syntax of `RUST_LOG` (e.g. `info` or
+    /// `airguitar=debug,mdns_sd=warn`), taking precedence over it.
+    pub(crate) level: Option<String>,
+    /// File log lines are appended to instead of writing them to stdout.
+    pub(crate) file: Option<PathBuf>,
+    /// Playback statistics are logged whatever the level, they are only
+    /// produced when asked for with `stats_interval`.
+    pub(crate) stats: bool,
+}
+
+/// Changes the level of the installed log subscriber.
+pub(crate) struct LogHandle(reload::Handle<EnvFilter, Registry>);
+
+impl LogHandle {
+    pub(crate) fn reload(&self, config: &LogConfig) -> crate::result::Result<()> {
+        self.0.reload(filter(config)?)?;
+        Ok(())
+    }
+}
+
+/// Installs the global log subscriber.
+///
+/// Without a configured level and `RUST_LOG` only errors are logged.
+pub(crate) fn init(config: &LogConfig) -> crate::result::Result<LogHandle> {
+    let (subscriber, handle) = subscriber(config)?;
+    subscriber.try_init()?;
+    Ok(handle)
+}
+
+fn subscriber(config: &LogConfig) -> crate::result::Result<(Subscriber, LogHandle)> {
+    let (filter, handle) = reload::Layer::new(filter(config)?);
+
+    let output: Box<dyn Layer<Filtered> + Send + Sync> = match (config.format, &config.file) {
+        (LogFormat::Human, None) => Box::new(fmt::layer().with_writer(io::stdout)),
+        (LogFormat::Json, None) => Box::new(
+            fmt::layer()
+                .json()
+                .with_current_span(false)
+                .with_writer(io::stdout),
+        ),
+        (format, Some(path)) => {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let layer = fmt::layer().with_ansi(false).with_writer(Mutex::new(file));
+            match format {
+                LogFormat::Human => Box::new(layer),
+                LogFormat::Json => Box::new(layer.json().with_current_span(false)),
+            }
+        }
+    };
+
+    let subscriber = tracing_subscriber::registry().with(filter).with(output);
+    Ok((subscriber, LogHandle(handle)))
+}
+
+fn filter(config: &LogConfig) -> crate::result::Result<EnvFilter> {
+    let filter = match config.level {
+        Some(ref filter) => EnvFilter::try_new(filter)?,
+        None => EnvFilter::from_default_env(),
+    };
+    match config.stats {
+        true => Ok(filter.add_directive(format!("{}=info", stats::LOG_TARGET).parse()?)),
+        false => Ok(filter),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::fs;
+    use tracing::{debug, info, warn};
+
+    #[test]
+    fn parses_formats_and_filters() {
+        assert_eq!(LogFormat::from_str("json", false), Ok(LogFormat::Json));
+        assert_eq!(LogFormat::from_str("human", false), Ok(LogFormat::Human));
+        assert!(LogFormat::from_str("xml", false).is_err());
+
+        for level in ["info", "airguitar=debug,mdns_sd=warn"] {
+            let config = LogConfig {
+                level: Some(level.into()),
+                ..LogConfig::default()
+            };
+            assert!(filter(&config).is_ok(), "{}", level);
+        }
+        let config = LogConfig {
+            level: Some("airguitar=loud".into()),
+            ..LogConfig::default()
+        };
+        assert!(filter(&config).is_err());
+    }
+
+    #[test]
+    fn reloads_the_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("airguitar.log");
+        let mut config = LogConfig {
+            format: LogFormat::Json,
+            level: Some("warn".into()),
+            file: Some(path.clone()),
+            stats: true,
+        };
+        let (subscriber, handle) = subscriber(&config).unwrap();
+
+        tracing::subscriber::with_default(subscriber, || {
+            info!("hidden");
+            warn!("shown");
+            info!(target: stats::LOG_TARGET, "statistics");
+
+            config.level = Some("debug".into());
+            handle.reload(&config).unwrap();
+            debug!("shown after reloading");
+
+            config.level = Some("airguitar=loud".into());
+            assert!(handle.reload(&config).is_err());
+            debug!("still shown");
+        });
+
+        let messages = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap()["fields"]["message"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "shown",
+                "statistics",
+                "shown after reloading",
+                "still shown"
+            ]
+        );
+    }
+}
@@ -1,57 +1,381 @@
 mod base64;
+mod config;
+mod dacp;
+mod device_id;
+mod dmap;
 mod error;
+mod events;
+mod hooks;
+mod http;
+mod logging;
 mod mdns;
+#[cfg(unix)]
+mod metadata_pipe;
+mod metrics;
+mod mpris;
+mod mqtt;
+mod network;
+mod now_playing;
+mod output;
 mod player;
 mod result;
 mod rtp_info;
 mod rtsp;
 mod server;
 mod shutdown;
+mod stats;
+mod systemd;
 
-use clap::{crate_version, Parser};
-use md5::{Digest, Md5};
-use std::sync::Arc;
-use tokio::{net::TcpListener, signal};
-use tracing_subscriber;
+use clap::{builder::BoolishValueParser, crate_version, Parser, Subcommand};
+use device_id::DeviceId;
+use hooks::HookConfig;
+use logging::{LogConfig, LogFormat};
+use mqtt::MqttConfig;
+use network::{NetworkConfig, PortRange};
+use output::SampleFormat;
+use player::{ChannelMode, Filter, VolumePolicy};
+use std::{
+    collections::BTreeMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use systemd::Systemd;
+use tokio::{signal, sync::watch};
+use tracing::info;
 
-#[tokio::main]
-async fn main() -> crate::result::Result<()> {
-    tracing_subscriber::fmt::try_init()?;
+fn main() -> crate::result::Result<()> {
+    // Taken over before starting the threads of the runtime.
+    let systemd = Systemd::from_env();
 
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(systemd))
+}
+
+async fn run(mut systemd: Systemd) -> crate::result::Result<()> {
     let cli_opts = CliOpts::parse();
-    let name_digest = Md5::digest(cli_opts.name.as_bytes());
-
-    let config = Configuration {
-        port: cli_opts.port,
-        name: cli_opts.name,
-        hw_addr: [
-            name_digest[0],
-            name_digest[1],
-            name_digest[2],
-            name_digest[3],
-            name_digest[4],
-            name_digest[5],
-        ],
+
+    // Does not depend on the configuration.
+    if let Some(CliCommand::ListDevices) = cli_opts.command {
+        return output::list_devices();
+    }
+
+    // Logging is configured by the file as well, report problems with it
+    // directly.
+    let configs = match Configuration::load(&cli_opts) {
+        Ok(configs) => configs,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            std::process::exit(1);
+        }
     };
 
-    let listener = TcpListener::bind(&format!("0.0.0.0:{}", config.port)).await?;
-    server::run(Arc::new(config), listener, signal::ctrl_c()).await
+    if let Some(CliCommand::AvahiService { ref receiver }) = cli_opts.command {
+        let config = match receiver {
+            Some(name) => configs
+                .iter()
+                .find(|config| &config.name == name)
+                .ok_or_else(|| format!("no receiver named {:?}", name))?,
+            None if configs.len() > 1 => {
+                return Err("several receivers are configured, choose one with `--receiver`".into())
+            }
+            None => &configs[0],
+        };
+        print!("{}", mdns::avahi_service(config)?);
+        return Ok(());
+    }
+
+    // All receivers share the log of the first one.
+    let log = logging::init(&configs[0].log)?;
+    if let Some(ref path) = configs[0].config_file {
+        info!("loaded configuration from {}", path.display());
+    }
+    for config in &configs {
+        info!(
+            "device id of {:?} is {}",
+            config.name,
+            device_id::format(&config.hw_addr)
+        );
+    }
+
+    // Sockets of a systemd socket unit take the place of the configured ones,
+    // with several receivers each takes the ones of its port.
+    let mut passed = systemd.listeners()?;
+    if !passed.is_empty() {
+        info!("taking over the sockets passed by systemd");
+    }
+    let single = configs.len() == 1;
+    let mut config_txs = Vec::new();
+    let mut receivers = Vec::new();
+    for config in configs {
+        let (own, rest) = passed.into_iter().partition::<Vec<_>, _>(|listener| {
+            single || listener.local_addr().map(|addr| addr.port()).ok() == Some(config.port)
+        });
+        passed = rest;
+        let listeners = match own.is_empty() {
+            true => network::bind(&config.network, config.port)?,
+            false => own,
+        };
+
+        let (config_tx, config_rx) = watch::channel(Arc::new(config));
+        config_txs.push(config_tx);
+        receivers.push((config_rx, listeners));
+    }
+    if let Some(listener) = passed.first() {
+        return Err(format!(
+            "socket {} passed by systemd matches the port of no receiver",
+            listener.local_addr()?
+        )
+        .into());
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(async move {
+        if let Err(err) = config::reload_on_hangup(cli_opts, config_txs, log).await {
+            tracing::error!(cause = %err, "configuration reload failed");
+        }
+    });
+    #[cfg(not(unix))]
+    let _ = (config_txs, log);
+
+    server::run_receivers(receivers, &systemd, signal::ctrl_c()).await
 }
 
+/// Command line options, each of them can be given in the environment as well
+/// (e.g. `AIRGUITAR_NAME`).
+///
+/// Options override the values of the configuration file, see `config`. Flags
+/// take an optional value to override it either way (e.g. `--mpris=false`).
+///
+/// With several receivers in the configuration file, options apply to all of
+/// them. `--name`, `--port`, `--device-id`, `--http-address`,
+/// `--metadata-pipe`, `--now-playing-file` and `--mqtt-topic` have to be set
+/// per receiver in the file then.
 #[derive(Debug, Parser)]
 #[clap(version = crate_version!(), author = "Stefan Stüben <msnexploder@gmail.com>")]
 pub(crate) struct CliOpts {
-    /// Listening port (0 means random free port)
-    #[clap(short, long, default_value = "0")]
-    port: u16,
-    /// Service name to identify this player
-    #[clap(short, long, default_value = "Airguitar")]
-    name: String,
+    /// Configuration file (defaults to `airguitar/config.toml` in
+    /// `$XDG_CONFIG_HOME` or `~/.config` if it exists, otherwise to
+    /// `/etc/airguitar/config.toml`)
+    #[clap(short, long, env = "AIRGUITAR_CONFIG")]
+    config: Option<PathBuf>,
+    /// Listening port (0 means random free port), single receiver only
+    /// [default: 5000]
+    #[clap(short, long, env = "AIRGUITAR_PORT")]
+    port: Option<u16>,
+    /// Network interface to listen on with all of its IPv4 and IPv6 addresses,
+    /// can be given multiple times (listens on all interfaces by default).
+    /// mDNS only answers on these interfaces, announcing the addresses
+    /// listened on
+    #[clap(short, long, env = "AIRGUITAR_INTERFACE", value_delimiter = ',')]
+    interface: Vec<String>,
+    /// Address to listen on, can be given multiple times (e.g. `192.168.1.10`
+    /// or `fd00::10`). mDNS only answers on the interfaces of the addresses,
+    /// announcing these
+    #[clap(long, env = "AIRGUITAR_LISTEN_ADDRESS", value_delimiter = ',')]
+    listen_address: Vec<IpAddr>,
+    /// Range of local UDP ports for the audio, control and timing sockets of a
+    /// session, each session needs three (e.g. `6000-6009`, random ports by
+    /// default)
+    #[clap(long, env = "AIRGUITAR_UDP_PORTS")]
+    udp_ports: Option<PortRange>,
+    /// Service name to identify this player, single receiver only [default:
+    /// Airguitar]
+    #[clap(short, long, env = "AIRGUITAR_NAME")]
+    name: Option<String>,
+    /// Password senders have to enter to stream to this player
+    #[clap(long, env = "AIRGUITAR_PASSWORD")]
+    password: Option<String>,
+    /// Id identifying this player towards senders, a MAC address (e.g.
+    /// `AA:BB:CC:DD:EE:FF`), `interface:<name>` for the MAC address of a
+    /// network interface, `name` for an id derived from the name when first
+    /// started or `random` for a random one, each kept in the state directory
+    /// separately. Without a state directory, `name` derives the id from the
+    /// current name.
+    /// Single receiver only [default: name]
+    #[clap(long, env = "AIRGUITAR_DEVICE_ID")]
+    device_id: Option<DeviceId>,
+    /// Directory for persistent state like the generated device id (defaults
+    /// to `$STATE_DIRECTORY` of systemd, otherwise to `airguitar` in
+    /// `$XDG_STATE_HOME` or `~/.local/state`)
+    #[clap(long, env = "AIRGUITAR_STATE_DIR")]
+    state_dir: Option<PathBuf>,
+    /// Do not announce the player with the built-in mDNS responder, e.g. when
+    /// avahi-daemon announces it (see `avahi-service`)
+    #[clap(long, env = "AIRGUITAR_NO_MDNS", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    no_mdns: Option<bool>,
+    /// Output device name or index (see `list-devices`)
+    #[clap(short, long, env = "AIRGUITAR_DEVICE")]
+    device: Option<String>,
+    /// Output sample rate in Hz (defaults to the native rate of the output device)
+    #[clap(short = 'r', long, env = "AIRGUITAR_SAMPLE_RATE")]
+    sample_rate: Option<u32>,
+    /// Output sample format (defaults to the native format of the output device)
+    #[clap(short, long, value_enum, env = "AIRGUITAR_FORMAT")]
+    format: Option<SampleFormat>,
+    /// Mapping of the left and right channel onto the output channels [default:
+    /// stereo]
+    #[clap(long, value_enum, env = "AIRGUITAR_CHANNEL_MAP")]
+    channel_map: Option<ChannelMode>,
+    /// Number of output channels (the mapped channels are repeated to fill them)
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), env = "AIRGUITAR_OUTPUT_CHANNELS")]
+    output_channels: Option<u16>,
+    /// Equalizer filter applied to the output, can be given multiple times
+    /// (e.g. `type=peaking,freq=1000,gain=-3,q=1.4,channel=0`, types are
+    /// peaking, lowshelf, highshelf, lowpass and highpass)
+    #[clap(long, env = "AIRGUITAR_FILTER", value_delimiter = ';')]
+    filter: Vec<Filter>,
+    /// Latency in frames reported to senders in the `Audio-Latency` header
+    /// [default: 11025]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..), env = "AIRGUITAR_LATENCY")]
+    latency: Option<u32>,
+    /// Milliseconds the playback is delayed by, negative values play the audio
+    /// early (e.g. to compensate for a slow amplifier) [default: 0]
+    #[clap(long, allow_negative_numbers = true, env = "AIRGUITAR_LATENCY_OFFSET")]
+    latency_offset: Option<i32>,
+    /// Maximum number of audio packets buffered for playback, older packets
+    /// get dropped [default: 1024]
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), env = "AIRGUITAR_MAX_BUFFERED_PACKETS")]
+    max_buffered_packets: Option<u16>,
+    /// Output volume (`0.0` to `1.0`) at the full volume of the sender
+    /// [default: 1.0]
+    #[clap(long, value_parser = parse_volume, env = "AIRGUITAR_MAX_VOLUME")]
+    max_volume: Option<f32>,
+    /// Play at full volume whatever volume the sender requests
+    #[clap(long, env = "AIRGUITAR_IGNORE_SENDER_VOLUME", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    ignore_sender_volume: Option<bool>,
+    /// Address of the HTTP status and control API, also serving Prometheus
+    /// metrics at `/metrics` (e.g. `127.0.0.1:8080`, disabled by default),
+    /// single receiver only
+    #[clap(long, env = "AIRGUITAR_HTTP_ADDRESS")]
+    http_address: Option<SocketAddr>,
+    /// Named pipe receiving metadata in the format of shairport-sync (created
+    /// if it does not exist), single receiver only
+    #[clap(long, env = "AIRGUITAR_METADATA_PIPE")]
+    metadata_pipe: Option<PathBuf>,
+    /// Expose the player via MPRIS on the D-Bus session bus
+    #[clap(long, env = "AIRGUITAR_MPRIS", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    mpris: Option<bool>,
+    /// JSON file kept up to date with the state, sender, volume, metadata and
+    /// position of the player, artwork is stored next to it. Single receiver
+    /// only
+    #[clap(long, env = "AIRGUITAR_NOW_PLAYING_FILE")]
+    now_playing_file: Option<PathBuf>,
+    /// Host of the MQTT broker receiving the player state (disabled by
+    /// default)
+    #[clap(long, env = "AIRGUITAR_MQTT_HOST")]
+    mqtt_host: Option<String>,
+    /// Port of the MQTT broker [default: 1883]
+    #[clap(long, env = "AIRGUITAR_MQTT_PORT")]
+    mqtt_port: Option<u16>,
+    /// Username for the MQTT broker
+    #[clap(long, env = "AIRGUITAR_MQTT_USERNAME")]
+    mqtt_username: Option<String>,
+    /// Password for the MQTT broker
+    #[clap(long, env = "AIRGUITAR_MQTT_PASSWORD")]
+    mqtt_password: Option<String>,
+    /// Prefix of all published and subscribed MQTT topics, single receiver
+    /// only [default: airguitar]
+    #[clap(long, env = "AIRGUITAR_MQTT_TOPIC")]
+    mqtt_topic: Option<String>,
+    /// Command run when a sender connects (details about the session are passed
+    /// as `AIRGUITAR_*` environment variables)
+    #[clap(long, env = "AIRGUITAR_ON_SESSION_START")]
+    on_session_start: Option<String>,
+    /// Command run when a sender starts streaming audio
+    #[clap(long, env = "AIRGUITAR_ON_PLAYBACK_START")]
+    on_playback_start: Option<String>,
+    /// Command run when a sender flushes its audio, e.g. when skipping a track
+    #[clap(long, env = "AIRGUITAR_ON_FLUSH")]
+    on_flush: Option<String>,
+    /// Command run when a sender disconnects
+    #[clap(long, env = "AIRGUITAR_ON_SESSION_END")]
+    on_session_end: Option<String>,
+    /// Command run when a sender changes the volume
+    #[clap(long, env = "AIRGUITAR_ON_VOLUME_CHANGE")]
+    on_volume_change: Option<String>,
+    /// Seconds after which hook commands get killed [default: 10]
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), env = "AIRGUITAR_HOOK_TIMEOUT")]
+    hook_timeout: Option<u64>,
+    /// Delay the playback until the playback start command completed
+    #[clap(long, env = "AIRGUITAR_WAIT_FOR_PLAYBACK_START_HOOK", num_args = 0..=1, require_equals = true, default_missing_value = "true", value_parser = BoolishValueParser::new())]
+    wait_for_playback_start_hook: Option<bool>,
+    /// Log playback statistics every given number of seconds while a sender is
+    /// connected, whatever the log level
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), env = "AIRGUITAR_STATS_INTERVAL")]
+    stats_interval: Option<u64>,
+    /// Format of the log output [default: human]
+    #[clap(long, value_enum, env = "AIRGUITAR_LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+    /// Log level or filter directives in the syntax of `RUST_LOG`, which is
+    /// used otherwise (e.g. `info` or `airguitar=debug`, defaults to `error`)
+    #[clap(long, env = "AIRGUITAR_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Append log output to the given file instead of writing it to stdout
+    #[clap(long, env = "AIRGUITAR_LOG_FILE")]
+    log_file: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<CliCommand>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Subcommand)]
+pub(crate) enum CliCommand {
+    /// List available output devices with their supported configurations
+    ListDevices,
+    /// Print an avahi-daemon service file announcing the player, for use with
+    /// `--no-mdns` (e.g. `/etc/avahi/services/airguitar.service`)
+    AvahiService {
+        /// Name of the receiver to announce, required with several receivers
+        #[clap(long)]
+        receiver: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct Configuration {
+    /// File the configuration was loaded from, if any.
+    config_file: Option<PathBuf>,
     port: u16,
+    network: NetworkConfig,
+    udp_ports: Option<PortRange>,
     name: String,
+    /// Device id, see `DeviceId`.
     hw_addr: [u8; 6],
+    device: Option<String>,
+    sample_rate: Option<u32>,
+    sample_format: Option<SampleFormat>,
+    channel_mode: ChannelMode,
+    output_channels: Option<u16>,
+    filters: Vec<Filter>,
+    /// Latency in frames reported to senders.
+    latency: u32,
+    /// Milliseconds the playback is delayed by.
+    latency_offset: i32,
+    max_buffered_packets: u16,
+    volume: VolumePolicy,
+    /// Password required from senders, if any.
+    password: Option<String>,
+    /// Announce the player with the built-in mDNS responder.
+    mdns: bool,
+    /// Additional or overridden TXT records of the mDNS service.
+    txt_records: BTreeMap<String, String>,
+    http_address: Option<SocketAddr>,
+    metadata_pipe: Option<PathBuf>,
+    mpris: bool,
+    now_playing_file: Option<PathBuf>,
+    mqtt: Option<MqttConfig>,
+    hooks: HookConfig,
+    stats_interval: Option<Duration>,
+    log: LogConfig,
+}
+
+fn parse_volume(input: &str) -> Result<f32, String> {
+    match input.parse() {
+        Ok(volume) if (0.0..=1.0).contains(&volume) => Ok(volume),
+        _ => Err("expected a number between 0.0 and 1.0".into()),
+    }
 }
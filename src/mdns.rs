@@ -1,74 +1,571 @@
-use crate::{shutdown::Shutdown, Configuration};
-use std::sync::Arc;
-use tokio::sync::mpsc;
+use crate::{player, shutdown::Shutdown, Configuration};
+use mdns_sd::{DaemonEvent, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{
+    collections::{BTreeMap, HashSet},
+    future,
+    net::IpAddr,
+    sync::Arc,
+};
+use tokio::sync::watch;
+use tokio_stream::{wrappers::WatchStream, StreamExt, StreamMap};
+use tracing::{error, info, warn};
 
-pub(crate) struct Mdns {
-    /// App configuration.
-    pub(crate) config: Arc<Configuration>,
+const RAOP_SERVICE_TYPE: &str = "_raop._tcp.local.";
+
+/// Other players found while browsing, their device id and name by the full
+/// name of their service.
+type Players = BTreeMap<String, (String, String)>;
+
+/// Model announced in the `am` TXT record.
+const MODEL: &str = "Airguitar";
+
+/// A receiver announced as a service of its own.
+pub(crate) struct Receiver {
+    /// Configuration of the receiver, the service is registered again when its
+    /// name or TXT records change, e.g. when the password is set.
+    pub(crate) config: watch::Receiver<Arc<Configuration>>,
 
-    /// Our advertised port.
+    /// Receives the name the receiver is announced as.
+    pub(crate) name_tx: watch::Sender<String>,
+
+    /// Advertised port of the receiver.
     pub(crate) port: u16,
+}
+
+/// Announces all receivers and looks for other players with a single mDNS
+/// daemon.
+///
+/// The services are registered with the configured names right away. Other
+/// players are browsed for as long as they are registered, a receiver is
+/// renamed with a suffix as soon as another player announces its name. Players
+/// starting at the same time may announce the same name until they found each
+/// other, only the one with the lowest device id keeps it then. Services with
+/// the same full name, i.e. the same device id as well, are renamed by the
+/// probing of the daemon.
+///
+/// Browsing only helps to avoid name conflicts, if it fails the services stay
+/// registered with the names chosen so far.
+pub(crate) struct Mdns {
+    /// Receivers to announce.
+    pub(crate) receivers: Vec<Receiver>,
+
+    /// Addresses announced for the services, empty for all addresses of the
+    /// host.
+    pub(crate) addresses: Vec<IpAddr>,
+
+    /// Registers the services and browses for other players, shared with
+    /// `Dacp`. Required if any of the `receivers` is announced.
+    pub(crate) daemon: Option<ServiceDaemon>,
 
     /// Listen for shutdown notifications.
-    ///
-    /// A wrapper around the `broadcast::Receiver` paired with the sender in
-    /// `Listener`. The connection handler processes requests from the
-    /// connection until the peer disconnects **or** a shutdown notification is
-    /// received from `shutdown`. In the latter case, any in-flight work being
-    /// processed for the peer is continued until it reaches a safe state, at
-    /// which point the connection is terminated.
     pub(crate) shutdown: Shutdown,
+}
+
+/// Registers services with the mDNS daemon.
+struct Responder {
+    daemon: ServiceDaemon,
+    host_name: String,
+    addresses: Vec<IpAddr>,
+}
 
-    /// Not used directly. Instead, when `Handler` is dropped...
-    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+/// Service registered for a receiver.
+struct Registered {
+    config: Arc<Configuration>,
+    name: String,
+    /// Full name of the service as registered, `None` if registering failed.
+    fullname: Option<String>,
 }
 
 impl Mdns {
     pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
-        let hw_addr = self
-            .config
-            .hw_addr
-            .iter()
-            .map(|f| format!("{:02X}", f))
-            .collect::<String>();
-
-        let (responder, task) = libmdns::Responder::with_default_handle()?;
-        let _service = responder.register(
-            "_raop._tcp".into(),
-            format!("{}@{}", hw_addr, self.config.name),
-            self.port,
-            &[
-                "sf=0x4",
-                "fv=76400.10",
-                "am=Airguitar",
-                "vs=105.1",
-                "tp=TCP,UDP",
-                "vn=65537",
-                "ss=16",
-                "sr=44100",
-                "da=true",
-                "sv=false",
-                "et=0,1",
-                "ek=1",
-                "cn=0,1",
-                "ch=2",
-                "txtvers=1",
-                "pw=false",
-            ],
-        );
+        self.receivers.retain(|receiver| {
+            let config = receiver.config.borrow();
+            if !config.mdns {
+                info!(
+                    "mdns disabled, {:?} has to be announced otherwise",
+                    config.name
+                );
+            }
+            config.mdns
+        });
+        if self.receivers.is_empty() {
+            self.shutdown.recv().await;
+            return Ok(());
+        }
+
+        let host_name = hostname::get()?;
+        // Only the first label of a fully qualified host name.
+        let host_name = host_name.to_string_lossy();
+        let host_name = host_name.split('.').next().unwrap_or_default();
+        let responder = Responder {
+            daemon: self.daemon.clone().ok_or("no mdns daemon")?,
+            host_name: format!("{}.local.", host_name),
+            addresses: self.addresses.clone(),
+        };
+
+        self.announce(&responder).await;
+        Ok(())
+    }
 
-        tokio::spawn(task);
+    async fn announce(&mut self, responder: &Responder) {
+        // Other players are browsed for as long as the services are
+        // registered, to notice names taken after announcing ours as well.
+        let mut browser = responder
+            .daemon
+            .browse(RAOP_SERVICE_TYPE)
+            .map_err(|err| error!(cause = %err, "failed to browse for other players"))
+            .ok();
+        // Tells about names changed by the daemon while probing.
+        let mut monitor = responder
+            .daemon
+            .monitor()
+            .map_err(|err| error!(cause = %err, "failed to monitor the mdns daemon"))
+            .ok();
+        let mut players = Players::new();
 
-        while !self.shutdown.is_shutdown() {
+        let mut registered = Vec::new();
+        for receiver in &mut self.receivers {
+            let config = receiver.config.borrow_and_update().clone();
+            let name = config.name.clone();
+            registered.push(receiver.register(responder, config, &name, &players));
+        }
+        let mut changes = self
+            .receivers
+            .iter()
+            .map(|receiver| WatchStream::from_changes(receiver.config.clone()))
+            .enumerate()
+            .collect::<StreamMap<_, _>>();
+
+        // Keep the services registered until a shutdown signal is received.
+        loop {
             tokio::select! {
+                Some((index, new)) = changes.next() => {
+                    let registered = &mut registered[index];
+                    let config = &registered.config;
+                    if (&new.name, new.hw_addr, txt_records(&new))
+                        != (&config.name, config.hw_addr, txt_records(config))
+                    {
+                        // The service has to be unregistered before
+                        // registering the same name again.
+                        responder.unregister(registered);
+                        let name = new.name.clone();
+                        *registered =
+                            self.receivers[index].register(responder, new, &name, &players);
+                        info!("updated mdns service {:?}", registered.name);
+                    } else {
+                        registered.config = new;
+                    }
+                }
+                event = next_event(&mut browser, "browsing for other players") => {
+                    if !update_players(&mut players, event) {
+                        continue;
+                    }
+                    for (receiver, registered) in self.receivers.iter().zip(&mut registered) {
+                        let config = registered.config.clone();
+                        let own_id = hex_id(&config.hw_addr);
+                        let name = registered.name.clone();
+                        if unique_name(&config.name, &own_id, &name, &players) != name {
+                            responder.unregister(registered);
+                            *registered = receiver.register(responder, config, &name, &players);
+                        }
+                    }
+                }
+                event = next_event(&mut monitor, "monitoring the mdns daemon") => {
+                    let DaemonEvent::NameChange(change) = event else {
+                        continue;
+                    };
+                    let Some((_, new_name)) = parse_service_name(&change.new_name) else {
+                        continue;
+                    };
+                    for (receiver, registered) in self.receivers.iter().zip(&mut registered) {
+                        if registered.fullname.as_ref() == Some(&change.original)
+                            && registered.name != new_name
+                        {
+                            warn!(
+                                "name {:?} is taken by another player with the same device id, announcing as {:?}",
+                                registered.name, new_name
+                            );
+                            registered.name = new_name.clone();
+                            receiver.name_tx.send_replace(new_name.clone());
+                        }
+                    }
+                }
                 _ = self.shutdown.recv() => {
-                    // If a shutdown signal is received, return from `run`.
-                    // This will result in the task terminating.
-                    return Ok(());
+                    for registered in &mut registered {
+                        responder.unregister(registered);
+                    }
+                    return;
                 }
-            };
+            }
         }
+    }
+}
 
-        Ok(())
+/// Receives the next event of `receiver`, `what` it is for is logged if it
+/// fails. The returned future never completes without a receiver.
+async fn next_event<T>(receiver: &mut Option<mdns_sd::Receiver<T>>, what: &str) -> T {
+    if let Some(ref events) = receiver {
+        match events.recv_async().await {
+            Ok(event) => return event,
+            Err(err) => {
+                error!(cause = %err, "{} failed", what);
+                *receiver = None;
+            }
+        }
+    }
+    future::pending().await
+}
+
+impl Responder {
+    /// Registers the RAOP service of `config` announced as `name`, returns its
+    /// full name.
+    fn register(
+        &self,
+        config: &Configuration,
+        name: &str,
+        port: u16,
+    ) -> crate::result::Result<String> {
+        let txt_records = txt_records(config);
+        let properties = txt_records
+            .iter()
+            .filter_map(|record| record.split_once('='))
+            .collect::<Vec<_>>();
+        let service = ServiceInfo::new(
+            RAOP_SERVICE_TYPE,
+            &service_name(config, name),
+            &self.host_name,
+            &self.addresses[..],
+            port,
+            &properties[..],
+        )?;
+        // Without addresses, the ones of all interfaces are announced.
+        let service = match self.addresses.is_empty() {
+            true => service.enable_addr_auto(),
+            false => service,
+        };
+        let fullname = service.get_fullname().to_string();
+        self.daemon.register(service)?;
+        Ok(fullname)
+    }
+
+    /// Unregisters the service, if it was registered.
+    fn unregister(&self, registered: &mut Registered) {
+        if let Some(fullname) = registered.fullname.take() {
+            if let Err(err) = self.daemon.unregister(&fullname) {
+                error!(cause = %err, "failed to unregister mdns service {:?}", fullname);
+            }
+        }
+    }
+}
+
+impl Receiver {
+    /// Registers the service of the receiver with the name chosen by
+    /// `unique_name` and passes the name on.
+    fn register(
+        &self,
+        responder: &Responder,
+        config: Arc<Configuration>,
+        current: &str,
+        players: &Players,
+    ) -> Registered {
+        let name = unique_name(&config.name, &hex_id(&config.hw_addr), current, players);
+        if name != config.name {
+            warn!(
+                "name {:?} is taken by another player, announcing as {:?}",
+                config.name, name
+            );
+        }
+        info!("announcing as {:?}", name);
+        self.name_tx.send_replace(name.clone());
+
+        let fullname = responder
+            .register(&config, &name, self.port)
+            .map_err(|err| error!(cause = %err, "failed to register mdns service {:?}", name))
+            .ok();
+        Registered {
+            config,
+            name,
+            fullname,
+        }
+    }
+}
+
+/// Keeps track of the players found while browsing, returns whether they
+/// changed.
+fn update_players(players: &mut Players, event: ServiceEvent) -> bool {
+    match event {
+        ServiceEvent::ServiceFound(_, fullname) => match parse_service_name(&fullname) {
+            Some(player) => players.insert(fullname, player).is_none(),
+            None => false,
+        },
+        ServiceEvent::ServiceRemoved(_, fullname) => players.remove(&fullname).is_some(),
+        _ => false,
+    }
+}
+
+/// Splits the full name of a RAOP service into the device id and the name of
+/// the player, e.g. `AABBCCDDEEFF@Airguitar._raop._tcp.local.`.
+fn parse_service_name(fullname: &str) -> Option<(String, String)> {
+    let (id, name) = fullname
+        .strip_suffix(RAOP_SERVICE_TYPE)?
+        .strip_suffix('.')?
+        .split_once('@')?;
+    Some((id.to_string(), name.to_string()))
+}
+
+/// Returns the configured name, or the first one with a suffix (e.g.
+/// `Airguitar (2)`) which no other player announces. Names are compared
+/// case-insensitively like mDNS does.
+///
+/// Players which started at the same time may announce the same `current`
+/// name, only the one with the lowest device id keeps it.
+fn unique_name(configured: &str, own_id: &str, current: &str, players: &Players) -> String {
+    let taken = players
+        .values()
+        .filter(|(id, name)| {
+            id != own_id && !(id.as_str() > own_id && name.eq_ignore_ascii_case(current))
+        })
+        .map(|(_, name)| name.to_lowercase())
+        .collect::<HashSet<_>>();
+
+    let mut name = configured.to_string();
+    for suffix in 2.. {
+        if !taken.contains(&name.to_lowercase()) {
+            break;
+        }
+        name = format!("{} ({})", configured, suffix);
+    }
+    name
+}
+
+/// Name of the RAOP service, the device id followed by the name of the player.
+fn service_name(config: &Configuration, name: &str) -> String {
+    format!("{}@{}", hex_id(&config.hw_addr), name)
+}
+
+fn hex_id(hw_addr: &[u8; 6]) -> String {
+    hw_addr.iter().map(|f| format!("{:02X}", f)).collect()
+}
+
+/// TXT records of the RAOP service as `key=value`, describing what the player
+/// supports. Records of the configuration take precedence.
+fn txt_records(config: &Configuration) -> Vec<String> {
+    let list = |values: &[u8]| {
+        values
+            .iter()
+            .map(u8::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    };
+
+    let mut txt_records: BTreeMap<String, String> = [
+        ("txtvers", "1".into()),
+        ("am", MODEL.into()),
+        ("vs", "105.1".into()),
+        ("vn", "65537".into()),
+        ("fv", "76400.10".into()),
+        ("sf", "0x4".into()),
+        ("tp", "UDP".into()),
+        ("ch", player::CHANNELS.to_string()),
+        ("ss", player::SAMPLE_SIZE.to_string()),
+        ("sr", player::SAMPLE_RATE.to_string()),
+        ("cn", list(player::CODECS)),
+        ("et", list(player::ENCRYPTION_TYPES)),
+        ("ek", "1".into()),
+        ("md", list(player::METADATA_TYPES)),
+        ("da", "true".into()),
+        ("sv", "false".into()),
+        ("pw", config.password.is_some().to_string()),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value))
+    .collect();
+    txt_records.extend(config.txt_records.clone());
+    txt_records
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect()
+}
+
+/// Service file announcing the player via avahi-daemon, usually stored as
+/// `/etc/avahi/services/airguitar.service`.
+pub(crate) fn avahi_service(config: &Configuration) -> crate::result::Result<String> {
+    if config.port == 0 {
+        return Err("announcing via avahi requires a fixed port, set `--port`".into());
+    }
+
+    let mut service = String::new();
+    service.push_str("<?xml version=\"1.0\" standalone='no'?>\n");
+    service.push_str("<!DOCTYPE service-group SYSTEM \"avahi-service.dtd\">\n");
+    service.push_str("<service-group>\n");
+    service.push_str(&format!(
+        "  <name>{}</name>\n",
+        escape_xml(&service_name(config, &config.name))
+    ));
+    service.push_str("  <service>\n");
+    service.push_str("    <type>_raop._tcp</type>\n");
+    service.push_str(&format!("    <port>{}</port>\n", config.port));
+    for record in txt_records(config) {
+        service.push_str(&format!(
+            "    <txt-record>{}</txt-record>\n",
+            escape_xml(&record)
+        ));
+    }
+    service.push_str("  </service>\n");
+    service.push_str("</service-group>\n");
+
+    Ok(service)
+}
+
+fn escape_xml(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::CliOpts;
+    use clap::Parser;
+
+    /// Loads `contents` as the configuration file of a single receiver.
+    fn load(contents: &str) -> Configuration {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, contents).unwrap();
+
+        let cli = CliOpts::parse_from([
+            "airguitar",
+            "--config",
+            path.to_str().unwrap(),
+            "--state-dir",
+            dir.path().to_str().unwrap(),
+        ]);
+        Configuration::load(&cli).unwrap().remove(0)
+    }
+
+    /// Players found while browsing, as device id and name.
+    fn players(found: &[(&str, &str)]) -> Players {
+        found
+            .iter()
+            .map(|(id, name)| {
+                let fullname = format!("{}@{}.{}", id, name, RAOP_SERVICE_TYPE);
+                (fullname, (id.to_string(), name.to_string()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parses_service_names() {
+        assert_eq!(
+            parse_service_name("AABBCCDDEEFF@Living Room._raop._tcp.local."),
+            Some(("AABBCCDDEEFF".into(), "Living Room".into()))
+        );
+        assert_eq!(parse_service_name("Living Room._raop._tcp.local."), None);
+        assert_eq!(parse_service_name("AABBCCDDEEFF@Living Room"), None);
+    }
+
+    #[test]
+    fn tracks_found_players() {
+        let mut players = Players::new();
+        let fullname = format!("AABBCCDDEEFF@Kitchen.{}", RAOP_SERVICE_TYPE);
+        let found = ServiceEvent::ServiceFound(RAOP_SERVICE_TYPE.into(), fullname.clone());
+        assert!(update_players(&mut players, found));
+        let found = ServiceEvent::ServiceFound(RAOP_SERVICE_TYPE.into(), fullname.clone());
+        assert!(!update_players(&mut players, found));
+        assert_eq!(players.len(), 1);
+
+        let removed = ServiceEvent::ServiceRemoved(RAOP_SERVICE_TYPE.into(), fullname);
+        assert!(update_players(&mut players, removed));
+        assert!(players.is_empty());
+    }
+
+    #[test]
+    fn picks_a_free_name() {
+        let own = "222222222222";
+        assert_eq!(
+            unique_name("Kitchen", own, "Kitchen", &players(&[])),
+            "Kitchen"
+        );
+        // Our own service is found as well.
+        let found = players(&[(own, "Kitchen")]);
+        assert_eq!(unique_name("Kitchen", own, "Kitchen", &found), "Kitchen");
+
+        let found = players(&[("333333333333", "kitchen"), ("444444444444", "KITCHEN (2)")]);
+        assert_eq!(unique_name("Kitchen", own, "", &found), "Kitchen (3)");
+    }
+
+    #[test]
+    fn keeps_the_name_for_the_lowest_device_id() {
+        let lower = "111111111111";
+        let own = "222222222222";
+        let higher = "333333333333";
+
+        let found = players(&[(higher, "Kitchen")]);
+        assert_eq!(unique_name("Kitchen", own, "Kitchen", &found), "Kitchen");
+        let found = players(&[(lower, "Kitchen")]);
+        assert_eq!(
+            unique_name("Kitchen", own, "Kitchen", &found),
+            "Kitchen (2)"
+        );
+        // The name is available again once the other player is gone.
+        assert_eq!(
+            unique_name("Kitchen", own, "Kitchen (2)", &players(&[])),
+            "Kitchen"
+        );
+    }
+
+    #[test]
+    fn writes_avahi_services() {
+        let config = load(
+            r#"
+            name = "Tom & Jerry's <\"Den\">"
+            port = 5001
+            device_id = "AA:BB:CC:DD:EE:FF"
+            [mdns.txt]
+            am = "<Model>"
+            "#,
+        );
+        let service = avahi_service(&config).unwrap();
+
+        assert!(service.starts_with("<?xml version=\"1.0\" standalone='no'?>\n"));
+        assert!(service.contains(
+            "  <name>AABBCCDDEEFF@Tom &amp; Jerry&apos;s &lt;&quot;Den&quot;&gt;</name>\n"
+        ));
+        assert!(service.contains("    <type>_raop._tcp</type>\n    <port>5001</port>\n"));
+        assert!(service.contains("    <txt-record>am=&lt;Model&gt;</txt-record>\n"));
+        assert!(service.contains("    <txt-record>txtvers=1</txt-record>\n"));
+        assert!(service.ends_with("  </service>\n</service-group>\n"));
+
+        let err = avahi_service(&load("port = 0")).unwrap_err().to_string();
+        assert!(err.contains("--port"), "{}", err);
+    }
+
+    #[test]
+    fn describes_the_player_in_txt_records() {
+        let records = txt_records(&load(""));
+        for record in [
+            "ch=2", "ss=16", "sr=44100", "cn=1", "et=1", "md=0,1,2", "pw=false",
+        ] {
+            assert!(records.contains(&record.into()), "{:?}", records);
+        }
+        // Sorted by key, each key once.
+        let keys = records
+            .iter()
+            .map(|record| record.split_once('=').unwrap().0)
+            .collect::<Vec<_>>();
+        assert!(keys.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", keys);
+
+        let records = txt_records(&load("password = \"secret\""));
+        assert!(records.contains(&"pw=true".into()), "{:?}", records);
+
+        let records = txt_records(&load("[mdns.txt]\nam = \"Custom\"\nxx = \"1\""));
+        assert!(records.contains(&"am=Custom".into()), "{:?}", records);
+        assert!(!records.contains(&format!("am={}", MODEL)), "{:?}", records);
+        assert!(records.contains(&"xx=1".into()), "{:?}", records);
     }
 }
@@ -0,0 +1,260 @@
+use crate::{
+    base64::encode_base64_padded,
+    events::{Event, EventBus},
+    shutdown::Shutdown,
+};
+use std::{
+    ffi::CString,
+    io::{self, ErrorKind},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::unix::pipe,
+    sync::{broadcast::error::RecvError, mpsc},
+};
+use tracing::{debug, info};
+
+/// Item type of DAAP metadata.
+const CORE: &[u8; 4] = b"core";
+/// Item type of everything else.
+const SSNC: &[u8; 4] = b"ssnc";
+
+/// Writes events to a named pipe in the metadata format of shairport-sync.
+///
+/// Every item looks like
+///
+/// ```text
+/// <item><type>636f7265</type><code>6d696e6d</code><length>5</length>
+/// <data encoding="base64">
+/// VGl0bGU=</data></item>
+/// ```
+///
+/// with type and code being the hex encoded four character codes. Items
+/// without data omit the `<data>` element. Items are dropped while no reader
+/// has the pipe opened.
+pub(crate) struct MetadataPipe {
+    /// Path of the pipe, created if it does not exist.
+    pub(crate) path: PathBuf,
+
+    /// Source of the written items.
+    pub(crate) events: EventBus,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `MetadataPipe` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl MetadataPipe {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        create_fifo(&self.path)?;
+        info!("writing metadata to {}", self.path.display());
+
+        let (_, mut rx) = self.events.subscribe(None);
+        let mut sender: Option<pipe::Sender> = None;
+
+        while !self.shutdown.is_shutdown() {
+            let notification = tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(notification) => notification,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!(skipped, "metadata pipe lagging behind");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = self.shutdown.recv() => {
+                    return Ok(());
+                }
+            };
+
+            let items = items(&notification.event);
+            if items.is_empty() {
+                continue;
+            }
+
+            if sender.is_none() {
+                sender = match pipe::OpenOptions::new().open_sender(&self.path) {
+                    Ok(sender) => Some(sender),
+                    // Nobody is reading, drop the items.
+                    Err(err) if err.raw_os_error() == Some(libc::ENXIO) => continue,
+                    Err(err) => return Err(err.into()),
+                };
+            }
+
+            if let Some(ref mut writer) = sender {
+                let res = tokio::select! {
+                    res = writer.write_all(&items) => res,
+                    // Do not wait for a stalled reader.
+                    _ = self.shutdown.recv() => return Ok(()),
+                };
+                match res {
+                    Ok(()) => {}
+                    // The reader went away, reopen the pipe with the next event.
+                    Err(err) if err.kind() == ErrorKind::BrokenPipe => sender = None,
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a FIFO at `path` unless there is one already.
+fn create_fifo(path: &Path) -> crate::result::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+
+    // SAFETY: `c_path` is a valid NUL terminated string.
+    if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != ErrorKind::AlreadyExists {
+            return Err(err.into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Formats the items describing an event.
+fn items(event: &Event) -> Vec<u8> {
+    let mut output = Vec::new();
+
+    match event {
+        Event::SessionStarted {
+            client,
+            client_name,
+            user_agent,
+            ..
+        } => {
+            item(
+                &mut output,
+                SSNC,
+                b"clip",
+                client.ip().to_string().as_bytes(),
+            );
+            if let Some(client_name) = client_name {
+                item(&mut output, SSNC, b"snam", client_name.as_bytes());
+            }
+            if let Some(user_agent) = user_agent {
+                item(&mut output, SSNC, b"snua", user_agent.as_bytes());
+            }
+            item(&mut output, SSNC, b"pbeg", &[]);
+        }
+        Event::SessionEnded { .. } => item(&mut output, SSNC, b"pend", &[]),
+        Event::Flush { .. } => item(&mut output, SSNC, b"pfls", &[]),
+        Event::VolumeChanged { volume, gain } => {
+            // AirPlay volume, volume of the output in dB, lowest and highest
+            // volume in dB
+            let gain = match *gain > 0.0 {
+                true => (20.0 * gain.log10()).clamp(-144.0, 0.0),
+                false => -144.0,
+            };
+            let value = format!("{:.2},{:.2},-30.00,0.00", volume, gain);
+            item(&mut output, SSNC, b"pvol", value.as_bytes());
+        }
+        Event::MetadataUpdated { metadata } => {
+            item(&mut output, SSNC, b"mdst", &[]);
+            for dmap_item in &metadata.items {
+                item(&mut output, CORE, &dmap_item.code, &dmap_item.data);
+            }
+            item(&mut output, SSNC, b"mden", &[]);
+        }
+        Event::ArtworkUpdated { data, .. } if !data.is_empty() => {
+            item(&mut output, SSNC, b"PICT", data);
+        }
+        Event::ProgressUpdated { progress } => {
+            let value = format!("{}/{}/{}", progress.start, progress.current, progress.end);
+            item(&mut output, SSNC, b"prgr", value.as_bytes());
+        }
+        Event::PlaybackStarted
+        | Event::ArtworkUpdated { .. }
+        | Event::LocalVolumeChanged { .. }
+        | Event::OutputPaused { .. }
+        | Event::Underrun => {}
+    }
+
+    output
+}
+
+fn item(output: &mut Vec<u8>, item_type: &[u8; 4], code: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(
+        format!(
+            "<item><type>{:08x}</type><code>{:08x}</code><length>{}</length>",
+            u32::from_be_bytes(*item_type),
+            u32::from_be_bytes(*code),
+            data.len()
+        )
+        .as_bytes(),
+    );
+
+    if !data.is_empty() {
+        output.extend_from_slice(b"\n<data encoding=\"base64\">\n");
+        output.extend_from_slice(encode_base64_padded(data).as_bytes());
+        output.extend_from_slice(b"</data>");
+    }
+
+    output.extend_from_slice(b"</item>\n");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn pvol(volume: f64, gain: f32) -> String {
+        let items = items(&Event::VolumeChanged { volume, gain });
+        let data = String::from_utf8(items).unwrap();
+        let data = data.split('\n').nth(2).unwrap();
+        let data = data.strip_suffix("</data></item>").unwrap();
+        String::from_utf8(crate::base64::decode_base64(data).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reports_the_output_volume() {
+        assert_eq!(pvol(-10.0, 1.0), "-10.00,0.00,-30.00,0.00");
+        assert_eq!(pvol(-10.0, 0.1), "-10.00,-20.00,-30.00,0.00");
+        assert_eq!(pvol(-144.0, 0.0), "-144.00,-144.00,-30.00,0.00");
+    }
+
+    #[tokio::test]
+    async fn shuts_down_with_a_stalled_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("metadata");
+        create_fifo(&path).unwrap();
+        // Opened, but never read.
+        let _reader = pipe::OpenOptions::new().open_receiver(&path).unwrap();
+
+        let events = EventBus::new();
+        let (notify_shutdown, _) = tokio::sync::broadcast::channel(1);
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let mut metadata_pipe = MetadataPipe {
+            path,
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        let task = tokio::spawn(async move { metadata_pipe.run().await });
+        tokio::task::yield_now().await;
+
+        // More than the pipe buffers.
+        for _ in 0..4 {
+            events.publish(Event::ArtworkUpdated {
+                content_type: Some("image/jpeg".into()),
+                data: vec![0; 64 * 1024].into(),
+            });
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!task.is_finished());
+
+        notify_shutdown.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("metadata pipe did not shut down")
+            .unwrap()
+            .unwrap();
+    }
+}
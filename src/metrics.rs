@@ -0,0 +1,283 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+/// RTSP methods counted by their name, all others are counted as `other` to
+/// keep the number of label values bounded whatever senders send.
+const RTSP_METHODS: &[&str] = &[
+    "OPTIONS",
+    "ANNOUNCE",
+    "SETUP",
+    "RECORD",
+    "FLUSH",
+    "TEARDOWN",
+    "GET_PARAMETER",
+    "SET_PARAMETER",
+    "POST",
+    "GET",
+];
+
+/// Monotonically increasing count of something.
+#[derive(Debug, Default)]
+pub(crate) struct Counter(AtomicU64);
+
+impl Counter {
+    pub(crate) fn inc(&self) {
+        self.add(1);
+    }
+
+    pub(crate) fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Value which can go up and down.
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicU64);
+
+impl Gauge {
+    pub(crate) fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Receiver metrics, rendered in the Prometheus text format.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+    pub(crate) packets_received: Counter,
+    /// Packets arriving after their playback position was passed.
+    pub(crate) packets_late: Counter,
+    pub(crate) packets_duplicate: Counter,
+    /// Packets skipped because they never arrived.
+    pub(crate) packets_lost: Counter,
+    pub(crate) resend_requests: Counter,
+    pub(crate) resend_requested_packets: Counter,
+    pub(crate) resend_fulfilled_packets: Counter,
+    pub(crate) buffered_packets: Gauge,
+    pub(crate) underruns: Counter,
+    /// Offset of the sender clock to ours in seconds.
+    pub(crate) clock_offset: Gauge,
+    pub(crate) round_trip_time: Gauge,
+    /// Seconds the playback is ahead of the position intended by the sender,
+    /// `NaN` if unknown.
+    pub(crate) sync_error: Gauge,
+    pub(crate) active_sessions: Gauge,
+    /// Handled RTSP requests by method and response status.
+    rtsp_requests: Mutex<BTreeMap<(&'static str, u16), u64>>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Metrics {
+        let metrics = Metrics::default();
+        metrics.sync_error.set(f64::NAN);
+        metrics
+    }
+
+    pub(crate) fn rtsp_request(&self, method: &str, status: u16) {
+        let method = RTSP_METHODS
+            .iter()
+            .find(|known| **known == method)
+            .copied()
+            .unwrap_or("other");
+        let mut rtsp_requests = self.rtsp_requests.lock().unwrap();
+        *rtsp_requests.entry((method, status)).or_default() += 1;
+    }
+
+    /// Renders all metrics in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut output = String::new();
+
+        let counters = [
+            (
+                "airguitar_packets_received_total",
+                "Audio packets received, including resent ones.",
+                &self.packets_received,
+            ),
+            (
+                "airguitar_packets_late_total",
+                "Audio packets received after their playback position.",
+                &self.packets_late,
+            ),
+            (
+                "airguitar_packets_duplicate_total",
+                "Audio packets received more than once.",
+                &self.packets_duplicate,
+            ),
+            (
+                "airguitar_packets_lost_total",
+                "Audio packets skipped because they never arrived.",
+                &self.packets_lost,
+            ),
+            (
+                "airguitar_resend_requests_total",
+                "Resend requests sent to the sender.",
+                &self.resend_requests,
+            ),
+            (
+                "airguitar_resend_requested_packets_total",
+                "Audio packets requested to be resent.",
+                &self.resend_requested_packets,
+            ),
+            (
+                "airguitar_resend_fulfilled_packets_total",
+                "Audio packets resent by the sender.",
+                &self.resend_fulfilled_packets,
+            ),
+            (
+                "airguitar_underruns_total",
+                "Times the output ran out of audio packets.",
+                &self.underruns,
+            ),
+        ];
+        for (name, help, counter) in counters {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} counter", name);
+            let _ = writeln!(output, "{} {}", name, counter.get());
+        }
+
+        let gauges = [
+            (
+                "airguitar_buffered_packets",
+                "Audio packets buffered for playback.",
+                &self.buffered_packets,
+            ),
+            (
+                "airguitar_clock_offset_seconds",
+                "Offset of the sender clock, measured by the timing exchange.",
+                &self.clock_offset,
+            ),
+            (
+                "airguitar_round_trip_time_seconds",
+                "Round trip time to the sender, measured by the timing exchange.",
+                &self.round_trip_time,
+            ),
+            (
+                "airguitar_sync_error_seconds",
+                "Seconds the playback is ahead of the position intended by the sender.",
+                &self.sync_error,
+            ),
+            (
+                "airguitar_active_sessions",
+                "Sessions currently playing to the receiver.",
+                &self.active_sessions,
+            ),
+        ];
+        for (name, help, gauge) in gauges {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            let _ = writeln!(output, "{} {}", name, gauge.get());
+        }
+
+        let name = "airguitar_rtsp_requests_total";
+        let _ = writeln!(
+            output,
+            "# HELP {} RTSP requests by method and status.",
+            name
+        );
+        let _ = writeln!(output, "# TYPE {} counter", name);
+        for ((method, status), count) in self.rtsp_requests.lock().unwrap().iter() {
+            let _ = writeln!(
+                output,
+                "{}{{method=\"{}\",status=\"{}\"}} {}",
+                name,
+                escape_label(method),
+                status,
+                count
+            );
+        }
+
+        output
+    }
+}
+
+/// Escapes a label value of the text format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+
+    #[test]
+    fn renders_the_text_format() {
+        let metrics = Metrics::new();
+        metrics.packets_received.add(3);
+        metrics.packets_lost.inc();
+        metrics.buffered_packets.set(42.0);
+        metrics.clock_offset.set(-0.25);
+        metrics.rtsp_request("SETUP", 200);
+        metrics.rtsp_request("OPTIONS", 200);
+        metrics.rtsp_request("OPTIONS", 200);
+        metrics.rtsp_request("SETUP", 453);
+        metrics.rtsp_request("PLAY\"\n", 501);
+        metrics.rtsp_request("MADEUP", 501);
+
+        let output = metrics.render();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        for (name, kind) in [
+            ("airguitar_packets_received_total", "counter"),
+            ("airguitar_underruns_total", "counter"),
+            ("airguitar_buffered_packets", "gauge"),
+            ("airguitar_sync_error_seconds", "gauge"),
+            ("airguitar_rtsp_requests_total", "counter"),
+        ] {
+            let type_line = format!("# TYPE {} {}", name, kind);
+            assert!(lines.contains(&type_line.as_str()), "{}", type_line);
+            assert!(
+                lines
+                    .iter()
+                    .any(|line| line.starts_with(&format!("# HELP {} ", name))),
+                "{}",
+                name
+            );
+        }
+
+        for sample in [
+            "airguitar_packets_received_total 3",
+            "airguitar_packets_lost_total 1",
+            "airguitar_packets_late_total 0",
+            "airguitar_buffered_packets 42",
+            "airguitar_clock_offset_seconds -0.25",
+            "airguitar_sync_error_seconds NaN",
+            "airguitar_rtsp_requests_total{method=\"OPTIONS\",status=\"200\"} 2",
+            "airguitar_rtsp_requests_total{method=\"SETUP\",status=\"200\"} 1",
+            "airguitar_rtsp_requests_total{method=\"SETUP\",status=\"453\"} 1",
+            "airguitar_rtsp_requests_total{method=\"other\",status=\"501\"} 2",
+        ] {
+            assert!(lines.contains(&sample), "{}", sample);
+        }
+
+        // Every sample follows the `TYPE` line of its metric.
+        let mut current = "";
+        for line in &lines {
+            match line.strip_prefix("# TYPE ") {
+                Some(rest) => current = rest.split(' ').next().unwrap(),
+                None if line.starts_with('#') => {}
+                None => assert!(line.starts_with(current), "{}", line),
+            }
+        }
+    }
+}
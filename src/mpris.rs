@@ -0,0 +1,537 @@
+use crate::{
+    dacp::RemoteCommand,
+    events::{Event, EventBus},
+    player::{Command, Status},
+    shutdown::Shutdown,
+};
+use std::{collections::HashMap, time::Instant};
+use tokio::sync::{broadcast::error::RecvError, mpsc, oneshot};
+use zbus::{
+    fdo, interface,
+    object_server::InterfaceRef,
+    zvariant::{ObjectPath, Value},
+};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.airguitar";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const TRACK_ID: &str = "/org/airguitar/track/current";
+const NO_TRACK_ID: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+
+/// Exposes the player on the D-Bus session bus following the MPRIS
+/// specification.
+///
+/// Playback controls are forwarded to the sender through DACP.
+pub(crate) struct Mpris {
+    /// Shown as the identity of the player, the name of the receiver.
+    pub(crate) identity: String,
+
+    /// Tells the players of several receivers apart, their bus names end with
+    /// `.instance<n>`.
+    pub(crate) instance: Option<usize>,
+
+    /// Used to control our Player
+    pub(crate) player_tx: mpsc::Sender<Command>,
+
+    /// Keeps the exposed properties up to date.
+    pub(crate) events: EventBus,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `Mpris` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl Mpris {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        self.serve(zbus::connection::Builder::session()?).await
+    }
+
+    /// Serves the player on the bus connected to by `builder`.
+    async fn serve(&mut self, builder: zbus::connection::Builder<'_>) -> crate::result::Result<()> {
+        let (_, mut rx) = self.events.subscribe(None);
+
+        let player = MprisPlayer {
+            player_tx: self.player_tx.clone(),
+            status: status(&self.player_tx).await?,
+            progress_updated: Instant::now(),
+        };
+        let bus_name = match self.instance {
+            Some(instance) => format!("{}.instance{}", BUS_NAME, instance),
+            None => BUS_NAME.to_string(),
+        };
+        let media_player = MediaPlayer2 {
+            identity: self.identity.clone(),
+        };
+        let connection = builder
+            .name(bus_name)?
+            .serve_at(OBJECT_PATH, media_player)?
+            .serve_at(OBJECT_PATH, player)?
+            .build()
+            .await?;
+        let player = connection
+            .object_server()
+            .interface::<_, MprisPlayer>(OBJECT_PATH)
+            .await?;
+
+        while !self.shutdown.is_shutdown() {
+            let notification = tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(notification) => Some(notification),
+                    // Missed some events, refresh everything.
+                    Err(RecvError::Lagged(_)) => None,
+                    Err(RecvError::Closed) => return Ok(()),
+                },
+                _ = self.shutdown.recv() => {
+                    return Ok(());
+                }
+            };
+
+            let event = notification.map(|notification| notification.event);
+            if let Some(Event::Flush { .. } | Event::Underrun) = event {
+                continue;
+            }
+
+            self.refresh(&player, event.as_ref()).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates the cached player status and signals changed properties.
+    async fn refresh(
+        &self,
+        player: &InterfaceRef<MprisPlayer>,
+        event: Option<&Event>,
+    ) -> crate::result::Result<()> {
+        let new_status = status(&self.player_tx).await?;
+        {
+            let mut player = player.get_mut().await;
+            player.status = new_status;
+            if let Some(Event::ProgressUpdated { .. }) = event {
+                player.progress_updated = Instant::now();
+            }
+        }
+
+        let emitter = player.signal_emitter();
+        let player = player.get().await;
+        match event {
+            Some(Event::VolumeChanged { .. } | Event::LocalVolumeChanged { .. }) => {
+                player.volume_changed(emitter).await?
+            }
+            Some(Event::MetadataUpdated { .. } | Event::ProgressUpdated { .. }) => {
+                player.metadata_changed(emitter).await?
+            }
+            Some(Event::OutputPaused { .. }) => player.playback_status_changed(emitter).await?,
+            _ => {
+                player.playback_status_changed(emitter).await?;
+                player.metadata_changed(emitter).await?;
+                player.volume_changed(emitter).await?;
+                player.can_go_next_changed(emitter).await?;
+                player.can_go_previous_changed(emitter).await?;
+                player.can_play_changed(emitter).await?;
+                player.can_pause_changed(emitter).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Fetches the current status from the `Player`.
+async fn status(player_tx: &mpsc::Sender<Command>) -> crate::result::Result<Status> {
+    let (tx, rx) = oneshot::channel();
+    player_tx.send(Command::GetStatus { resp: tx }).await?;
+    Ok(rx.await?)
+}
+
+/// The `org.mpris.MediaPlayer2` interface.
+struct MediaPlayer2 {
+    identity: String,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer2 {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// The `org.mpris.MediaPlayer2.Player` interface.
+struct MprisPlayer {
+    player_tx: mpsc::Sender<Command>,
+
+    /// Status of the `Player` as of the last event.
+    status: Status,
+    /// When the progress was last reported by the sender.
+    progress_updated: Instant,
+}
+
+impl MprisPlayer {
+    async fn remote_control(&self, command: RemoteCommand) -> fdo::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.player_tx
+            .send(Command::RemoteControl { command, resp: tx })
+            .await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+        rx.await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    fn remote_control_available(&self) -> bool {
+        self.status
+            .session
+            .as_ref()
+            .is_some_and(|session| session.remote_control)
+    }
+
+    /// Sample rate of the RTP timestamps of the progress.
+    fn sample_rate(&self) -> f64 {
+        self.status
+            .session
+            .as_ref()
+            .map(|session| session.sample_rate)
+            .unwrap_or(44100) as f64
+    }
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MprisPlayer {
+    async fn play(&self) -> fdo::Result<()> {
+        self.remote_control(RemoteCommand::Play).await
+    }
+
+    async fn pause(&self) -> fdo::Result<()> {
+        self.remote_control(RemoteCommand::Pause).await
+    }
+
+    async fn play_pause(&self) -> fdo::Result<()> {
+        self.remote_control(RemoteCommand::PlayPause).await
+    }
+
+    async fn stop(&self) -> fdo::Result<()> {
+        self.remote_control(RemoteCommand::Stop).await
+    }
+
+    async fn next(&self) -> fdo::Result<()> {
+        self.remote_control(RemoteCommand::Next).await
+    }
+
+    async fn previous(&self) -> fdo::Result<()> {
+        self.remote_control(RemoteCommand::Previous).await
+    }
+
+    fn seek(&self, _offset: i64) {}
+
+    fn set_position(&self, _track_id: ObjectPath<'_>, _position: i64) {}
+
+    fn open_uri(&self, _uri: &str) {}
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        match (&self.status.session, self.status.paused) {
+            (None, _) => "Stopped",
+            (Some(_), true) => "Paused",
+            (Some(_), false) => "Playing",
+        }
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<&str, Value<'_>> {
+        let metadata = &self.status.metadata;
+        let mut values = HashMap::new();
+
+        let track_id = match metadata.title {
+            Some(_) => TRACK_ID,
+            None => NO_TRACK_ID,
+        };
+        values.insert(
+            "mpris:trackid",
+            Value::from(ObjectPath::from_static_str_unchecked(track_id)),
+        );
+
+        if let Some(ref title) = metadata.title {
+            values.insert("xesam:title", Value::from(title.as_str()));
+        }
+        if let Some(ref artist) = metadata.artist {
+            values.insert("xesam:artist", Value::from(vec![artist.as_str()]));
+        }
+        if let Some(ref album) = metadata.album {
+            values.insert("xesam:album", Value::from(album.as_str()));
+        }
+        if let Some(ref genre) = metadata.genre {
+            values.insert("xesam:genre", Value::from(vec![genre.as_str()]));
+        }
+        if let Some(ref composer) = metadata.composer {
+            values.insert("xesam:composer", Value::from(vec![composer.as_str()]));
+        }
+        if let Some(ref progress) = self.status.progress {
+            let length = progress.end.wrapping_sub(progress.start) as f64 / self.sample_rate();
+            values.insert("mpris:length", Value::from((length * 1e6) as i64));
+        }
+
+        values
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.status.local_volume as f64
+    }
+
+    #[zbus(property)]
+    async fn set_volume(&mut self, volume: f64) -> fdo::Result<()> {
+        let (tx, rx) = oneshot::channel();
+        self.player_tx
+            .send(Command::SetLocalVolume {
+                volume: volume.clamp(0.0, 1.0) as f32,
+                resp: tx,
+            })
+            .await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?;
+        rx.await
+            .map_err(|err| fdo::Error::Failed(err.to_string()))?
+            .map_err(|err| fdo::Error::Failed(err.to_string()))
+    }
+
+    /// Position in microseconds, extrapolated from the last reported progress.
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        let progress = match self.status.progress {
+            Some(ref progress) => progress,
+            None => return 0,
+        };
+
+        let mut position =
+            progress.current.wrapping_sub(progress.start) as f64 / self.sample_rate();
+        if self.playback_status() == "Playing" {
+            position += self.progress_updated.elapsed().as_secs_f64();
+        }
+
+        (position * 1e6) as i64
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        self.remote_control_available()
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        self.remote_control_available()
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        self.remote_control_available()
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        self.remote_control_available()
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        false
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dmap::Metadata;
+    use std::{process::Stdio, time::Duration};
+    use tokio::{
+        io::{AsyncBufReadExt, BufReader},
+        process,
+        sync::broadcast,
+        time,
+    };
+
+    /// Answers the commands of `Mpris` like the `Player` without a session.
+    async fn player(mut player_rx: mpsc::Receiver<Command>, events: EventBus) {
+        let mut local_volume = 1.0;
+        while let Some(command) = player_rx.recv().await {
+            match command {
+                Command::GetStatus { resp } => {
+                    let _ = resp.send(Status {
+                        name: "Airguitar".into(),
+                        session: None,
+                        volume: 0.0,
+                        local_volume,
+                        muted: false,
+                        paused: false,
+                        metadata: Metadata::default(),
+                        progress: None,
+                        artwork: None,
+                    });
+                }
+                Command::SetLocalVolume { volume, resp } => {
+                    local_volume = volume;
+                    events.publish(Event::LocalVolumeChanged {
+                        local_volume,
+                        muted: false,
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                Command::RemoteControl { resp, .. } => {
+                    let _ = resp.send(Err("client does not support remote control".into()));
+                }
+                command => panic!("unexpected command {:?}", command),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_the_player() {
+        // Runs on a private bus.
+        let mut daemon = match process::Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+        {
+            Ok(daemon) => daemon,
+            Err(err) => {
+                eprintln!("skipped, dbus-daemon not available: {}", err);
+                return;
+            }
+        };
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .await
+            .unwrap();
+        let address = address.trim();
+
+        let events = EventBus::new();
+        let (player_tx, player_rx) = mpsc::channel(4);
+        tokio::spawn(player(player_rx, events.clone()));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let mut mpris = Mpris {
+            identity: "Kitchen".into(),
+            instance: Some(1),
+            player_tx,
+            events,
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        let builder = zbus::connection::Builder::address(address).unwrap();
+        let task = tokio::spawn(async move { mpris.serve(builder).await });
+
+        let connection = zbus::connection::Builder::address(address)
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        let proxy = zbus::proxy::Builder::<zbus::Proxy>::new(&connection)
+            .destination(format!("{}.instance1", BUS_NAME))
+            .unwrap()
+            .path(OBJECT_PATH)
+            .unwrap()
+            .interface("org.mpris.MediaPlayer2.Player")
+            .unwrap()
+            .cache_properties(zbus::proxy::CacheProperties::No)
+            .build()
+            .await
+            .unwrap();
+
+        // Wait for the name to be taken.
+        let mut status = proxy.get_property::<String>("PlaybackStatus").await;
+        for _ in 0..50 {
+            if status.is_ok() {
+                break;
+            }
+            time::sleep(Duration::from_millis(100)).await;
+            status = proxy.get_property::<String>("PlaybackStatus").await;
+        }
+        assert_eq!(status.unwrap(), "Stopped");
+
+        let media_player = zbus::proxy::Builder::<zbus::Proxy>::new(&connection)
+            .destination(format!("{}.instance1", BUS_NAME))
+            .unwrap()
+            .path(OBJECT_PATH)
+            .unwrap()
+            .interface("org.mpris.MediaPlayer2")
+            .unwrap()
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(
+            media_player
+                .get_property::<String>("Identity")
+                .await
+                .unwrap(),
+            "Kitchen"
+        );
+        assert!(!proxy.get_property::<bool>("CanGoNext").await.unwrap());
+
+        proxy.set_property("Volume", 0.25).await.unwrap();
+        let mut volume = 0.0;
+        for _ in 0..50 {
+            volume = proxy.get_property::<f64>("Volume").await.unwrap();
+            if volume == 0.25 {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(volume, 0.25);
+
+        assert!(proxy.call_method("Next", &()).await.is_err());
+
+        notify_shutdown.send(()).unwrap();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,479 @@
+use crate::{
+    dacp::RemoteCommand,
+    events::{Event, EventBus},
+    player::Command,
+    shutdown::Shutdown,
+};
+use bytes::Bytes;
+use rumqttc::{AsyncClient, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+    task::JoinHandle,
+    time,
+};
+use tracing::{debug, error, info};
+
+/// How long to wait before reconnecting to the broker.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// How long to wait for pending messages to be sent when shutting down.
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Payload of the availability topic while connected.
+const ONLINE: &str = "online";
+
+/// Payload of the availability topic after disconnecting, set as last will.
+const OFFLINE: &str = "offline";
+
+/// Connection to an MQTT broker.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct MqttConfig {
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) username: Option<String>,
+    pub(crate) password: Option<String>,
+    /// Prefix of all topics, e.g. `airguitar`.
+    pub(crate) topic: String,
+}
+
+/// Number of MQTT clients of this process so far, every receiver connects
+/// with a client id of its own.
+static CLIENTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Publishes the receiver state to an MQTT broker and accepts commands.
+///
+/// State topics, all retained:
+///
+/// - `{topic}/available` `online` or `offline`
+/// - `{topic}/state` `playing`, `paused` or `stopped`
+/// - `{topic}/client` name or address of the current sender
+/// - `{topic}/volume` local volume (`0.0` to `1.0`)
+/// - `{topic}/muted` `true` or `false`
+/// - `{topic}/title`, `{topic}/artist`, `{topic}/album` metadata of the
+///   current track
+/// - `{topic}/artwork` artwork of the current track as raw image data
+///
+/// Empty payloads signal unknown values. Command topics:
+///
+/// - `{topic}/command/volume` sets the local volume, e.g. `0.5`
+/// - `{topic}/command/mute` mutes the output, `true` or `false`
+/// - `{topic}/command/disconnect` disconnects the current sender
+/// - `{topic}/command/remote` remote controls the current sender, commands are
+///   `play`, `pause`, `play_pause`, `stop`, `next`, `previous`, `volume_up` and
+///   `volume_down`
+pub(crate) struct Mqtt {
+    pub(crate) config: MqttConfig,
+
+    /// Used to control our Player
+    pub(crate) player_tx: mpsc::Sender<Command>,
+
+    /// Keeps the published state up to date.
+    pub(crate) events: EventBus,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `Mqtt` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+impl Mqtt {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        let mut options = MqttOptions::new(
+            format!(
+                "airguitar-{}-{}",
+                std::process::id(),
+                CLIENTS.fetch_add(1, Ordering::Relaxed)
+            ),
+            &self.config.host,
+            self.config.port,
+        );
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(
+            format!("{}/available", self.config.topic.trim_end_matches('/')),
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        if let Some(ref username) = self.config.username {
+            options.set_credentials(username, self.config.password.as_deref().unwrap_or(""));
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let (incoming_tx, mut incoming_rx) = mpsc::channel(16);
+
+        // The event loop has to be polled continuously to make any progress,
+        // even while shutting down.
+        let mut poller: JoinHandle<()> = tokio::spawn(async move {
+            loop {
+                match eventloop.poll().await {
+                    Ok(rumqttc::Event::Incoming(packet)) => {
+                        let _ = incoming_tx.send(packet).await;
+                    }
+                    Ok(rumqttc::Event::Outgoing(Outgoing::Disconnect)) => return,
+                    Ok(rumqttc::Event::Outgoing(_)) => {}
+                    Err(err) => {
+                        error!(cause = %err, "mqtt connection failed");
+                        time::sleep(RECONNECT_DELAY).await;
+                    }
+                }
+            }
+        });
+
+        let mut publisher = Publisher {
+            client,
+            prefix: self.config.topic.trim_end_matches('/').to_string(),
+            retained: HashMap::new(),
+        };
+        let (_, mut rx) = self.events.subscribe(None);
+        let command_topic = publisher.topic("command/");
+
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                Some(packet) = incoming_rx.recv() => match packet {
+                    Packet::ConnAck(_) => {
+                        info!("connected to mqtt broker {}:{}", self.config.host, self.config.port);
+                        publisher
+                            .client
+                            .try_subscribe(format!("{}#", command_topic), QoS::AtLeastOnce)?;
+                        // The broker might have lost the retained messages.
+                        publisher.retained.clear();
+                        publisher.publish("available", ONLINE);
+                        self.publish_status(&mut publisher).await?;
+                        self.publish_artwork(&mut publisher).await?;
+                    }
+                    Packet::Publish(publish) => {
+                        if let Some(command) = publish.topic.strip_prefix(&command_topic) {
+                            let payload = String::from_utf8_lossy(&publish.payload);
+                            if let Err(err) = self.command(command, payload.trim()).await {
+                                error!(cause = %err, command, "mqtt command failed");
+                            }
+                        }
+                    }
+                    _ => {}
+                },
+                res = rx.recv() => match res {
+                    Ok(notification) => match notification.event {
+                        Event::Flush { .. } | Event::ProgressUpdated { .. } | Event::Underrun => {}
+                        Event::ArtworkUpdated { data, .. } => publisher.publish("artwork", data),
+                        Event::SessionEnded { .. } => {
+                            self.publish_status(&mut publisher).await?;
+                            publisher.publish("artwork", Bytes::new());
+                        }
+                        _ => self.publish_status(&mut publisher).await?,
+                    },
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!(skipped, "mqtt lagging behind");
+                        self.publish_status(&mut publisher).await?;
+                        self.publish_artwork(&mut publisher).await?;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = self.shutdown.recv() => {}
+            }
+        }
+
+        publisher.publish("available", OFFLINE);
+        let _ = publisher.client.try_disconnect();
+        drop(incoming_rx);
+        if time::timeout(DISCONNECT_TIMEOUT, &mut poller)
+            .await
+            .is_err()
+        {
+            debug!("mqtt broker unreachable, dropping pending messages");
+            poller.abort();
+        }
+
+        Ok(())
+    }
+
+    /// Publishes all state topics except for the artwork.
+    async fn publish_status(&self, publisher: &mut Publisher) -> crate::result::Result<()> {
+        let status = request(&self.player_tx, |resp| Command::GetStatus { resp }).await?;
+
+        let state = match (&status.session, status.paused) {
+            (None, _) => "stopped",
+            (Some(_), true) => "paused",
+            (Some(_), false) => "playing",
+        };
+        let client_name = status
+            .session
+            .as_ref()
+            .map(|session| {
+                session
+                    .client_name
+                    .clone()
+                    .unwrap_or_else(|| session.client.ip().to_string())
+            })
+            .unwrap_or_default();
+        let metadata = status.metadata;
+
+        publisher.publish("state", state);
+        publisher.publish("client", client_name);
+        publisher.publish("volume", format!("{:.2}", status.local_volume));
+        publisher.publish("muted", status.muted.to_string());
+        publisher.publish("title", metadata.title.unwrap_or_default());
+        publisher.publish("artist", metadata.artist.unwrap_or_default());
+        publisher.publish("album", metadata.album.unwrap_or_default());
+
+        Ok(())
+    }
+
+    async fn publish_artwork(&self, publisher: &mut Publisher) -> crate::result::Result<()> {
+        let artwork = request(&self.player_tx, |resp| Command::GetArtwork { resp }).await?;
+        publisher.publish(
+            "artwork",
+            artwork.map(|artwork| artwork.data).unwrap_or_default(),
+        );
+        Ok(())
+    }
+
+    /// Executes a command received on `{topic}/command/{command}`.
+    async fn command(&self, command: &str, payload: &str) -> crate::result::Result<()> {
+        match command {
+            "volume" => {
+                let volume: f32 = payload.parse()?;
+                if !(0.0..=1.0).contains(&volume) {
+                    return Err(format!("volume {} out of range", volume).into());
+                }
+                request(&self.player_tx, |resp| Command::SetLocalVolume {
+                    volume,
+                    resp,
+                })
+                .await?
+            }
+            "mute" => {
+                let muted = payload.parse()?;
+                request(&self.player_tx, |resp| Command::SetMuted { muted, resp }).await?
+            }
+            "disconnect" => {
+                request(&self.player_tx, |resp| Command::Disconnect { resp }).await?;
+                Ok(())
+            }
+            "remote" => {
+                let command: RemoteCommand = payload.parse()?;
+                request(&self.player_tx, |resp| Command::RemoteControl {
+                    command,
+                    resp,
+                })
+                .await?
+            }
+            _ => Err(format!("unknown command {:?}", command).into()),
+        }
+    }
+}
+
+/// Sends a command to the `Player` and waits for its response.
+async fn request<T>(
+    player_tx: &mpsc::Sender<Command>,
+    command: impl FnOnce(oneshot::Sender<T>) -> Command,
+) -> crate::result::Result<T> {
+    let (tx, rx) = oneshot::channel();
+    player_tx.send(command(tx)).await?;
+    Ok(rx.await?)
+}
+
+/// Publishes retained messages, skipping the ones which did not change.
+struct Publisher {
+    client: AsyncClient,
+    prefix: String,
+    /// Last payload published per topic.
+    retained: HashMap<String, Bytes>,
+}
+
+impl Publisher {
+    fn topic(&self, name: &str) -> String {
+        format!("{}/{}", self.prefix, name)
+    }
+
+    /// Publishes a retained message.
+    ///
+    /// Messages are dropped while the broker is unreachable for long, the
+    /// complete state is published again after reconnecting.
+    fn publish(&mut self, name: &str, payload: impl Into<Bytes>) {
+        let topic = self.topic(name);
+        let payload = payload.into();
+        if self.retained.get(&topic) == Some(&payload) {
+            return;
+        }
+
+        match self
+            .client
+            .try_publish(&topic, QoS::AtLeastOnce, true, payload.clone())
+        {
+            Ok(()) => {
+                self.retained.insert(topic, payload);
+            }
+            Err(err) => debug!(cause = %err, topic, "dropping mqtt message"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dmap::Metadata, player::Status};
+    use bytes::BytesMut;
+    use rumqttc::{
+        mqttbytes::Error as MqttError, ConnAck, ConnectReturnCode, PubAck, Publish, SubAck,
+        SubscribeReasonCode,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::broadcast,
+    };
+
+    const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+    /// Answers the commands of `Mqtt` like the `Player` without a session.
+    async fn player(mut player_rx: mpsc::Receiver<Command>, events: EventBus) {
+        let mut local_volume = 1.0;
+        while let Some(command) = player_rx.recv().await {
+            match command {
+                Command::GetStatus { resp } => {
+                    let _ = resp.send(Status {
+                        name: "Airguitar".into(),
+                        session: None,
+                        volume: 0.0,
+                        local_volume,
+                        muted: false,
+                        paused: false,
+                        metadata: Metadata::default(),
+                        progress: None,
+                        artwork: None,
+                    });
+                }
+                Command::GetArtwork { resp } => {
+                    let _ = resp.send(None);
+                }
+                Command::SetLocalVolume { volume, resp } => {
+                    local_volume = volume;
+                    events.publish(Event::LocalVolumeChanged {
+                        local_volume,
+                        muted: false,
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                command => panic!("unexpected command {:?}", command),
+            }
+        }
+    }
+
+    /// Minimal broker for a single client, returns the messages published by
+    /// it and sends it `commands` after it subscribed.
+    async fn broker(
+        listener: TcpListener,
+        commands: Vec<Publish>,
+        published_tx: mpsc::UnboundedSender<Publish>,
+    ) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = BytesMut::new();
+        loop {
+            let packet = match Packet::read(&mut buffer, MAX_PACKET_SIZE) {
+                Ok(packet) => packet,
+                Err(MqttError::InsufficientBytes(_)) => {
+                    if stream.read_buf(&mut buffer).await.unwrap() == 0 {
+                        return;
+                    }
+                    continue;
+                }
+                Err(err) => panic!("invalid packet: {:?}", err),
+            };
+
+            let response = match packet {
+                Packet::Connect(_) => vec![Packet::ConnAck(ConnAck::new(
+                    ConnectReturnCode::Success,
+                    false,
+                ))],
+                Packet::Subscribe(subscribe) => {
+                    let return_codes = subscribe
+                        .filters
+                        .iter()
+                        .map(|filter| SubscribeReasonCode::Success(filter.qos))
+                        .collect();
+                    let mut response =
+                        vec![Packet::SubAck(SubAck::new(subscribe.pkid, return_codes))];
+                    response.extend(commands.iter().cloned().map(Packet::Publish));
+                    response
+                }
+                Packet::Publish(publish) => {
+                    let pkid = publish.pkid;
+                    published_tx.send(publish).unwrap();
+                    vec![Packet::PubAck(PubAck::new(pkid))]
+                }
+                Packet::PingReq => vec![Packet::PingResp],
+                Packet::Disconnect => return,
+                _ => Vec::new(),
+            };
+            for packet in response {
+                let mut output = BytesMut::new();
+                packet.write(&mut output, MAX_PACKET_SIZE).unwrap();
+                stream.write_all(&output).await.unwrap();
+            }
+        }
+    }
+
+    /// Waits for the next message published on `topic`.
+    async fn next(published_rx: &mut mpsc::UnboundedReceiver<Publish>, topic: &str) -> String {
+        loop {
+            let publish = time::timeout(Duration::from_secs(5), published_rx.recv())
+                .await
+                .unwrap_or_else(|_| panic!("nothing published on {}", topic))
+                .unwrap();
+            if publish.topic == topic {
+                assert!(publish.retain);
+                return String::from_utf8(publish.payload.to_vec()).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn publishes_state_and_accepts_commands() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (published_tx, mut published_rx) = mpsc::unbounded_channel();
+        let mut command = Publish::new("airguitar/command/volume", QoS::AtLeastOnce, "0.5");
+        command.pkid = 1;
+        let commands = vec![command];
+        tokio::spawn(broker(listener, commands, published_tx));
+
+        let events = EventBus::new();
+        let (player_tx, player_rx) = mpsc::channel(4);
+        tokio::spawn(player(player_rx, events.clone()));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let mut mqtt = Mqtt {
+            config: MqttConfig {
+                host: "127.0.0.1".into(),
+                port,
+                username: None,
+                password: None,
+                topic: "airguitar/".into(),
+            },
+            player_tx,
+            events,
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        let task = tokio::spawn(async move { mqtt.run().await });
+
+        assert_eq!(next(&mut published_rx, "airguitar/available").await, ONLINE);
+        assert_eq!(next(&mut published_rx, "airguitar/state").await, "stopped");
+        assert_eq!(next(&mut published_rx, "airguitar/volume").await, "1.00");
+        // Set by the command.
+        assert_eq!(next(&mut published_rx, "airguitar/volume").await, "0.50");
+
+        notify_shutdown.send(()).unwrap();
+        assert_eq!(
+            next(&mut published_rx, "airguitar/available").await,
+            OFFLINE
+        );
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,232 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    str::FromStr,
+};
+use tokio::net::{TcpListener, UdpSocket};
+use tracing::warn;
+
+/// Addresses the receiver listens on.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(crate) struct NetworkConfig {
+    /// Network interfaces, all of their addresses are used.
+    pub(crate) interfaces: Vec<String>,
+    /// Addresses in addition to the ones of `interfaces`.
+    pub(crate) addresses: Vec<IpAddr>,
+}
+
+impl NetworkConfig {
+    /// Addresses to listen on, empty for all addresses of both IPv4 and IPv6.
+    ///
+    /// IPv6 link-local addresses of interfaces carry the index of the
+    /// interface as their scope id.
+    pub(crate) fn socket_addrs(&self, port: u16) -> crate::result::Result<Vec<SocketAddr>> {
+        let mut addrs: Vec<SocketAddr> = self
+            .addresses
+            .iter()
+            .map(|ip| SocketAddr::new(*ip, port))
+            .collect();
+
+        if !self.interfaces.is_empty() {
+            let interfaces = if_addrs::get_if_addrs()?;
+            for name in &self.interfaces {
+                let before = addrs.len();
+                for interface in interfaces
+                    .iter()
+                    .filter(|interface| &interface.name == name)
+                {
+                    addrs.push(match interface.ip() {
+                        IpAddr::V6(ip) if is_unicast_link_local(&ip) => SocketAddr::V6(
+                            SocketAddrV6::new(ip, port, 0, interface.index.unwrap_or(0)),
+                        ),
+                        ip => SocketAddr::new(ip, port),
+                    });
+                }
+                if addrs.len() == before {
+                    return Err(format!("network interface {:?} has no addresses", name).into());
+                }
+            }
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Local UDP ports of the audio, control and timing sockets, e.g. `6000-6009`.
+///
+/// Every session needs three ports, so the range covers at least three.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct PortRange {
+    pub(crate) start: u16,
+    pub(crate) end: u16,
+}
+
+impl FromStr for PortRange {
+    type Err = crate::error::Error;
+
+    fn from_str(input: &str) -> crate::result::Result<PortRange> {
+        let (start, end) = input.split_once('-').ok_or_else(|| {
+            format!(
+                "expected a range of ports (e.g. `6000-6009`), got {:?}",
+                input
+            )
+        })?;
+        let range = PortRange {
+            start: start.trim().parse()?,
+            end: end.trim().parse()?,
+        };
+        if range.start == 0 || range.end < range.start.saturating_add(2) {
+            return Err(format!("range {} does not contain three ports", range).into());
+        }
+        Ok(range)
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+/// Binds a UDP socket to the address with the first free port of the range, or
+/// a random port without one.
+pub(crate) async fn bind_udp(
+    mut addr: SocketAddr,
+    ports: Option<PortRange>,
+) -> crate::result::Result<UdpSocket> {
+    let range = match ports {
+        Some(range) => range,
+        None => {
+            addr.set_port(0);
+            return Ok(UdpSocket::bind(addr).await?);
+        }
+    };
+
+    for port in range.start..=range.end {
+        addr.set_port(port);
+        match UdpSocket::bind(addr).await {
+            Ok(socket) => return Ok(socket),
+            Err(err) if err.kind() == io::ErrorKind::AddrInUse => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Err(format!("no free UDP port in range {}", range).into())
+}
+
+/// Binds the RTSP listeners, all of them share the same port.
+///
+/// Without configured addresses a single dual-stack socket accepts IPv4 and
+/// IPv6 connections, falling back to IPv4 only where IPv6 is unavailable.
+pub(crate) fn bind(config: &NetworkConfig, port: u16) -> crate::result::Result<Vec<TcpListener>> {
+    let addrs = config.socket_addrs(port)?;
+    if addrs.is_empty() {
+        let any = SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port);
+        return match bind_tcp(any, false) {
+            Ok(listener) => Ok(vec![listener]),
+            Err(err) => {
+                warn!(cause = %err, "ipv6 unavailable, listening on ipv4 only");
+                Ok(vec![bind_tcp(
+                    SocketAddr::new([0, 0, 0, 0].into(), port),
+                    false,
+                )?])
+            }
+        };
+    }
+
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    for mut addr in addrs {
+        // A random port is chosen by the first listener.
+        if let Some(first) = listeners.first() {
+            addr.set_port(first.local_addr()?.port());
+        }
+        let listener =
+            bind_tcp(addr, true).map_err(|err| format!("failed to listen on {}: {}", addr, err))?;
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+fn bind_tcp(addr: SocketAddr, only_v6: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+/// Maps IPv4 addresses of dual-stack sockets (`::ffff:a.b.c.d`) back to IPv4.
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+fn is_unicast_link_local(ip: &Ipv6Addr) -> bool {
+    (ip.segments()[0] & 0xffc0) == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_port_ranges() {
+        assert_eq!(
+            " 6000 - 6009".parse::<PortRange>().unwrap(),
+            PortRange {
+                start: 6000,
+                end: 6009
+            }
+        );
+        assert!("6000-6002".parse::<PortRange>().is_ok());
+
+        for range in [
+            "",
+            "6000",
+            "6000-",
+            "-6009",
+            "a-b",
+            "6000-70000",
+            "6000-6001",
+            "6009-6000",
+            "0-9",
+        ] {
+            assert!(range.parse::<PortRange>().is_err(), "{:?}", range);
+        }
+    }
+
+    #[tokio::test]
+    async fn binds_udp_in_range() {
+        let taken = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let start = taken.local_addr().unwrap().port();
+        let range = PortRange {
+            start,
+            end: start + 2,
+        };
+
+        let socket = bind_udp("127.0.0.1:0".parse().unwrap(), Some(range))
+            .await
+            .unwrap();
+        let port = socket.local_addr().unwrap().port();
+        assert!(port > start && port <= range.end, "{}", port);
+    }
+
+    #[test]
+    fn maps_ipv4_addresses_back() {
+        let mapped: SocketAddr = "[::ffff:192.168.1.10]:5000".parse().unwrap();
+        assert_eq!(canonical(mapped), "192.168.1.10:5000".parse().unwrap());
+        let v6: SocketAddr = "[fd00::10]:5000".parse().unwrap();
+        assert_eq!(canonical(v6), v6);
+    }
+}
@@ -0,0 +1,447 @@
+use crate::{
+    dmap::Metadata,
+    events::{Event, EventBus},
+    player::{Artwork, Command, Status},
+    shutdown::Shutdown,
+};
+use serde::Serialize;
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::{
+    fs,
+    sync::{broadcast::error::RecvError, mpsc, oneshot},
+};
+use tracing::{debug, error, info};
+
+/// Keeps a JSON file with the current state of the receiver up to date, e.g.
+/// for status bars.
+///
+/// The document looks like
+///
+/// ```json
+/// {
+///   "state": "playing",
+///   "client": "iPhone",
+///   "client_address": "192.168.1.23:52170",
+///   "volume": 0.8,
+///   "muted": false,
+///   "metadata": { "title": "Title", "artist": "Artist", "album": null, ... },
+///   "position": { "elapsed": 12.5, "duration": 215.1, "updated_at": 1700000000.2 },
+///   "artwork": "/run/user/1000/airguitar-artwork.jpg"
+/// }
+/// ```
+///
+/// with `state` being `playing`, `paused` or `idle`. The elapsed time was
+/// reported by the sender at `updated_at` (seconds since the Unix epoch),
+/// readers extrapolate it while playing. The artwork is written next to the
+/// document. Files are replaced atomically, so readers never see partial
+/// content. The document is reset to `idle` at the end of a session and
+/// removed on shutdown. Failed writes are logged and retried on the next
+/// change.
+pub(crate) struct NowPlaying {
+    /// Path of the JSON document.
+    pub(crate) path: PathBuf,
+
+    /// Used to query the state of our Player
+    pub(crate) player_tx: mpsc::Sender<Command>,
+
+    /// Tells when the state changes.
+    pub(crate) events: EventBus,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `NowPlaying` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+#[derive(Debug, Serialize)]
+struct Document {
+    state: &'static str,
+    client: Option<String>,
+    client_address: Option<String>,
+    volume: f32,
+    muted: bool,
+    metadata: Option<Metadata>,
+    position: Option<Position>,
+    artwork: Option<PathBuf>,
+}
+
+#[derive(Debug, Serialize)]
+struct Position {
+    elapsed: f64,
+    duration: f64,
+    updated_at: f64,
+}
+
+impl NowPlaying {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        info!("writing now playing state to {}", self.path.display());
+
+        let (_, mut rx) = self.events.subscribe(None);
+        let mut artwork: Option<PathBuf> = None;
+        let mut progress_updated = SystemTime::now();
+        let mut written: Option<Vec<u8>> = None;
+
+        self.update(&mut written, &artwork, progress_updated).await;
+
+        while !self.shutdown.is_shutdown() {
+            let notification = tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(notification) => notification,
+                    Err(RecvError::Lagged(skipped)) => {
+                        debug!(skipped, "now playing lagging behind");
+                        artwork = self.write_artwork(artwork).await;
+                        self.update(&mut written, &artwork, progress_updated).await;
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = self.shutdown.recv() => break,
+            };
+
+            match notification.event {
+                Event::Underrun => continue,
+                Event::ProgressUpdated { .. } => progress_updated = SystemTime::now(),
+                Event::ArtworkUpdated { .. } => {
+                    artwork = self.write_artwork(artwork).await;
+                }
+                Event::SessionEnded { .. } => {
+                    if let Some(previous) = artwork.take() {
+                        remove_file(&previous).await;
+                    }
+                }
+                _ => {}
+            }
+
+            self.update(&mut written, &artwork, progress_updated).await;
+        }
+
+        remove_file(&self.path).await;
+        if let Some(ref artwork) = artwork {
+            remove_file(artwork).await;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the document unless it did not change since the last write.
+    async fn update(
+        &self,
+        written: &mut Option<Vec<u8>>,
+        artwork: &Option<PathBuf>,
+        progress_updated: SystemTime,
+    ) {
+        let status = match request(&self.player_tx, |resp| Command::GetStatus { resp }).await {
+            Ok(status) => status,
+            Err(err) => {
+                error!(cause = %err, "failed to get the player status");
+                return;
+            }
+        };
+        let document = document(status, artwork.clone(), progress_updated);
+        let contents = serde_json::to_vec_pretty(&document).expect("serializable document");
+        if written.as_ref() == Some(&contents) {
+            return;
+        }
+
+        match write_atomically(&self.path, &contents).await {
+            Ok(()) => *written = Some(contents),
+            Err(err) => error!(
+                cause = %err,
+                path = %self.path.display(),
+                "failed to write now playing state"
+            ),
+        }
+    }
+
+    /// Replaces the artwork file with the current artwork, returns its path
+    /// if it was written.
+    async fn write_artwork(&self, previous: Option<PathBuf>) -> Option<PathBuf> {
+        let artwork = match request(&self.player_tx, |resp| Command::GetArtwork { resp }).await {
+            Ok(artwork) => artwork,
+            Err(err) => {
+                error!(cause = %err, "failed to get the artwork");
+                return previous;
+            }
+        };
+        let path = artwork
+            .as_ref()
+            .map(|artwork| artwork_path(&self.path, artwork));
+
+        // The extension changes with the content type.
+        if let Some(previous) = previous.filter(|previous| Some(previous) != path.as_ref()) {
+            remove_file(&previous).await;
+        }
+
+        match (artwork, path) {
+            (Some(artwork), Some(path)) => match write_atomically(&path, &artwork.data).await {
+                Ok(()) => Some(path),
+                Err(err) => {
+                    error!(cause = %err, path = %path.display(), "failed to write artwork");
+                    // Not referenced by the document, drop an outdated one.
+                    remove_file(&path).await;
+                    None
+                }
+            },
+            _ => None,
+        }
+    }
+}
+
+fn document(status: Status, artwork: Option<PathBuf>, progress_updated: SystemTime) -> Document {
+    let session = match status.session {
+        Some(session) => session,
+        None => {
+            return Document {
+                state: "idle",
+                client: None,
+                client_address: None,
+                volume: status.local_volume,
+                muted: status.muted,
+                metadata: None,
+                position: None,
+                artwork: None,
+            }
+        }
+    };
+
+    let rate = session.sample_rate as f64;
+    let position = status.progress.map(|progress| Position {
+        elapsed: progress.current.wrapping_sub(progress.start) as f64 / rate,
+        duration: progress.end.wrapping_sub(progress.start) as f64 / rate,
+        updated_at: progress_updated
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_secs_f64())
+            .unwrap_or_default(),
+    });
+
+    Document {
+        state: if status.paused { "paused" } else { "playing" },
+        client: Some(
+            session
+                .client_name
+                .unwrap_or_else(|| session.client.ip().to_string()),
+        ),
+        client_address: Some(session.client.to_string()),
+        volume: status.local_volume,
+        muted: status.muted,
+        metadata: Some(status.metadata),
+        position,
+        artwork,
+    }
+}
+
+/// Artwork is stored next to the document, e.g. `airguitar.json` gets
+/// `airguitar-artwork.jpg`.
+fn artwork_path(path: &Path, artwork: &Artwork) -> PathBuf {
+    let extension = match artwork.content_type.as_str() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        _ => "bin",
+    };
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-artwork.{}", stem, extension))
+}
+
+/// Writes to a temporary file first and renames it, so readers either see the
+/// old or the new content.
+async fn write_atomically(path: &Path, contents: &[u8]) -> crate::result::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    fs::write(&tmp, contents).await?;
+    fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+async fn remove_file(path: &Path) {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() != ErrorKind::NotFound => {
+            error!(cause = %err, path = %path.display(), "failed to remove file")
+        }
+        _ => {}
+    }
+}
+
+/// Sends a command to the `Player` and waits for its response.
+async fn request<T>(
+    player_tx: &mpsc::Sender<Command>,
+    command: impl FnOnce(oneshot::Sender<T>) -> Command,
+) -> crate::result::Result<T> {
+    let (tx, rx) = oneshot::channel();
+    player_tx.send(command(tx)).await?;
+    Ok(rx.await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::SessionStatus;
+    use bytes::Bytes;
+    use serde_json::Value;
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::{sync::broadcast, task::JoinHandle, time};
+
+    const CLIENT: &str = "192.168.1.23:52170";
+
+    /// Answers the commands of `NowPlaying` like the `Player`, with a session
+    /// while `session` is set.
+    async fn player(mut player_rx: mpsc::Receiver<Command>, session: Arc<Mutex<bool>>) {
+        while let Some(command) = player_rx.recv().await {
+            let active = *session.lock().unwrap();
+            match command {
+                Command::GetStatus { resp } => {
+                    let _ = resp.send(Status {
+                        name: "Airguitar".into(),
+                        session: active.then(|| SessionStatus {
+                            client: CLIENT.parse().unwrap(),
+                            client_name: Some("iPhone".into()),
+                            user_agent: None,
+                            codec: "AppleLossless".into(),
+                            sample_rate: 44100,
+                            remote_control: false,
+                            buffered_packets: 0,
+                            buffered_seconds: 0.0,
+                        }),
+                        volume: 0.0,
+                        local_volume: 1.0,
+                        muted: false,
+                        paused: false,
+                        metadata: Metadata::default(),
+                        progress: None,
+                        artwork: None,
+                    });
+                }
+                Command::GetArtwork { resp } => {
+                    let _ = resp.send(active.then(|| Artwork {
+                        content_type: "image/jpeg".into(),
+                        data: Bytes::from_static(b"jpeg"),
+                    }));
+                }
+                command => panic!("unexpected command {:?}", command),
+            }
+        }
+    }
+
+    fn start(
+        path: PathBuf,
+        session: Arc<Mutex<bool>>,
+    ) -> (EventBus, broadcast::Sender<()>, JoinHandle<()>) {
+        let events = EventBus::new();
+        let (player_tx, player_rx) = mpsc::channel(4);
+        tokio::spawn(player(player_rx, session));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let mut now_playing = NowPlaying {
+            path,
+            player_tx,
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        let task = tokio::spawn(async move { now_playing.run().await.unwrap() });
+        (events, notify_shutdown, task)
+    }
+
+    /// Waits for a document matching `predicate` to be written.
+    async fn wait_for(path: &Path, predicate: impl Fn(&Value) -> bool) -> Value {
+        for _ in 0..500 {
+            if let Ok(contents) = std::fs::read(path) {
+                let document = serde_json::from_slice::<Value>(&contents).unwrap();
+                if predicate(&document) {
+                    return document;
+                }
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("expected document was never written");
+    }
+
+    fn session_started() -> Event {
+        Event::SessionStarted {
+            session_id: 1,
+            client: CLIENT.parse::<SocketAddr>().unwrap(),
+            client_name: Some("iPhone".into()),
+            user_agent: None,
+            codec: "AppleLossless".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_the_state_and_removes_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("airguitar.json");
+        let artwork_path = dir.path().join("airguitar-artwork.jpg");
+        let session = Arc::new(Mutex::new(false));
+        let (events, notify_shutdown, task) = start(path.clone(), session.clone());
+
+        wait_for(&path, |document| document["state"] == "idle").await;
+
+        *session.lock().unwrap() = true;
+        events.publish(session_started());
+        let document = wait_for(&path, |document| document["state"] == "playing").await;
+        assert_eq!(document["client"], "iPhone");
+        assert_eq!(document["client_address"], CLIENT);
+
+        events.publish(Event::ArtworkUpdated {
+            content_type: Some("image/jpeg".into()),
+            data: Bytes::from_static(b"jpeg"),
+        });
+        wait_for(&path, |document| {
+            document["artwork"] == artwork_path.to_str().unwrap()
+        })
+        .await;
+        assert_eq!(std::fs::read(&artwork_path).unwrap(), b"jpeg");
+
+        // The session end resets the document and removes the artwork.
+        *session.lock().unwrap() = false;
+        events.publish(Event::SessionEnded {
+            client: CLIENT.parse().unwrap(),
+        });
+        let document = wait_for(&path, |document| document["state"] == "idle").await;
+        assert_eq!(document["client"], Value::Null);
+        assert_eq!(document["artwork"], Value::Null);
+        assert!(!artwork_path.exists());
+
+        // Written through temporary files which are renamed, none is left.
+        let mut files = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["airguitar.json"]);
+
+        notify_shutdown.send(()).unwrap();
+        task.await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn keeps_running_when_writes_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("missing").join("airguitar.json");
+        let session = Arc::new(Mutex::new(false));
+        let (events, notify_shutdown, task) = start(path.clone(), session.clone());
+
+        *session.lock().unwrap() = true;
+        events.publish(session_started());
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+
+        // Written on the next change once possible.
+        std::fs::create_dir(dir.path().join("missing")).unwrap();
+        events.publish(Event::PlaybackStarted);
+        wait_for(&path, |document| document["state"] == "playing").await;
+
+        notify_shutdown.send(()).unwrap();
+        task.await.unwrap();
+        assert!(!path.exists());
+    }
+}
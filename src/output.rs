@@ -0,0 +1,170 @@
+use clap::ValueEnum;
+use rodio::{
+    cpal::{
+        self,
+        traits::{DeviceTrait, HostTrait},
+        SampleRate,
+    },
+    Device, OutputStream, OutputStreamHandle,
+};
+
+/// Sample format delivered to the output device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum SampleFormat {
+    /// Signed 16 bit integer
+    S16,
+    /// Signed 24 bit integer (padded to 32 bit)
+    S24,
+    /// Signed 32 bit integer
+    S32,
+    /// 32 bit floating point
+    F32,
+}
+
+impl SampleFormat {
+    /// Returns the bit depth samples need to be dithered to, `None` if the
+    /// format is at least as precise as our internal `f32` pipeline.
+    pub(crate) fn dither_bits(&self) -> Option<u32> {
+        match self {
+            SampleFormat::S16 => Some(16),
+            SampleFormat::S24 => Some(24),
+            SampleFormat::S32 | SampleFormat::F32 => None,
+        }
+    }
+
+    fn from_cpal(format: cpal::SampleFormat) -> Option<SampleFormat> {
+        match format {
+            cpal::SampleFormat::I16 | cpal::SampleFormat::U16 => Some(SampleFormat::S16),
+            cpal::SampleFormat::I32 | cpal::SampleFormat::U32 => Some(SampleFormat::S32),
+            cpal::SampleFormat::F32 => Some(SampleFormat::F32),
+            _ => None,
+        }
+    }
+
+    fn to_cpal(self) -> cpal::SampleFormat {
+        match self {
+            SampleFormat::S16 => cpal::SampleFormat::I16,
+            // there is no packed 24 bit format, devices expect it padded to 32 bit
+            SampleFormat::S24 | SampleFormat::S32 => cpal::SampleFormat::I32,
+            SampleFormat::F32 => cpal::SampleFormat::F32,
+        }
+    }
+}
+
+/// Looks up the output device to play on.
+///
+/// `selector` is either the exact name of a device or its index as printed by
+/// `list-devices`. Without a selector the host's default output device is used.
+pub(crate) fn find_device(selector: Option<&str>) -> crate::result::Result<Device> {
+    let host = cpal::default_host();
+
+    let selector = match selector {
+        Some(selector) => selector,
+        None => {
+            return host
+                .default_output_device()
+                .ok_or_else(|| "no default output device available".into())
+        }
+    };
+
+    let mut devices = host.output_devices()?.collect::<Vec<_>>();
+
+    // an exact name match always wins over an index
+    let index = devices
+        .iter()
+        .position(|device| device.name().ok().as_deref() == Some(selector))
+        .or_else(|| selector.parse::<usize>().ok())
+        .filter(|index| *index < devices.len())
+        .ok_or_else(|| format!("unknown output device {:?}", selector))?;
+
+    Ok(devices.swap_remove(index))
+}
+
+/// Opens an output stream on `device`.
+///
+/// The stream runs with `channels`, at `sample_rate` and in `sample_format` if
+/// given, otherwise the device's native (default) configuration is used.
+/// Returns the stream together with its sample rate and format (`None` if the
+/// device uses a format we do not know about).
+pub(crate) fn open_stream(
+    device: &Device,
+    channels: Option<u16>,
+    sample_rate: Option<u32>,
+    sample_format: Option<SampleFormat>,
+) -> crate::result::Result<(OutputStream, OutputStreamHandle, u32, Option<SampleFormat>)> {
+    let default_config = device.default_output_config()?;
+    let default_channels = default_config.channels();
+    let default_sample_rate = default_config.sample_rate().0;
+    let default_sample_format = default_config.sample_format();
+
+    let config = if channels.is_none_or(|channels| channels == default_channels)
+        && sample_rate.is_none_or(|rate| rate == default_sample_rate)
+        && sample_format.is_none_or(|format| format.to_cpal() == default_sample_format)
+    {
+        default_config
+    } else {
+        let channels = channels.unwrap_or(default_channels);
+        let sample_rate = sample_rate.unwrap_or(default_sample_rate);
+        let cpal_sample_format = sample_format.map_or(default_sample_format, SampleFormat::to_cpal);
+
+        device
+            .supported_output_configs()?
+            .filter(|config| config.channels() == channels)
+            .filter(|config| config.sample_format() == cpal_sample_format)
+            .find_map(|config| config.try_with_sample_rate(SampleRate(sample_rate)))
+            .ok_or_else(|| {
+                format!(
+                    "output device does not support {} channels of {:?} samples at {} Hz",
+                    channels, cpal_sample_format, sample_rate
+                )
+            })?
+    };
+
+    let sample_rate = config.sample_rate().0;
+    let sample_format = sample_format.or_else(|| SampleFormat::from_cpal(config.sample_format()));
+    let (stream, stream_handle) = OutputStream::try_from_device_config(device, config)?;
+
+    Ok((stream, stream_handle, sample_rate, sample_format))
+}
+
+/// Prints all available output devices together with their supported
+/// configurations.
+pub(crate) fn list_devices() -> crate::result::Result<()> {
+    let host = cpal::default_host();
+    let default_name = host
+        .default_output_device()
+        .and_then(|device| device.name().ok());
+
+    for (index, device) in host.output_devices()?.enumerate() {
+        let name = device.name()?;
+        if Some(&name) == default_name.as_ref() {
+            println!("{}: {} (default)", index, name);
+        } else {
+            println!("{}: {}", index, name);
+        }
+
+        match device.supported_output_configs() {
+            Ok(configs) => {
+                for config in configs {
+                    let min_sample_rate = config.min_sample_rate().0;
+                    let max_sample_rate = config.max_sample_rate().0;
+                    let sample_rate = if min_sample_rate == max_sample_rate {
+                        format!("{} Hz", min_sample_rate)
+                    } else {
+                        format!("{}-{} Hz", min_sample_rate, max_sample_rate)
+                    };
+
+                    println!(
+                        "    {} channels, {}, {:?}",
+                        config.channels(),
+                        sample_rate,
+                        config.sample_format()
+                    );
+                }
+            }
+            Err(err) => println!("    unable to query configurations: {}", err),
+        }
+    }
+
+    Ok(())
+}
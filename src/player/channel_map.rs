@@ -0,0 +1,215 @@
+use clap::ValueEnum;
+use rodio::Source;
+use std::time::Duration;
+
+/// How the decoded left and right channels are mapped onto the output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub(crate) enum ChannelMode {
+    /// Left and right as sent
+    #[default]
+    Stereo,
+    /// Average of left and right on every channel
+    Mono,
+    /// Left and right swapped
+    Swap,
+    /// Left channel only, on every channel
+    Left,
+    /// Right channel only, on every channel
+    Right,
+}
+
+/// Maps the channels of a source according to a `ChannelMode`.
+///
+/// The mapped left / right pair is repeated over all output channels, so four
+/// output channels receive `L R L R`. With a single output channel, stereo
+/// modes are downmixed to mono.
+pub(crate) struct ChannelMap<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+    mode: ChannelMode,
+    channels: u16,
+
+    /// Samples of the current output frame.
+    frame: Vec<f32>,
+    next_channel: usize,
+}
+
+impl<I> ChannelMap<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Builds a new `ChannelMap` with `channels` output channels.
+    ///
+    /// # Panic
+    ///
+    /// - Panics if the number of channels is zero.
+    ///
+    pub(crate) fn new(input: I, mode: ChannelMode, channels: u16) -> ChannelMap<I> {
+        assert!(channels != 0);
+
+        ChannelMap {
+            input,
+            mode,
+            channels,
+
+            frame: vec![0.0; channels as usize],
+            next_channel: channels as usize,
+        }
+    }
+
+    /// Reads the next input frame and fills `frame`. Returns `false` if the
+    /// input ended.
+    fn next_frame(&mut self) -> bool {
+        let mut left = 0.0;
+        let mut right = 0.0;
+        for channel in 0..self.input.channels() {
+            match (channel, self.input.next()) {
+                (0, Some(sample)) => {
+                    left = sample;
+                    right = sample;
+                }
+                (1, Some(sample)) => right = sample,
+                (_, Some(_)) => {}
+                (_, None) => return false,
+            }
+        }
+
+        let (left, right) = match self.mode {
+            ChannelMode::Stereo => (left, right),
+            ChannelMode::Mono => ((left + right) / 2.0, (left + right) / 2.0),
+            ChannelMode::Swap => (right, left),
+            ChannelMode::Left => (left, left),
+            ChannelMode::Right => (right, right),
+        };
+
+        if self.channels == 1 {
+            self.frame[0] = (left + right) / 2.0;
+        } else {
+            for (channel, sample) in self.frame.iter_mut().enumerate() {
+                *sample = if channel % 2 == 0 { left } else { right };
+            }
+        }
+
+        true
+    }
+}
+
+impl<I> Source for ChannelMap<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for ChannelMap<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.next_channel == self.channels as usize {
+            if !self.next_frame() {
+                return None;
+            }
+            self.next_channel = 0;
+        }
+
+        let sample = self.frame[self.next_channel];
+        self.next_channel += 1;
+        Some(sample)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn map(mode: ChannelMode, input_channels: u16, channels: u16, samples: &[f32]) -> Vec<f32> {
+        let input = SamplesBuffer::new(input_channels, 44100, samples.to_vec());
+        ChannelMap::new(input, mode, channels).collect()
+    }
+
+    #[test]
+    fn maps_stereo() {
+        let samples = [0.5, -0.25, 1.0, 0.0];
+        assert_eq!(map(ChannelMode::Stereo, 2, 2, &samples), samples);
+        assert_eq!(
+            map(ChannelMode::Mono, 2, 2, &samples),
+            [0.125, 0.125, 0.5, 0.5]
+        );
+        assert_eq!(
+            map(ChannelMode::Swap, 2, 2, &samples),
+            [-0.25, 0.5, 0.0, 1.0]
+        );
+        assert_eq!(map(ChannelMode::Left, 2, 2, &samples), [0.5, 0.5, 1.0, 1.0]);
+        assert_eq!(
+            map(ChannelMode::Right, 2, 2, &samples),
+            [-0.25, -0.25, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn repeats_over_output_channels() {
+        assert_eq!(
+            map(ChannelMode::Swap, 2, 4, &[0.5, -0.25]),
+            [-0.25, 0.5, -0.25, 0.5]
+        );
+        assert_eq!(
+            map(ChannelMode::Stereo, 2, 3, &[0.5, -0.25]),
+            [0.5, -0.25, 0.5]
+        );
+    }
+
+    #[test]
+    fn downmixes_to_a_single_channel() {
+        assert_eq!(
+            map(ChannelMode::Stereo, 2, 1, &[0.5, -0.25, 1.0, 0.0]),
+            [0.125, 0.5]
+        );
+        assert_eq!(map(ChannelMode::Left, 2, 1, &[0.5, -0.25]), [0.5]);
+    }
+
+    #[test]
+    fn spreads_mono_input() {
+        assert_eq!(
+            map(ChannelMode::Stereo, 1, 2, &[0.5, -0.25]),
+            [0.5, 0.5, -0.25, -0.25]
+        );
+    }
+
+    #[test]
+    fn drops_incomplete_frames() {
+        assert_eq!(
+            map(ChannelMode::Stereo, 2, 2, &[0.5, -0.25, 1.0]),
+            [0.5, -0.25]
+        );
+    }
+}
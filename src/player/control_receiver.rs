@@ -1,5 +1,5 @@
 use super::Command;
-use crate::{player::ntp::Time, shutdown::Shutdown};
+use crate::{metrics::Metrics, player::ntp::Time, shutdown::Shutdown};
 use std::sync::Arc;
 use tokio::{net::UdpSocket, sync::mpsc};
 use tracing::{debug, instrument, trace};
@@ -8,6 +8,7 @@ use tracing::{debug, instrument, trace};
 pub(crate) struct ControlReceiver {
     pub(crate) player_tx: mpsc::Sender<Command>,
     pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) metrics: Arc<Metrics>,
 
     pub(crate) shutdown: Shutdown,
 }
@@ -40,11 +41,12 @@ impl ControlReceiver {
                 }
             };
 
-            match rtp_rs::RtpReader::new(&buf[..length]) {
-                Ok(reader) if reader.payload_type() == 84 => {
-                    let seq = reader.sequence_number();
-                    // rtp reader expects `SSRC` field atm and interprets half of the first timestamp as `SSRC`
-                    // pull out timestamp data directly from our buffer
+            // Sync packets have the extension bit set without carrying an
+            // extension, parse the packets by hand instead of using `RtpReader`.
+            match (buf[1] & 0x7f, length) {
+                (84, 20..) => {
+                    let seq = u16::from_be_bytes(buf[2..4].try_into().unwrap());
+                    let timestamp_less_latency = u32::from_be_bytes(buf[4..8].try_into().unwrap());
                     let time = Time {
                         sec: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
                         frac: u32::from_be_bytes(buf[12..16].try_into().unwrap()),
@@ -52,25 +54,33 @@ impl ControlReceiver {
                     let timestamp = u32::from_be_bytes(buf[16..20].try_into().unwrap());
 
                     trace!("{:?} - {:?}-{:?}", seq, time, timestamp);
+
+                    // The frame at the timestamp less the latency is meant to
+                    // be played at `time`.
+                    self.player_tx
+                        .send(Command::Sync {
+                            timestamp: timestamp_less_latency,
+                            time,
+                        })
+                        .await?
                 }
-                Ok(reader) if reader.payload_type() == 86 => {
-                    // rtp reader expects `SSRC` field atm and interprets original seq as `SSRC`
-                    // pull out seq + audio packet data directly from our buffer
+                (86, 16..) => {
+                    // pull out seq + audio packet data of the resent packet
                     let seq = (buf[6] as u16) << 8 | (buf[7] as u16);
+                    let timestamp = u32::from_be_bytes(buf[8..12].try_into().unwrap());
                     let packet = buf[16..length].to_vec();
+                    self.metrics.resend_fulfilled_packets.inc();
 
                     self.player_tx
                         .send(Command::PutPacket {
                             seq: seq.into(),
-                            packet: packet,
+                            timestamp,
+                            packet,
                         })
                         .await?
                 }
-                Ok(_) => {
-                    trace!("unknown payload type");
-                }
-                Err(e) => {
-                    debug!("{:?}", e);
+                (payload_type, _) => {
+                    debug!(payload_type, length, "unknown control packet");
                 }
             };
         }
@@ -1,4 +1,4 @@
-use crate::shutdown::Shutdown;
+use crate::{metrics::Metrics, shutdown::Shutdown};
 use rtp_rs::{IntoSeqIterator, Seq};
 use std::{ops::Range, sync::Arc};
 use tokio::{net::UdpSocket, sync::mpsc};
@@ -13,6 +13,7 @@ pub(crate) enum ControlSenderCommand {
 pub(crate) struct ControlSender {
     pub(crate) control_server_rx: mpsc::Receiver<ControlSenderCommand>,
     pub(crate) socket: Arc<UdpSocket>,
+    pub(crate) metrics: Arc<Metrics>,
 
     pub(crate) shutdown: Shutdown,
 }
@@ -42,15 +43,19 @@ impl ControlSender {
                 ControlSenderCommand::MissingSeqs { seqs } => {
                     trace!("missing seqs: {:?}", seqs);
 
+                    let count = seqs.clone().seq_iter().count() as u16;
                     let message = [
                         [0x80, (0x55 | 0x80)],
                         1_u16.to_be_bytes(),
                         u16::from(seqs.start).to_be_bytes(),
-                        (seqs.seq_iter().count() as u16).to_be_bytes(),
+                        count.to_be_bytes(),
                     ]
                     .concat();
 
-                    let _ = self.socket.send(&message).await;
+                    if self.socket.send(&message).await.is_ok() {
+                        self.metrics.resend_requests.inc();
+                        self.metrics.resend_requested_packets.add(count.into());
+                    }
                 }
             }
         }
@@ -0,0 +1,131 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use rodio::Source;
+use std::time::Duration;
+
+/// Reduces samples to a lower bit depth using TPDF dither.
+///
+/// Before rounding to the target resolution, triangular distributed noise with
+/// an amplitude of ±1 LSB is added, which decorrelates the quantization error
+/// from the signal. Samples which are already representable at the target
+/// resolution (e.g. untouched 16 bit audio played at 16 bit) are passed
+/// through unchanged, keeping playback bit-perfect whenever possible.
+pub(crate) struct Dither<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+    /// Number of quantization steps per unit (`2^(bits - 1)`).
+    scale: f32,
+    rng: StdRng,
+}
+
+impl<I> Dither<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Builds a new `Dither` quantizing `input` to `bits` bits.
+    ///
+    /// # Panic
+    ///
+    /// - Panics if bits is not within `2..=24` (the precision of `f32`).
+    ///
+    pub(crate) fn new(input: I, bits: u32) -> Dither<I> {
+        assert!((2..=24).contains(&bits));
+
+        Dither {
+            input,
+            scale: (1_u32 << (bits - 1)) as f32,
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl<I> Source for Dither<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for Dither<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()? * self.scale;
+
+        let quantized = if sample.fract() == 0.0 {
+            sample
+        } else {
+            let noise = self.rng.gen::<f32>() - self.rng.gen::<f32>();
+            (sample + noise).round()
+        };
+
+        Some(quantized.clamp(-self.scale, self.scale - 1.0) / self.scale)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn stays_in_range() {
+        let samples = (0..=2000)
+            .map(|i| i as f32 / 1000.0 - 1.0)
+            .chain([1.0, -1.0, 0.99999, -0.99999])
+            .collect::<Vec<_>>();
+        let scale = (1 << 15) as f32;
+
+        for (input, output) in samples.iter().zip(Dither::new(
+            SamplesBuffer::new(1, 44100, samples.clone()),
+            16,
+        )) {
+            assert!((-1.0..=(scale - 1.0) / scale).contains(&output));
+            assert_eq!((output * scale).fract(), 0.0);
+            assert!(
+                (output - input).abs() <= 2.0 / scale,
+                "{} {}",
+                input,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn passes_representable_samples_through() {
+        let samples = [0, 1, -1, 12345, -32768, 32767]
+            .map(|sample| sample as f32 / (1 << 15) as f32)
+            .to_vec();
+        let output =
+            Dither::new(SamplesBuffer::new(2, 44100, samples.clone()), 16).collect::<Vec<_>>();
+        assert_eq!(output, samples);
+    }
+}
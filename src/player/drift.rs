@@ -0,0 +1,138 @@
+use std::time::Instant;
+
+/// Sync errors collected before correcting the playback rate, senders send
+/// one per second.
+const SAMPLES: usize = 30;
+
+/// Largest correction of the playback rate (±500 ppm).
+const MAX_CORRECTION: f64 = 0.0005;
+
+/// Estimates the drift between the clocks of the sender and the output from
+/// the sync errors of the playback, see `RateCorrection`.
+///
+/// The rate gets corrected by how fast the sync error grows, a constant error
+/// (e.g. a latency offset) is left alone.
+#[derive(Debug)]
+pub(crate) struct Drift {
+    /// Seconds since the first sample and sync error in seconds.
+    samples: Vec<(f64, f64)>,
+    start: Option<Instant>,
+    ratio: f64,
+}
+
+impl Drift {
+    pub(crate) fn new() -> Drift {
+        Drift {
+            samples: Vec::with_capacity(SAMPLES),
+            start: None,
+            ratio: 1.0,
+        }
+    }
+
+    /// Forgets the collected sync errors, e.g. after the playback got shifted
+    /// by a flush. The correction is kept.
+    pub(crate) fn reset(&mut self) {
+        self.samples.clear();
+        self.start = None;
+    }
+
+    /// Adds the sync `error` (in seconds, positive if the playback is ahead)
+    /// measured `at`. Returns the new ratio of the playback rate once enough
+    /// errors are collected.
+    pub(crate) fn add(&mut self, at: Instant, error: f64) -> Option<f64> {
+        if !error.is_finite() {
+            return None;
+        }
+        let start = *self.start.get_or_insert(at);
+        self.samples
+            .push((at.saturating_duration_since(start).as_secs_f64(), error));
+        if self.samples.len() < SAMPLES {
+            return None;
+        }
+
+        // Least squares slope, in seconds of error per second.
+        let n = self.samples.len() as f64;
+        let mean_t = self.samples.iter().map(|(t, _)| t).sum::<f64>() / n;
+        let mean_e = self.samples.iter().map(|(_, e)| e).sum::<f64>() / n;
+        let (covariance, variance) =
+            self.samples
+                .iter()
+                .fold((0.0, 0.0), |(covariance, variance), (t, e)| {
+                    (
+                        covariance + (t - mean_t) * (e - mean_e),
+                        variance + (t - mean_t) * (t - mean_t),
+                    )
+                });
+        self.reset();
+        if variance == 0.0 {
+            return None;
+        }
+
+        // A growing error means the output plays too fast, consume the input
+        // slower.
+        let slope = covariance / variance;
+        self.ratio = (self.ratio * (1.0 - slope)).clamp(1.0 - MAX_CORRECTION, 1.0 + MAX_CORRECTION);
+        Some(self.ratio)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Feeds a sync error per second growing by `slope` seconds per second.
+    fn feed(drift: &mut Drift, start: Instant, slope: f64, offset: f64) -> Option<f64> {
+        (0..SAMPLES)
+            .map(|i| {
+                let at = start + Duration::from_secs(i as u64);
+                drift.add(at, offset + slope * i as f64)
+            })
+            .last()
+            .flatten()
+    }
+
+    #[test]
+    fn leaves_a_constant_error_alone() {
+        let mut drift = Drift::new();
+        assert_eq!(feed(&mut drift, Instant::now(), 0.0, 0.05), Some(1.0));
+    }
+
+    #[test]
+    fn corrects_a_growing_error() {
+        let mut drift = Drift::new();
+        let start = Instant::now();
+
+        // The output plays 100 ppm too fast.
+        let ratio = feed(&mut drift, start, 0.0001, 0.0).unwrap();
+        assert!((ratio - 0.9999).abs() < 1e-9, "{}", ratio);
+
+        // The remaining drift adds up.
+        let ratio = feed(&mut drift, start, 0.0001, 0.0).unwrap();
+        assert!((ratio - 0.9999 * 0.9999).abs() < 1e-9, "{}", ratio);
+    }
+
+    #[test]
+    fn limits_the_correction() {
+        let mut drift = Drift::new();
+        assert_eq!(
+            feed(&mut drift, Instant::now(), -0.01, 0.0),
+            Some(1.0 + MAX_CORRECTION)
+        );
+    }
+
+    #[test]
+    fn waits_for_enough_errors() {
+        let mut drift = Drift::new();
+        let start = Instant::now();
+        for i in 0..SAMPLES - 1 {
+            assert_eq!(
+                drift.add(start + Duration::from_secs(i as u64), 0.001),
+                None
+            );
+        }
+        drift.reset();
+        assert_eq!(drift.add(start, 0.001), None);
+        assert_eq!(drift.add(start, f64::NAN), None);
+    }
+}
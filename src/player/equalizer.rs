@@ -0,0 +1,352 @@
+use rodio::Source;
+use std::{f64::consts::PI, str::FromStr, time::Duration};
+
+/// Response type of a single equalizer filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FilterType {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl FromStr for FilterType {
+    type Err = crate::error::Error;
+
+    fn from_str(input: &str) -> crate::result::Result<FilterType> {
+        match input {
+            "peaking" => Ok(FilterType::Peaking),
+            "lowshelf" => Ok(FilterType::LowShelf),
+            "highshelf" => Ok(FilterType::HighShelf),
+            "lowpass" => Ok(FilterType::LowPass),
+            "highpass" => Ok(FilterType::HighPass),
+            _ => Err(format!("unknown filter type {:?}", input).into()),
+        }
+    }
+}
+
+/// Configuration of a single equalizer filter.
+///
+/// Parsed from a comma separated list of `key=value` pairs, e.g.
+/// `type=peaking,freq=1000,gain=-3,q=1.4,channel=0`. `gain` (in dB) defaults
+/// to 0 and is ignored by low and high pass filters, `q` defaults to 0.707.
+/// Without `channel` the filter is applied to all output channels.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Filter {
+    pub(crate) filter_type: FilterType,
+    pub(crate) frequency: f64,
+    pub(crate) gain: f64,
+    pub(crate) q: f64,
+    pub(crate) channel: Option<u16>,
+}
+
+impl FromStr for Filter {
+    type Err = crate::error::Error;
+
+    fn from_str(input: &str) -> crate::result::Result<Filter> {
+        let mut filter_type = None;
+        let mut frequency = None;
+        let mut gain = 0.0_f64;
+        let mut q = std::f64::consts::FRAC_1_SQRT_2;
+        let mut channel = None;
+
+        for pair in input.split(',') {
+            match pair.trim().split_once('=') {
+                Some(("type", value)) => filter_type = Some(value.trim().parse()?),
+                Some(("freq", value)) => frequency = Some(value.trim().parse::<f64>()?),
+                Some(("gain", value)) => gain = value.trim().parse()?,
+                Some(("q", value)) => q = value.trim().parse()?,
+                Some(("channel", value)) => channel = Some(value.trim().parse()?),
+                _ => return Err(format!("invalid filter parameter {:?}", pair).into()),
+            }
+        }
+
+        let filter_type = filter_type.ok_or("missing filter type")?;
+        let frequency = frequency.ok_or("missing filter frequency")?;
+        if !frequency.is_finite() || frequency <= 0.0 {
+            return Err("filter frequency must be positive".into());
+        }
+        if !gain.is_finite() {
+            return Err("filter gain must be finite".into());
+        }
+        if !q.is_finite() || q <= 0.0 {
+            return Err("filter q must be positive".into());
+        }
+
+        Ok(Filter {
+            filter_type,
+            frequency,
+            gain,
+            q,
+            channel,
+        })
+    }
+}
+
+/// Second order IIR filter (transposed direct form II).
+///
+/// Coefficients are calculated as described in the "Audio EQ Cookbook" by
+/// Robert Bristow-Johnson.
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn new(filter: &Filter, sample_rate: u32) -> Biquad {
+        // keep the center frequency below nyquist
+        let frequency = filter.frequency.min(0.49 * sample_rate as f64);
+        let w0 = 2.0 * PI * frequency / sample_rate as f64;
+        let (sin_w0, cos_w0) = w0.sin_cos();
+        let alpha = sin_w0 / (2.0 * filter.q);
+        let a = 10_f64.powf(filter.gain / 40.0);
+
+        let (b0, b1, b2, a0, a1, a2) = match filter.filter_type {
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos_w0,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos_w0,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => {
+                let beta = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 + beta),
+                    2.0 * a * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) - (a - 1.0) * cos_w0 - beta),
+                    (a + 1.0) + (a - 1.0) * cos_w0 + beta,
+                    -2.0 * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    (a + 1.0) + (a - 1.0) * cos_w0 - beta,
+                )
+            }
+            FilterType::HighShelf => {
+                let beta = 2.0 * a.sqrt() * alpha;
+                (
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 + beta),
+                    -2.0 * a * ((a - 1.0) + (a + 1.0) * cos_w0),
+                    a * ((a + 1.0) + (a - 1.0) * cos_w0 - beta),
+                    (a + 1.0) - (a - 1.0) * cos_w0 + beta,
+                    2.0 * ((a - 1.0) - (a + 1.0) * cos_w0),
+                    (a + 1.0) - (a - 1.0) * cos_w0 - beta,
+                )
+            }
+            FilterType::LowPass => (
+                (1.0 - cos_w0) / 2.0,
+                1.0 - cos_w0,
+                (1.0 - cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+            FilterType::HighPass => (
+                (1.0 + cos_w0) / 2.0,
+                -(1.0 + cos_w0),
+                (1.0 + cos_w0) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos_w0,
+                1.0 - alpha,
+            ),
+        };
+
+        Biquad {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    #[inline]
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+        output
+    }
+}
+
+/// Applies a chain of `Filter`s to the channels of a source.
+pub(crate) struct Equalizer<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+
+    /// Filters for every channel, applied in order.
+    filters: Vec<Vec<Biquad>>,
+    next_channel: usize,
+}
+
+impl<I> Equalizer<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Builds a new `Equalizer`.
+    pub(crate) fn new(input: I, filters: &[Filter]) -> Equalizer<I> {
+        let sample_rate = input.sample_rate();
+        let filters = (0..input.channels())
+            .map(|channel| {
+                filters
+                    .iter()
+                    .filter(|filter| filter.channel.is_none_or(|c| c == channel))
+                    .map(|filter| Biquad::new(filter, sample_rate))
+                    .collect()
+            })
+            .collect();
+
+        Equalizer {
+            input,
+            filters,
+            next_channel: 0,
+        }
+    }
+}
+
+impl<I> Source for Equalizer<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for Equalizer<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+
+        let channel = self.next_channel;
+        self.next_channel = (channel + 1) % self.filters.len();
+
+        let output = self.filters[channel]
+            .iter_mut()
+            .fold(sample as f64, |sample, filter| filter.process(sample));
+        Some(output as f32)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    #[test]
+    fn passes_signal_through_at_0_db() {
+        let samples = (0..4410)
+            .map(|i| (i as f32 * 0.1).sin() * 0.5)
+            .collect::<Vec<_>>();
+        let filters = ["peaking", "lowshelf", "highshelf"]
+            .map(|filter_type| {
+                format!("type={},freq=1000,gain=0", filter_type)
+                    .parse()
+                    .unwrap()
+            })
+            .to_vec();
+
+        let input = SamplesBuffer::new(2, 44100, samples.clone());
+        for (input, output) in samples.iter().zip(Equalizer::new(input, &filters)) {
+            assert!((input - output).abs() < 1e-6, "{} {}", input, output);
+        }
+    }
+
+    #[test]
+    fn applies_filters_to_their_channel() {
+        let filter = "type=peaking,freq=1000,gain=-12,channel=1".parse().unwrap();
+        let samples = (0..4410)
+            .map(|i| ((i / 2) as f32 * 0.142).sin() * 0.5)
+            .collect::<Vec<_>>();
+
+        let input = SamplesBuffer::new(2, 44100, samples.clone());
+        let output = Equalizer::new(input, &[filter]).collect::<Vec<_>>();
+        let power = |channel: usize, samples: &[f32]| {
+            samples
+                .iter()
+                .skip(2000 + channel)
+                .step_by(2)
+                .map(|sample| sample * sample)
+                .sum::<f32>()
+        };
+
+        assert_eq!(power(0, &output), power(0, &samples));
+        // -12 dB at the center frequency (1000 Hz).
+        let ratio = power(1, &output) / power(1, &samples);
+        assert!((ratio - 10_f32.powf(-1.2)).abs() < 0.01, "{}", ratio);
+    }
+
+    #[test]
+    fn parses_filters() {
+        assert_eq!(
+            "type=lowpass, freq=200,q=2,channel=1"
+                .parse::<Filter>()
+                .unwrap(),
+            Filter {
+                filter_type: FilterType::LowPass,
+                frequency: 200.0,
+                gain: 0.0,
+                q: 2.0,
+                channel: Some(1),
+            }
+        );
+
+        for filter in [
+            "",
+            "freq=100",
+            "type=peaking",
+            "type=notch,freq=100",
+            "type=peaking,freq=0",
+            "type=peaking,freq=-100",
+            "type=peaking,freq=abc",
+            "type=peaking,freq=nan",
+            "type=peaking,freq=inf",
+            "type=peaking,freq=100,gain=nan",
+            "type=peaking,freq=100,gain=-inf",
+            "type=peaking,freq=100,q=0",
+            "type=peaking,freq=100,q=nan",
+            "type=peaking,freq=100,q=inf",
+            "type=peaking,freq=100,channel=-1",
+            "type=peaking,freq=100,width=2",
+        ] {
+            assert!(filter.parse::<Filter>().is_err(), "{:?}", filter);
+        }
+    }
+}
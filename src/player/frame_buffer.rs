@@ -1,18 +1,38 @@
+use crate::{
+    events::{Event, EventBus},
+    metrics::Metrics,
+};
 use rodio::{Sample, Source};
 use rtp_rs::Seq;
 use std::{
+    cmp::Ordering,
     collections::BTreeMap,
     ops::Range,
-    sync::{Arc, Mutex},
-    time::Duration,
+    sync::{
+        atomic::{self, AtomicI64},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
     vec::IntoIter,
 };
 
 pub(crate) struct FrameBuffer<S> {
-    data: BTreeMap<Seq, IntoIter<S>>,
+    /// Samples of each packet together with its RTP timestamp.
+    data: BTreeMap<Seq, (u32, IntoIter<S>)>,
 
     read_marker: Seq,
     write_marker: Seq,
+
+    /// Packet played last.
+    position: Option<Position>,
+}
+
+/// Packet played last and when it started playing.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Position {
+    pub(crate) timestamp: u32,
+    pub(crate) samples: usize,
+    pub(crate) started: Instant,
 }
 
 impl<S> FrameBuffer<S>
@@ -25,37 +45,140 @@ where
             data: BTreeMap::new(),
             read_marker: initial_seq,
             write_marker: initial_seq,
+            position: None,
         }
     }
 
-    pub(crate) fn add_packet(&mut self, seq: Seq, packet: IntoIter<S>) -> Range<Seq> {
+    pub(crate) fn add_packet(
+        &mut self,
+        seq: Seq,
+        timestamp: u32,
+        packet: IntoIter<S>,
+    ) -> AddedPacket {
         // trace!("packet added with seq {:?}", seq);
+        if seq - self.read_marker < 0 {
+            return AddedPacket::Late;
+        }
+        if self.data.contains_key(&seq) {
+            return AddedPacket::Duplicate;
+        }
+
+        self.data.insert(seq, (timestamp, packet));
+
+        // Resent packets fill gaps behind the write marker.
         let old_write_marker = self.write_marker;
+        if seq - old_write_marker <= 0 {
+            return AddedPacket::Added {
+                missing: Range {
+                    start: seq,
+                    end: seq,
+                },
+            };
+        }
         self.write_marker = seq;
-        self.data.insert(seq, packet);
 
-        Range {
-            start: old_write_marker.next(),
-            end: seq,
+        AddedPacket::Added {
+            missing: Range {
+                start: old_write_marker.next(),
+                end: seq,
+            },
         }
     }
 
-    pub(crate) fn flush(&mut self, seq: Seq) {
+    /// Returns the number of skipped packets which never arrived.
+    pub(crate) fn flush(&mut self, seq: Seq) -> u16 {
+        let mut lost = 0;
+        self.position = None;
+
         // move read marker to requested Seq
         // and remove all entries up until, but without requested Seq
         while self.read_marker != seq {
-            self.data.remove(&self.read_marker);
+            if self.data.remove(&self.read_marker).is_none()
+                && self.write_marker - self.read_marker > 0
+            {
+                lost += 1;
+            }
             self.read_marker = self.read_marker.next();
         }
+
+        lost
+    }
+
+    /// Number of packets between the read and the write marker.
+    pub(crate) fn buffered_packets(&self) -> u16 {
+        (self.write_marker - self.read_marker).max(0) as u16
+    }
+
+    /// Number of samples ready to be played.
+    pub(crate) fn buffered_samples(&self) -> usize {
+        self.data.values().map(|(_, packet)| packet.len()).sum()
+    }
+
+    /// Packet played last, `None` before the first packet got played after a
+    /// flush.
+    pub(crate) fn position(&self) -> Option<Position> {
+        self.position
+    }
+
+    /// Drops the oldest packets until at most `packets` packets are buffered.
+    ///
+    /// Returns the number of skipped packets which never arrived.
+    pub(crate) fn truncate(&mut self, packets: u16) -> u16 {
+        let buffered = self.buffered_packets();
+        if buffered > packets {
+            self.flush(self.read_marker + (buffered - packets))
+        } else {
+            0
+        }
     }
 
     fn pop_front(&mut self) -> Option<IntoIter<S>> {
         // trace!("packet popped");
-        let data = self.data.remove(&self.read_marker);
-        if data.is_some() {
-            self.read_marker = self.read_marker.next();
+        let (timestamp, data) = self.data.remove(&self.read_marker)?;
+        self.read_marker = self.read_marker.next();
+        self.position = Some(Position {
+            timestamp,
+            samples: data.len(),
+            started: Instant::now(),
+        });
+        Some(data)
+    }
+}
+
+/// Outcome of `FrameBuffer::add_packet`.
+#[derive(Debug)]
+pub(crate) enum AddedPacket {
+    /// The packet got buffered, `missing` packets were skipped by the sender.
+    Added { missing: Range<Seq> },
+    /// The packet was played already or flushed, it got dropped.
+    Late,
+    /// The packet is buffered already, it got dropped.
+    Duplicate,
+}
+
+/// Frames the playback gets shifted by, shared between the `Player` and the
+/// `FrameBufferSource`.
+///
+/// Positive values delay the playback by inserting silence, negative values
+/// skip audio.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PendingOffset(Arc<AtomicI64>);
+
+impl PendingOffset {
+    pub(crate) fn add(&self, frames: i64) {
+        self.0.fetch_add(frames, atomic::Ordering::Relaxed);
+    }
+
+    pub(crate) fn reset(&self) {
+        self.0.store(0, atomic::Ordering::Relaxed);
+    }
+
+    fn take(&self) -> i64 {
+        // Avoid the more expensive swap for every frame.
+        if self.0.load(atomic::Ordering::Relaxed) == 0 {
+            return 0;
         }
-        data
+        self.0.swap(0, atomic::Ordering::Relaxed)
     }
 }
 
@@ -63,8 +186,19 @@ pub(crate) struct FrameBufferSource<S> {
     frame_buffer: Arc<Mutex<FrameBuffer<S>>>,
     channels: u16,
     sample_rate: u32,
+    events: EventBus,
+    metrics: Arc<Metrics>,
+    offset: PendingOffset,
 
     current: Option<IntoIter<S>>,
+    /// `true` until the first packet and after running out of packets.
+    starved: bool,
+    /// Index of the next sample within its frame.
+    channel: u16,
+    /// Samples of silence still to be inserted.
+    silence: usize,
+    /// Samples of audio still to be skipped.
+    skip: usize,
 }
 
 impl<S> FrameBufferSource<S>
@@ -82,6 +216,9 @@ where
         frame_buffer: Arc<Mutex<FrameBuffer<S>>>,
         channels: u16,
         sample_rate: u32,
+        events: EventBus,
+        metrics: Arc<Metrics>,
+        offset: PendingOffset,
     ) -> FrameBufferSource<S> {
         assert!(channels != 0);
         assert!(sample_rate != 0);
@@ -90,10 +227,41 @@ where
             frame_buffer,
             channels,
             sample_rate,
+            events,
+            metrics,
+            offset,
 
             current: None,
+            starved: true,
+            channel: 0,
+            silence: 0,
+            skip: 0,
         }
     }
+
+    /// Next buffered sample, `None` if there is none.
+    fn next_buffered(&mut self) -> Option<S> {
+        loop {
+            if let Some(sample) = self.current.as_mut().and_then(Iterator::next) {
+                self.starved = false;
+                return Some(sample);
+            }
+
+            // Continue with the next packet once the current one is played.
+            match self.frame_buffer.lock().unwrap().pop_front() {
+                Some(packet) => self.current = Some(packet),
+                None => break,
+            }
+        }
+
+        if !self.starved {
+            self.starved = true;
+            self.metrics.underruns.inc();
+            self.events.publish(Event::Underrun);
+        }
+
+        None
+    }
 }
 
 impl<S> Source for FrameBufferSource<S>
@@ -129,28 +297,31 @@ where
 
     #[inline]
     fn next(&mut self) -> Option<S> {
-        // TODO cleanup
-        if let Some(mut current) = self.current.take() {
-            if current.len() > 0 {
-                let val = current.next();
-                self.current = Some(current);
-                return val;
+        // Shift the playback by whole frames only.
+        if self.channel == 0 {
+            let frames = self.offset.take();
+            let samples = frames.unsigned_abs() as usize * self.channels as usize;
+            match frames.cmp(&0) {
+                Ordering::Greater => self.silence += samples,
+                Ordering::Less => self.skip += samples,
+                Ordering::Equal => {}
             }
         }
+        self.channel = (self.channel + 1) % self.channels;
 
-        let mut data = self.frame_buffer.lock().unwrap();
-        self.current = data.pop_front();
-        drop(data);
+        if self.silence > 0 {
+            self.silence -= 1;
+            return Some(S::zero_value());
+        }
 
-        if let Some(mut current) = self.current.take() {
-            if current.len() > 0 {
-                let val = current.next();
-                self.current = Some(current);
-                return val;
+        // Silence during underruns is not skipped, only actual audio.
+        loop {
+            match self.next_buffered() {
+                Some(_) if self.skip > 0 => self.skip -= 1,
+                Some(sample) => return Some(sample),
+                None => return Some(S::zero_value()),
             }
         }
-
-        Some(S::zero_value())
     }
 
     #[inline]
@@ -158,3 +329,60 @@ where
         (0, None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Source of two channels playing `packets`, shifted by `offset` frames.
+    fn source(packets: &[&[f32]], offset: i64) -> FrameBufferSource<f32> {
+        let mut frame_buffer = FrameBuffer::new(Seq::from(0));
+        for (seq, packet) in packets.iter().enumerate() {
+            let packet = Vec::from(*packet);
+            frame_buffer.add_packet(Seq::from(seq as u16), seq as u32 * 2, packet.into_iter());
+        }
+
+        let pending_offset = PendingOffset::default();
+        pending_offset.add(offset);
+        FrameBufferSource::new(
+            Arc::new(Mutex::new(frame_buffer)),
+            2,
+            44100,
+            EventBus::new(),
+            Arc::new(Metrics::new()),
+            pending_offset,
+        )
+    }
+
+    #[test]
+    fn plays_packets_in_order() {
+        let packets: &[&[f32]] = &[&[0.1, 0.2], &[], &[0.3, 0.4, 0.5, 0.6]];
+        let samples = source(packets, 0).take(8).collect::<Vec<_>>();
+        assert_eq!(samples, [0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn shifts_the_playback() {
+        let packets: &[&[f32]] = &[&[0.1, 0.2], &[0.3, 0.4]];
+
+        // Delayed by silence.
+        let samples = source(packets, 1).take(6).collect::<Vec<_>>();
+        assert_eq!(samples, [0.0, 0.0, 0.1, 0.2, 0.3, 0.4]);
+
+        // Early by skipping audio.
+        let samples = source(packets, -1).take(4).collect::<Vec<_>>();
+        assert_eq!(samples, [0.3, 0.4, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn shifts_by_whole_frames() {
+        let packets: &[&[f32]] = &[&[0.1, 0.2, 0.3, 0.4]];
+        let mut source = source(packets, 0);
+        assert_eq!(source.next(), Some(0.1));
+
+        // Applied with the next frame.
+        source.offset.add(1);
+        let samples = source.take(5).collect::<Vec<_>>();
+        assert_eq!(samples, [0.2, 0.0, 0.0, 0.3, 0.4]);
+    }
+}
@@ -1,48 +1,83 @@
+mod channel_map;
 mod control_receiver;
 mod control_sender;
+mod dither;
+mod drift;
+mod equalizer;
 mod frame_buffer;
 mod ntp;
+mod resampler;
 mod server_receiver;
 mod timing_receiver;
 mod timing_sender;
+mod volume;
 
 use crate::{
+    dacp::{DacpCommand, Remote, RemoteCommand},
+    dmap::Metadata,
+    events::{Event, EventBus},
+    metrics::Metrics,
+    network, output,
     player::{
+        channel_map::ChannelMap,
         control_receiver::ControlReceiver,
         control_sender::{ControlSender, ControlSenderCommand},
-        frame_buffer::{FrameBuffer, FrameBufferSource},
+        dither::Dither,
+        drift::Drift,
+        equalizer::Equalizer,
+        frame_buffer::{AddedPacket, FrameBuffer, FrameBufferSource, PendingOffset},
+        ntp::Time,
+        resampler::{RateCorrection, Resampler},
         server_receiver::ServerReceiver,
         timing_receiver::TimingReceiver,
         timing_sender::TimingSender,
+        volume::{airplay_volume_to_gain, Gain, Volume},
     },
     result::Result,
     rtp_info::RtpInfo,
     shutdown::Shutdown,
+    Configuration,
 };
 use aes::{
     cipher::block_padding::ZeroPadding,
-    cipher::{
-        block_padding::Padding, generic_array::typenum::U16, generic_array::GenericArray,
-        BlockDecryptMut,
-    },
+    cipher::{generic_array::GenericArray, BlockDecryptMut},
     cipher::{InnerIvInit, KeyInit},
     Aes128,
 };
 use alac::{Decoder, StreamInfo};
-use rodio::{OutputStream, Sink};
+use bytes::Bytes;
+use rodio::Sink;
 use rtp_rs::Seq;
+use serde::Serialize;
 use std::{
-    net::{IpAddr, SocketAddr},
+    net::SocketAddr,
     sync::{Arc, Mutex},
+    time::Instant,
 };
-use tokio::{
-    net::UdpSocket,
-    sync::{
-        broadcast::{self, Sender},
-        mpsc, oneshot,
-    },
+use tokio::sync::{
+    broadcast::{self, Sender},
+    mpsc, oneshot, watch,
 };
-use tracing::error;
+use tracing::{debug, error, info, Instrument, Span};
+
+pub(crate) use channel_map::ChannelMode;
+pub(crate) use equalizer::Filter;
+pub(crate) use volume::VolumePolicy;
+
+/// Codecs senders can stream, numbered as in the `cn` TXT record of the mDNS
+/// service (1 = ALAC).
+pub(crate) const CODECS: &[u8] = &[1];
+/// Encryption of the audio, numbered as in the `et` TXT record (1 = RSA).
+pub(crate) const ENCRYPTION_TYPES: &[u8] = &[1];
+/// Metadata senders can send, numbered as in the `md` TXT record (0 = text,
+/// 1 = artwork, 2 = progress).
+pub(crate) const METADATA_TYPES: &[u8] = &[0, 1, 2];
+/// Number of channels of the audio streamed by senders.
+pub(crate) const CHANNELS: u16 = 2;
+/// Bits per sample of the audio streamed by senders.
+pub(crate) const SAMPLE_SIZE: u16 = 16;
+/// Sample rate of the audio streamed by senders.
+pub(crate) const SAMPLE_RATE: u32 = 44100;
 
 type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
 
@@ -52,19 +87,47 @@ pub(crate) struct Encryption {
     pub(crate) aeskey: Vec<u8>,
 }
 
+/// The sender owning the current session.
+#[derive(Debug, Clone)]
+pub(crate) struct Client {
+    /// Id of the RTSP connection the session was announced on.
+    pub(crate) connection_id: u64,
+    pub(crate) addr: SocketAddr,
+    /// Name of the sending device, e.g. `Stefan's iPhone`.
+    pub(crate) name: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    /// DACP server of the client, if it can be remote controlled.
+    pub(crate) remote: Option<Remote>,
+    /// Asks the RTSP connection of the client to close.
+    pub(crate) disconnect: mpsc::Sender<()>,
+}
+
 #[derive(Debug)]
 pub(crate) struct Announce {
+    pub(crate) client: Client,
+    /// Encoding name from the SDP `rtpmap`, e.g. `AppleLossless`.
+    pub(crate) codec: String,
     pub(crate) fmtp: String,
+    #[allow(dead_code)]
     pub(crate) minimum_latency: u32,
+    #[allow(dead_code)]
     pub(crate) maximum_latency: u32,
     pub(crate) encryption: Option<Encryption>,
+    /// Span of the RTSP connection, the parent of everything the player logs
+    /// for the session.
+    pub(crate) span: Span,
 }
 
 #[derive(Debug)]
 pub(crate) struct Setup {
-    pub(crate) ip: IpAddr,
+    /// Address of the sender, IPv6 link-local addresses include their scope.
+    pub(crate) peer_addr: SocketAddr,
+    /// Address the sender connected to, the UDP sockets are bound to it.
+    pub(crate) local_addr: SocketAddr,
     pub(crate) control_port: u16,
     pub(crate) timing_port: u16,
+    /// Span of the RTSP connection, entered by the UDP tasks of the session.
+    pub(crate) span: Span,
 }
 
 #[derive(Debug)]
@@ -79,6 +142,50 @@ pub(crate) struct GetParameterResponse {
     pub(crate) volume: f64,
 }
 
+#[derive(Debug, Clone)]
+pub(crate) struct Artwork {
+    pub(crate) content_type: String,
+    pub(crate) data: Bytes,
+}
+
+/// Playback position of the current track as RTP timestamps.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct Progress {
+    pub(crate) start: u32,
+    pub(crate) current: u32,
+    pub(crate) end: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct SessionStatus {
+    pub(crate) client: SocketAddr,
+    pub(crate) client_name: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) codec: String,
+    pub(crate) sample_rate: u32,
+    /// Whether the client can be remote controlled.
+    pub(crate) remote_control: bool,
+    pub(crate) buffered_packets: u16,
+    pub(crate) buffered_seconds: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Status {
+    /// Name the player is announced as, see `Mdns`.
+    pub(crate) name: String,
+    pub(crate) session: Option<SessionStatus>,
+    /// Volume requested by the sender (AirPlay dB scale).
+    pub(crate) volume: f64,
+    /// Local volume (`0.0..=1.0`) applied on top of the sender volume.
+    pub(crate) local_volume: f32,
+    pub(crate) muted: bool,
+    pub(crate) paused: bool,
+    pub(crate) metadata: Metadata,
+    pub(crate) progress: Option<Progress>,
+    /// Content type of the current artwork, if any.
+    pub(crate) artwork: Option<String>,
+}
+
 #[derive(Debug)]
 pub(crate) enum Command {
     // RTSP
@@ -95,6 +202,7 @@ pub(crate) enum Command {
         resp: oneshot::Sender<Result<()>>,
     },
     Teardown {
+        connection_id: u64,
         resp: oneshot::Sender<Result<()>>,
     },
     SetParameter {
@@ -104,22 +212,90 @@ pub(crate) enum Command {
     GetParameter {
         resp: oneshot::Sender<GetParameterResponse>,
     },
+    SetMetadata {
+        metadata: Metadata,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    SetArtwork {
+        artwork: Option<Artwork>,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    SetProgress {
+        progress: Progress,
+        resp: oneshot::Sender<Result<()>>,
+    },
     Flush {
         payload: RtpInfo,
         resp: oneshot::Sender<Result<()>>,
     },
 
+    // Control
+    GetStatus {
+        resp: oneshot::Sender<Status>,
+    },
+    GetArtwork {
+        resp: oneshot::Sender<Option<Artwork>>,
+    },
+    SetLocalVolume {
+        volume: f32,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    SetMuted {
+        muted: bool,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    SetPaused {
+        paused: bool,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    /// Sends a playback control to the current client.
+    RemoteControl {
+        command: RemoteCommand,
+        resp: oneshot::Sender<Result<()>>,
+    },
+    /// Closes the connection of the current client, responds whether there
+    /// was one.
+    Disconnect {
+        resp: oneshot::Sender<bool>,
+    },
+
     // Internal
     PutPacket {
         seq: Seq,
+        timestamp: u32,
         packet: Vec<u8>,
     },
+    /// Frame `timestamp` is meant to be played at `time` of the sender clock.
+    Sync {
+        timestamp: u32,
+        time: Time,
+    },
 }
 
 pub(crate) struct Player {
+    /// App configuration, the volume policy and the latency offset are applied
+    /// as soon as they change.
+    pub(crate) config: watch::Receiver<Arc<Configuration>>,
+
+    /// Name the player is announced as, differs from the configured one after
+    /// resolving a name conflict.
+    pub(crate) name: watch::Receiver<String>,
+
+    /// Publishes what happens during playback.
+    pub(crate) events: EventBus,
+
+    /// Counts packets, resend requests and the like.
+    pub(crate) metrics: Arc<Metrics>,
+
+    /// Used to remote control the current client.
+    pub(crate) dacp_tx: mpsc::Sender<DacpCommand>,
+
     pub(crate) player_tx: mpsc::Sender<Command>,
     pub(crate) player_rx: mpsc::Receiver<Command>,
 
+    /// Told once the output stream is open and the player is ready to play.
+    pub(crate) ready: mpsc::UnboundedSender<()>,
+
     /// Listen for shutdown notifications.
     ///
     /// A wrapper around the `broadcast::Receiver` paired with the sender in
@@ -141,17 +317,69 @@ impl Player {
         let mut encryption: Option<Encryption> = None;
         let mut cipher: Option<Aes128> = None;
         let mut alac: Option<Decoder> = None;
-        let mut frame_buffer: Option<Arc<Mutex<FrameBuffer<i16>>>> = None;
+        let mut frame_buffer: Option<Arc<Mutex<FrameBuffer<f32>>>> = None;
         let mut control_tx: Option<mpsc::Sender<ControlSenderCommand>> = None;
-
-        let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-        let sink = Sink::try_new(&stream_handle).unwrap();
+        let mut clock_offset: Option<watch::Receiver<f64>> = None;
+        let mut client: Option<Client> = None;
+        let mut session = Span::none();
+        let mut codec: Option<String> = None;
+        let mut stream_format = (CHANNELS, SAMPLE_RATE);
+        let mut metadata = Metadata::default();
+        let mut artwork: Option<Artwork> = None;
+        let mut progress: Option<Progress> = None;
+        let mut local_volume = 1.0;
+        let mut muted = false;
+        let mut paused = false;
+        let mut paused_backlog = 0;
+        let gain = Gain::new(1.0);
+        let offset = PendingOffset::default();
+
+        let config = self.config.borrow_and_update().clone();
+        let mut volume_policy = config.volume;
+        let mut latency_offset = config.latency_offset;
+        gain.set(output_gain(
+            airplay_volume,
+            local_volume,
+            muted,
+            volume_policy,
+        ));
+
+        let device = output::find_device(config.device.as_deref())?;
+        let (_stream, stream_handle, sample_rate, sample_format) = output::open_stream(
+            &device,
+            config.output_channels,
+            config.sample_rate,
+            config.sample_format,
+        )?;
+        let mut sink = Sink::try_new(&stream_handle)?;
+        let correction = RateCorrection::new();
+        let mut drift = Drift::new();
+        let _ = self.ready.send(());
 
         while !self.shutdown.is_shutdown() {
             let maybe_request = tokio::select! {
                 res = self.player_rx.recv() => {
                   res
                 },
+                Ok(()) = self.config.changed() => {
+                    let config = self.config.borrow_and_update().clone();
+                    if config.volume != volume_policy {
+                        volume_policy = config.volume;
+                        gain.set(output_gain(airplay_volume, local_volume, muted, volume_policy));
+                        info!(parent: &session, ?volume_policy, "applied volume policy");
+                    }
+                    if config.latency_offset != latency_offset {
+                        // Shift the running playback by the difference.
+                        offset.add(offset_frames(
+                            config.latency_offset - latency_offset,
+                            stream_format.1,
+                        ));
+                        latency_offset = config.latency_offset;
+                        drift.reset();
+                        info!(parent: &session, latency_offset, "applied latency offset");
+                    }
+                    continue;
+                },
                 _ = self.shutdown.recv() => {
                     // If a shutdown signal is received, return from `run`.
                     // This will result in the task terminating.
@@ -167,32 +395,75 @@ impl Player {
             // trace!("{:?}", request);
             match request {
                 Command::Announce { payload, resp } => {
+                    self.events.publish(Event::SessionStarted {
+                        session_id: payload.client.connection_id,
+                        client: payload.client.addr,
+                        client_name: payload.client.name.clone(),
+                        user_agent: payload.client.user_agent.clone(),
+                        codec: payload.codec.clone(),
+                    });
+
+                    client = Some(payload.client);
+                    session = payload.span;
+                    codec = Some(payload.codec);
+                    self.metrics.active_sessions.set(1.0);
+                    metadata = Metadata::default();
+                    artwork = None;
+                    progress = None;
+
                     encryption = payload.encryption;
                     if let Some(ref encryption) = encryption {
                         let key = GenericArray::from_slice(&encryption.aeskey);
-                        cipher = Some(Aes128::new(&key));
+                        cipher = Some(Aes128::new(key));
                     }
 
                     alac = StreamInfo::from_sdp_format_parameters(&payload.fmtp)
-                        .and_then(|config| Ok(Decoder::new(config)))
+                        .map(Decoder::new)
                         .ok();
 
                     let _ = resp.send(Ok(()));
                 }
                 Command::Setup { payload, resp } => {
-                    let c_sock = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-                    let t_sock = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-                    let s_sock = Arc::new(UdpSocket::bind("0.0.0.0:0").await?);
-
-                    let c_addr = SocketAddr::new(payload.ip, payload.control_port);
-                    let t_addr = SocketAddr::new(payload.ip, payload.timing_port);
-
-                    c_sock.connect(c_addr).await?;
-                    t_sock.connect(t_addr).await?;
+                    // Bound to the same address as the RTSP connection, which
+                    // keeps the address family and the scope of the sender.
+                    let ports = self.config.borrow().udp_ports;
+                    let sockets = async {
+                        let c_sock = network::bind_udp(payload.local_addr, ports).await?;
+                        let t_sock = network::bind_udp(payload.local_addr, ports).await?;
+                        let s_sock = network::bind_udp(payload.local_addr, ports).await?;
+                        Result::Ok((c_sock, t_sock, s_sock))
+                    };
+                    let (c_sock, t_sock, s_sock) = match sockets.await {
+                        Ok((c_sock, t_sock, s_sock)) => {
+                            (Arc::new(c_sock), Arc::new(t_sock), Arc::new(s_sock))
+                        }
+                        Err(err) => {
+                            let _ = resp.send(Err(err));
+                            continue;
+                        }
+                    };
 
-                    let c_port = c_sock.local_addr()?.port();
-                    let t_port = t_sock.local_addr()?.port();
-                    let s_port = s_sock.local_addr()?.port();
+                    let mut c_addr = payload.peer_addr;
+                    c_addr.set_port(payload.control_port);
+                    let mut t_addr = payload.peer_addr;
+                    t_addr.set_port(payload.timing_port);
+
+                    let ports = async {
+                        c_sock.connect(c_addr).await?;
+                        t_sock.connect(t_addr).await?;
+                        Result::Ok((
+                            c_sock.local_addr()?.port(),
+                            t_sock.local_addr()?.port(),
+                            s_sock.local_addr()?.port(),
+                        ))
+                    };
+                    let (c_port, t_port, s_port) = match ports.await {
+                        Ok(ports) => ports,
+                        Err(err) => {
+                            let _ = resp.send(Err(err));
+                            continue;
+                        }
+                    };
 
                     let (notify_shutdown_sender, _) = broadcast::channel(1);
                     _notify_shutdown = Some(notify_shutdown_sender.clone());
@@ -202,16 +473,21 @@ impl Player {
                         shutdown: Shutdown::new(notify_shutdown_sender.subscribe()),
                     };
 
+                    let (clock_offset_tx, clock_offset_rx) = watch::channel(0.0);
+                    clock_offset = Some(clock_offset_rx);
                     let mut timing_receiver = TimingReceiver {
                         socket: t_sock.clone(),
+                        clock_offset: clock_offset_tx,
+                        metrics: self.metrics.clone(),
                         player_tx: self.player_tx.clone(),
                         shutdown: Shutdown::new(notify_shutdown_sender.subscribe()),
                     };
 
                     let (control_server_tx, control_server_rx) = mpsc::channel(4);
                     let mut control_sender = ControlSender {
-                        control_server_rx: control_server_rx,
+                        control_server_rx,
                         socket: c_sock.clone(),
+                        metrics: self.metrics.clone(),
                         shutdown: Shutdown::new(notify_shutdown_sender.subscribe()),
                     };
 
@@ -219,6 +495,7 @@ impl Player {
 
                     let mut control_receiver = ControlReceiver {
                         socket: c_sock.clone(),
+                        metrics: self.metrics.clone(),
                         player_tx: self.player_tx.clone(),
                         shutdown: Shutdown::new(notify_shutdown_sender.subscribe()),
                     };
@@ -229,40 +506,55 @@ impl Player {
                         shutdown: Shutdown::new(notify_shutdown_sender.subscribe()),
                     };
 
-                    tokio::spawn(async move {
-                        // Process the connection. If an error is encountered, log it.
-                        if let Err(err) = timing_sender.run().await {
-                            error!(cause = ?err, "connection error");
+                    tokio::spawn(
+                        async move {
+                            // Process the connection. If an error is encountered, log it.
+                            if let Err(err) = timing_sender.run().await {
+                                error!(cause = ?err, "connection error");
+                            }
                         }
-                    });
-
-                    tokio::spawn(async move {
-                        // Process the connection. If an error is encountered, log it.
-                        if let Err(err) = timing_receiver.run().await {
-                            error!(cause = ?err, "connection error");
+                        .instrument(payload.span.clone()),
+                    );
+
+                    tokio::spawn(
+                        async move {
+                            // Process the connection. If an error is encountered, log it.
+                            if let Err(err) = timing_receiver.run().await {
+                                error!(cause = ?err, "connection error");
+                            }
                         }
-                    });
-
-                    tokio::spawn(async move {
-                        // Process the connection. If an error is encountered, log it.
-                        if let Err(err) = control_sender.run().await {
-                            error!(cause = ?err, "connection error");
+                        .instrument(payload.span.clone()),
+                    );
+
+                    tokio::spawn(
+                        async move {
+                            // Process the connection. If an error is encountered, log it.
+                            if let Err(err) = control_sender.run().await {
+                                error!(cause = ?err, "connection error");
+                            }
                         }
-                    });
-
-                    tokio::spawn(async move {
-                        // Process the connection. If an error is encountered, log it.
-                        if let Err(err) = control_receiver.run().await {
-                            error!(cause = ?err, "connection error");
+                        .instrument(payload.span.clone()),
+                    );
+
+                    tokio::spawn(
+                        async move {
+                            // Process the connection. If an error is encountered, log it.
+                            if let Err(err) = control_receiver.run().await {
+                                error!(cause = ?err, "connection error");
+                            }
                         }
-                    });
-
-                    tokio::spawn(async move {
-                        // Process the connection. If an error is encountered, log it.
-                        if let Err(err) = server_receiver.run().await {
-                            error!(cause = ?err, "connection error");
+                        .instrument(payload.span.clone()),
+                    );
+
+                    tokio::spawn(
+                        async move {
+                            // Process the connection. If an error is encountered, log it.
+                            if let Err(err) = server_receiver.run().await {
+                                error!(cause = ?err, "connection error");
+                            }
                         }
-                    });
+                        .instrument(payload.span.clone()),
+                    );
 
                     let _ = resp.send(Ok(SetupResponse {
                         control_port: c_port,
@@ -271,25 +563,99 @@ impl Player {
                     }));
                 }
                 Command::Record { payload, resp } => {
+                    // The source of a previous RECORD never ends on its own,
+                    // dropping its sink stops it.
+                    let new_sink = match Sink::try_new(&stream_handle) {
+                        Ok(new_sink) => new_sink,
+                        Err(err) => {
+                            let _ = resp.send(Err(err.into()));
+                            continue;
+                        }
+                    };
+                    if paused {
+                        new_sink.pause();
+                    }
+                    sink = new_sink;
+
                     let inner_frame_buffer =
-                        Arc::new(Mutex::new(FrameBuffer::<i16>::new(payload.seq.into())));
-                    let source = FrameBufferSource::new(inner_frame_buffer.clone(), 2, 44100);
-                    sink.append(source);
+                        Arc::new(Mutex::new(FrameBuffer::<f32>::new(payload.seq.into())));
+                    stream_format = alac
+                        .as_ref()
+                        .map(|decoder| {
+                            let stream_info = decoder.stream_info();
+                            (stream_info.channels() as u16, stream_info.sample_rate())
+                        })
+                        .unwrap_or((CHANNELS, SAMPLE_RATE));
+                    let (channels, input_sample_rate) = stream_format;
+                    offset.reset();
+                    offset.add(offset_frames(latency_offset, input_sample_rate));
+                    let source = FrameBufferSource::new(
+                        inner_frame_buffer.clone(),
+                        channels,
+                        input_sample_rate,
+                        self.events.clone(),
+                        self.metrics.clone(),
+                        offset.clone(),
+                    );
+                    let source = ChannelMap::new(
+                        source,
+                        config.channel_mode,
+                        config.output_channels.unwrap_or(channels),
+                    );
+                    let source = Equalizer::new(source, &config.filters);
+                    let source = Volume::new(source, gain.clone());
+                    correction.set(1.0);
+                    drift = Drift::new();
+                    let source = Resampler::new(source, sample_rate, correction.clone());
+                    match sample_format.and_then(|format| format.dither_bits()) {
+                        Some(bits) => sink.append(Dither::new(source, bits)),
+                        None => sink.append(source),
+                    }
 
                     frame_buffer = Some(inner_frame_buffer);
+                    self.events.publish(Event::PlaybackStarted);
 
                     let _ = resp.send(Ok(()));
                 }
-                Command::Teardown { resp } => {
-                    _notify_shutdown = None;
-                    encryption = None;
-                    cipher = None;
-                    alac = None;
+                Command::Teardown {
+                    connection_id,
+                    resp,
+                } => {
+                    // Ignore connections which were superseded by another client.
+                    if let Some(ended) =
+                        client.take_if(|client| client.connection_id == connection_id)
+                    {
+                        self.events
+                            .publish(Event::SessionEnded { client: ended.addr });
+                        session = Span::none();
+
+                        _notify_shutdown = None;
+                        encryption = None;
+                        cipher = None;
+                        alac = None;
+                        frame_buffer = None;
+                        control_tx = None;
+                        clock_offset = None;
+                        codec = None;
+                        metadata = Metadata::default();
+                        artwork = None;
+                        progress = None;
+                        sink.stop();
+                        self.metrics.active_sessions.set(0.0);
+                        self.metrics.buffered_packets.set(0.0);
+                        self.metrics.sync_error.set(f64::NAN);
+                    }
 
                     let _ = resp.send(Ok(()));
                 }
                 Command::SetParameter { volume: vol, resp } => {
                     airplay_volume = vol;
+                    let output = output_gain(airplay_volume, local_volume, muted, volume_policy);
+                    gain.set(output);
+                    self.events.publish(Event::VolumeChanged {
+                        volume: airplay_volume,
+                        gain: output,
+                    });
                     let _ = resp.send(Ok(()));
                 }
                 Command::GetParameter { resp } => {
@@ -297,15 +663,216 @@ impl Player {
                         volume: airplay_volume,
                     });
                 }
+                Command::SetMetadata {
+                    metadata: new_metadata,
+                    resp,
+                } => {
+                    metadata = new_metadata;
+                    self.events.publish(Event::MetadataUpdated {
+                        metadata: metadata.clone(),
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                Command::SetArtwork {
+                    artwork: new_artwork,
+                    resp,
+                } => {
+                    artwork = new_artwork;
+                    self.events.publish(Event::ArtworkUpdated {
+                        content_type: artwork.as_ref().map(|artwork| artwork.content_type.clone()),
+                        data: artwork
+                            .as_ref()
+                            .map(|artwork| artwork.data.clone())
+                            .unwrap_or_default(),
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                Command::SetProgress {
+                    progress: new_progress,
+                    resp,
+                } => {
+                    progress = Some(new_progress);
+                    self.events.publish(Event::ProgressUpdated {
+                        progress: new_progress,
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                Command::GetStatus { resp } => {
+                    let session = client.as_ref().map(|client| {
+                        let (buffered_packets, buffered_samples) = frame_buffer
+                            .as_ref()
+                            .map(|frame_buffer| {
+                                let frame_buffer = frame_buffer.lock().unwrap();
+                                (
+                                    frame_buffer.buffered_packets(),
+                                    frame_buffer.buffered_samples(),
+                                )
+                            })
+                            .unwrap_or((0, 0));
+                        let (channels, input_sample_rate) = stream_format;
+
+                        SessionStatus {
+                            client: client.addr,
+                            client_name: client.name.clone(),
+                            user_agent: client.user_agent.clone(),
+                            codec: codec.clone().unwrap_or_default(),
+                            sample_rate: input_sample_rate,
+                            remote_control: client.remote.is_some(),
+                            buffered_packets,
+                            buffered_seconds: buffered_samples as f64
+                                / channels as f64
+                                / input_sample_rate as f64,
+                        }
+                    });
+
+                    let _ = resp.send(Status {
+                        name: self.name.borrow().clone(),
+                        session,
+                        volume: airplay_volume,
+                        local_volume,
+                        muted,
+                        paused,
+                        metadata: metadata.clone(),
+                        progress,
+                        artwork: artwork.as_ref().map(|artwork| artwork.content_type.clone()),
+                    });
+                }
+                Command::GetArtwork { resp } => {
+                    let _ = resp.send(artwork.clone());
+                }
+                Command::SetLocalVolume { volume, resp } => {
+                    local_volume = volume.clamp(0.0, 1.0);
+                    gain.set(output_gain(
+                        airplay_volume,
+                        local_volume,
+                        muted,
+                        volume_policy,
+                    ));
+                    self.events.publish(Event::LocalVolumeChanged {
+                        local_volume,
+                        muted,
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                Command::SetMuted {
+                    muted: new_muted,
+                    resp,
+                } => {
+                    muted = new_muted;
+                    gain.set(output_gain(
+                        airplay_volume,
+                        local_volume,
+                        muted,
+                        volume_policy,
+                    ));
+                    self.events.publish(Event::LocalVolumeChanged {
+                        local_volume,
+                        muted,
+                    });
+                    let _ = resp.send(Ok(()));
+                }
+                Command::SetPaused {
+                    paused: new_paused,
+                    resp,
+                } => {
+                    if new_paused && !paused {
+                        paused_backlog = frame_buffer
+                            .as_ref()
+                            .map(|frame_buffer| frame_buffer.lock().unwrap().buffered_packets())
+                            .unwrap_or(0);
+                        sink.pause();
+                    } else if !new_paused && paused {
+                        // Packets kept arriving while paused, drop them to stay
+                        // in sync with the sender.
+                        if let Some(ref frame_buffer) = frame_buffer {
+                            let lost = frame_buffer.lock().unwrap().truncate(paused_backlog);
+                            self.metrics.packets_lost.add(lost.into());
+                        }
+                        sink.play();
+                    }
+                    if new_paused != paused {
+                        drift.reset();
+                        self.events
+                            .publish(Event::OutputPaused { paused: new_paused });
+                    }
+                    paused = new_paused;
+
+                    let _ = resp.send(Ok(()));
+                }
+                Command::RemoteControl { command, resp } => {
+                    match client.as_ref().and_then(|client| client.remote.clone()) {
+                        Some(remote) => {
+                            let _ = self
+                                .dacp_tx
+                                .send(DacpCommand::Send {
+                                    remote,
+                                    command,
+                                    resp,
+                                })
+                                .await;
+                        }
+                        None => {
+                            let _ = resp.send(Err("client does not support remote control".into()));
+                        }
+                    }
+                }
+                Command::Disconnect { resp } => {
+                    let disconnected = match client {
+                        Some(ref client) => {
+                            let _ = client.disconnect.try_send(());
+                            true
+                        }
+                        None => false,
+                    };
+
+                    let _ = resp.send(disconnected);
+                }
+                Command::Sync { timestamp, time } => {
+                    let position = frame_buffer
+                        .as_ref()
+                        .and_then(|frame_buffer| frame_buffer.lock().unwrap().position());
+                    if let Some(position) = position {
+                        let (channels, input_sample_rate) = stream_format;
+                        let rate = input_sample_rate as f64;
+                        let clock_offset = clock_offset.as_ref().map_or(0.0, |rx| *rx.borrow());
+                        let since_sync = Time::now().seconds_since(time) + clock_offset;
+                        let expected = timestamp.wrapping_add((since_sync * rate) as i32 as u32);
+                        // Playback stalls at the end of the packet if the next
+                        // one is missing.
+                        let frames = (position.started.elapsed().as_secs_f64() * rate)
+                            .min((position.samples / channels as usize) as f64);
+                        let playing = position.timestamp.wrapping_add(frames as u32);
+
+                        let error = playing.wrapping_sub(expected) as i32 as f64 / rate;
+                        self.metrics.sync_error.set(error);
+
+                        // The output does not consume any audio while paused.
+                        if !paused {
+                            if let Some(ratio) = drift.add(Instant::now(), error) {
+                                correction.set(ratio);
+                                debug!(parent: &session, ratio, "corrected playback rate");
+                            }
+                        }
+                    }
+                }
                 Command::Flush { payload, resp } => {
                     if let Some(ref frame_buffer) = frame_buffer {
                         let mut locked_frame_buffer = frame_buffer.lock().unwrap();
-                        locked_frame_buffer.flush(payload.seq.into());
+                        let lost = locked_frame_buffer.flush(payload.seq.into());
+                        self.metrics.packets_lost.add(lost.into());
+                        self.metrics
+                            .buffered_packets
+                            .set(locked_frame_buffer.buffered_packets().into());
+                        drift.reset();
                     }
 
                     let _ = resp.send(Ok(()));
                 }
-                Command::PutPacket { seq, packet } => match (encryption.take(), cipher.take()) {
+                Command::PutPacket {
+                    seq,
+                    timestamp,
+                    packet,
+                } => match (encryption.take(), cipher.take()) {
                     (Some(enc), Some(ci)) => {
                         let iv = GenericArray::from_slice(&enc.aesiv);
                         let mut buffer = packet.clone();
@@ -314,7 +881,7 @@ impl Player {
                         let aeslen = len & !0xf;
 
                         let be = (16 * (len / 16)) + 16;
-                        let decrypter = Aes128CbcDec::inner_iv_init(ci.clone(), &iv);
+                        let decrypter = Aes128CbcDec::inner_iv_init(ci.clone(), iv);
                         let mut result = decrypter
                             .decrypt_padded_vec_mut::<ZeroPadding>(&buffer[..be])
                             .unwrap();
@@ -324,38 +891,67 @@ impl Player {
                         match alac {
                             Some(ref mut decoder) => {
                                 let max_samples = decoder.stream_info().max_samples_per_packet();
-                                let mut out = vec![0; max_samples as usize];
+                                let mut out = vec![0_i32; max_samples as usize];
                                 let result = decoder.decode_packet(&result, &mut out).unwrap();
 
                                 // trace!("decoded: {:?} - {:?}", seq, result);
 
                                 let data = result
                                     .iter()
-                                    .map(|i| (i >> 16) as i16)
-                                    .collect::<Vec<i16>>();
+                                    .map(|sample| *sample as f32 / 2_147_483_648.0)
+                                    .collect::<Vec<f32>>();
                                 if let Some(ref frame_buffer) = frame_buffer {
-                                    let mut locked_frame_buffer = frame_buffer.lock().unwrap();
-                                    let missing_seqs =
-                                        locked_frame_buffer.add_packet(seq, data.into_iter());
-
-                                    if !missing_seqs.is_empty() {
-                                        if let Some(ref control_tx) = control_tx {
-                                            let _ = control_tx
-                                                .send(ControlSenderCommand::MissingSeqs {
-                                                    seqs: missing_seqs,
-                                                })
-                                                .await?;
+                                    self.metrics.packets_received.inc();
+
+                                    let added = {
+                                        let mut frame_buffer = frame_buffer.lock().unwrap();
+                                        let added = frame_buffer.add_packet(
+                                            seq,
+                                            timestamp,
+                                            data.into_iter(),
+                                        );
+                                        // Playback is stalled or far behind,
+                                        // catch up with the sender.
+                                        let lost = frame_buffer
+                                            .truncate(self.config.borrow().max_buffered_packets);
+                                        self.metrics.packets_lost.add(lost.into());
+                                        self.metrics
+                                            .buffered_packets
+                                            .set(frame_buffer.buffered_packets().into());
+                                        added
+                                    };
+
+                                    match added {
+                                        AddedPacket::Added { missing } if !missing.is_empty() => {
+                                            if let Some(ref control_tx) = control_tx {
+                                                control_tx
+                                                    .send(ControlSenderCommand::MissingSeqs {
+                                                        seqs: missing,
+                                                    })
+                                                    .await?;
+                                            }
+                                        }
+                                        AddedPacket::Added { .. } => {}
+                                        AddedPacket::Late => self.metrics.packets_late.inc(),
+                                        AddedPacket::Duplicate => {
+                                            self.metrics.packets_duplicate.inc()
                                         }
                                     }
                                 }
                             }
-                            None => todo!(),
+                            None => debug!(
+                                parent: &session,
+                                "dropping packet, no decoder for the stream"
+                            ),
                         }
 
                         encryption = Some(enc);
                         cipher = Some(ci);
                     }
-                    _ => todo!(),
+                    _ => debug!(
+                        parent: &session,
+                        "dropping packet, the stream is not encrypted"
+                    ),
                 },
             }
         }
@@ -363,3 +959,51 @@ impl Player {
         Ok(())
     }
 }
+
+/// Gain applied to the output for the given sender and local volume.
+fn output_gain(airplay_volume: f64, local_volume: f32, muted: bool, policy: VolumePolicy) -> f32 {
+    if muted {
+        0.0
+    } else if policy.ignore_sender {
+        local_volume * policy.max
+    } else {
+        airplay_volume_to_gain(airplay_volume) * local_volume * policy.max
+    }
+}
+
+/// Frames of the given latency offset in milliseconds.
+fn offset_frames(latency_offset: i32, sample_rate: u32) -> i64 {
+    latency_offset as i64 * sample_rate as i64 / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_the_volume_policy() {
+        let policy = VolumePolicy {
+            max: 0.5,
+            ignore_sender: false,
+        };
+        assert_eq!(output_gain(0.0, 1.0, false, policy), 0.5);
+        assert_eq!(output_gain(-20.0, 0.5, false, policy), 0.025);
+        assert_eq!(output_gain(-144.0, 1.0, false, policy), 0.0);
+        assert_eq!(output_gain(0.0, 1.0, true, policy), 0.0);
+
+        let policy = VolumePolicy {
+            max: 0.5,
+            ignore_sender: true,
+        };
+        assert_eq!(output_gain(-20.0, 0.5, false, policy), 0.25);
+        assert_eq!(output_gain(-144.0, 1.0, false, policy), 0.5);
+        assert_eq!(output_gain(-20.0, 1.0, true, policy), 0.0);
+    }
+
+    #[test]
+    fn converts_the_latency_offset() {
+        assert_eq!(offset_frames(0, 44100), 0);
+        assert_eq!(offset_frames(100, 44100), 4410);
+        assert_eq!(offset_frames(-250, 48000), -12000);
+    }
+}
@@ -1,5 +1,43 @@
-#[derive(Debug)]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+#[derive(Debug, Clone, Copy)]
 pub(crate) struct Time {
     pub(crate) sec: u32,
     pub(crate) frac: u32,
 }
+
+impl Time {
+    pub(crate) fn now() -> Time {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Time {
+            sec: (since_epoch.as_secs() + NTP_UNIX_OFFSET) as u32,
+            frac: (((since_epoch.subsec_nanos() as u64) << 32) / 1_000_000_000) as u32,
+        }
+    }
+
+    pub(crate) fn is_zero(self) -> bool {
+        self.sec == 0 && self.frac == 0
+    }
+
+    /// Seconds from `earlier` to `self`, negative if `self` is before it.
+    pub(crate) fn seconds_since(self, earlier: Time) -> f64 {
+        let diff = u64::from(self).wrapping_sub(u64::from(earlier)) as i64;
+        diff as f64 / (1_u64 << 32) as f64
+    }
+
+    pub(crate) fn to_be_bytes(self) -> [u8; 8] {
+        u64::from(self).to_be_bytes()
+    }
+}
+
+impl From<Time> for u64 {
+    fn from(time: Time) -> u64 {
+        (time.sec as u64) << 32 | time.frac as u64
+    }
+}
@@ -0,0 +1,302 @@
+use rodio::Source;
+use std::{
+    collections::VecDeque,
+    f64::consts::PI,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Number of input frames taken into account on each side of the
+/// interpolation point.
+const HALF_TAPS: usize = 16;
+
+/// Number of precomputed kernel values between two neighbouring input frames.
+const PHASES: usize = 256;
+
+/// Correction of the playback rate shared between the `Player` and the
+/// `Resampler`, e.g. `1.0001` consumes the input 100 ppm faster to compensate
+/// for the drift between the clocks of the sender and the output.
+#[derive(Debug, Clone)]
+pub(crate) struct RateCorrection(Arc<AtomicU64>);
+
+impl RateCorrection {
+    pub(crate) fn new() -> RateCorrection {
+        RateCorrection(Arc::new(AtomicU64::new(1.0_f64.to_bits())))
+    }
+
+    pub(crate) fn set(&self, ratio: f64) {
+        self.0.store(ratio.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Converts a source to a different sample rate.
+///
+/// Samples are interpolated using a Blackman windowed sinc kernel which is
+/// precomputed into a table (with linear interpolation between the table
+/// entries). When downsampling the cutoff frequency is lowered accordingly to
+/// avoid aliasing. If both sample rates match and the rate is not corrected,
+/// samples are passed through untouched.
+pub(crate) struct Resampler<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+    channels: usize,
+    sample_rate: u32,
+
+    /// Input frames to advance for every output frame at the nominal rate.
+    step: f64,
+    correction: RateCorrection,
+    kernel: Vec<f32>,
+
+    /// Interleaved input frames surrounding the interpolation point.
+    window: VecDeque<f32>,
+    /// Position of the interpolation point behind frame `HALF_TAPS - 1` of
+    /// `window`, always within `0.0..1.0`.
+    position: f64,
+
+    /// Interleaved samples of the current output frame.
+    frame: Vec<f32>,
+    next_channel: usize,
+    /// Samples are passed through, the sample rates match. The last
+    /// `HALF_TAPS - 1` frames are still kept in `window` to continue
+    /// seamlessly once the rate gets corrected.
+    passthrough: bool,
+}
+
+impl<I> Resampler<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Builds a new `Resampler` producing `sample_rate` samples per second,
+    /// corrected by `correction`.
+    ///
+    /// # Panic
+    ///
+    /// - Panics if the samples rate is zero.
+    ///
+    pub(crate) fn new(input: I, sample_rate: u32, correction: RateCorrection) -> Resampler<I> {
+        assert!(sample_rate != 0);
+
+        let channels = input.channels() as usize;
+        let step = input.sample_rate() as f64 / sample_rate as f64;
+
+        // cutoff relative to the input nyquist frequency
+        let cutoff = 0.97 * (1.0 / step).min(1.0);
+        let kernel = (0..=2 * HALF_TAPS * PHASES)
+            .map(|index| {
+                let x = index as f64 / PHASES as f64 - HALF_TAPS as f64;
+                (cutoff * sinc(cutoff * x) * blackman(x / HALF_TAPS as f64)) as f32
+            })
+            .collect();
+
+        // the first output frame is aligned with the first input frame
+        let mut window = VecDeque::with_capacity(2 * HALF_TAPS * channels);
+        window.resize((HALF_TAPS - 1) * channels, 0.0);
+
+        Resampler {
+            input,
+            channels,
+            sample_rate,
+
+            step,
+            correction,
+            kernel,
+
+            window,
+            position: 0.0,
+
+            frame: vec![0.0; channels],
+            next_channel: match step == 1.0 {
+                true => 0,
+                false => channels,
+            },
+            passthrough: step == 1.0,
+        }
+    }
+
+    /// Computes the next output frame. Returns `false` if the input ended.
+    fn next_frame(&mut self) -> bool {
+        while self.window.len() < 2 * HALF_TAPS * self.channels {
+            match self.input.next() {
+                Some(sample) => self.window.push_back(sample),
+                None => return false,
+            }
+        }
+
+        let phase = self.position * PHASES as f64;
+        let phase_index = phase.floor() as usize;
+        let phase_frac = (phase - phase.floor()) as f32;
+
+        for sample in self.frame.iter_mut() {
+            *sample = 0.0;
+        }
+
+        for tap in 0..2 * HALF_TAPS {
+            // kernel position for the distance between tap and interpolation point
+            let index = (tap + 1) * PHASES - phase_index;
+            let coefficient =
+                self.kernel[index] * (1.0 - phase_frac) + self.kernel[index - 1] * phase_frac;
+
+            for channel in 0..self.channels {
+                self.frame[channel] += self.window[tap * self.channels + channel] * coefficient;
+            }
+        }
+
+        self.position += self.step * self.correction.get();
+        while self.position >= 1.0 {
+            self.position -= 1.0;
+            self.window.drain(..self.channels);
+        }
+
+        true
+    }
+}
+
+impl<I> Source for Resampler<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.channels as u16
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for Resampler<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        if self.passthrough {
+            // Convert from the next frame on once the rate gets corrected,
+            // and keep converting.
+            if self.next_channel != 0 || self.correction.get() == 1.0 {
+                self.next_channel = (self.next_channel + 1) % self.channels;
+                let sample = self.input.next()?;
+                self.window.pop_front();
+                self.window.push_back(sample);
+                return Some(sample);
+            }
+            self.passthrough = false;
+            self.next_channel = self.channels;
+        }
+
+        if self.next_channel == self.channels {
+            if !self.next_frame() {
+                return None;
+            }
+            self.next_channel = 0;
+        }
+
+        let sample = self.frame[self.next_channel];
+        self.next_channel += 1;
+        Some(sample)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, None)
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn blackman(x: f64) -> f64 {
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    /// Output frames for `input_frames` frames at `step`, the kernel delays the
+    /// output by `HALF_TAPS` frames.
+    fn expected_frames(input_frames: usize, step: f64) -> f64 {
+        (input_frames - HALF_TAPS - 1) as f64 / step + 1.0
+    }
+
+    #[test]
+    fn converts_44100_to_48000() {
+        let input = SamplesBuffer::new(2, 44100, vec![0.5_f32; 2 * 44100]);
+        let resampler = Resampler::new(input, 48000, RateCorrection::new());
+        assert_eq!(resampler.sample_rate(), 48000);
+        assert_eq!(resampler.channels(), 2);
+
+        let output = resampler.collect::<Vec<_>>();
+        let frames = output.len() / 2;
+        assert_eq!(output.len() % 2, 0);
+        assert!((frames as f64 - expected_frames(44100, 44100.0 / 48000.0)).abs() <= 1.0);
+        assert!((frames as f64 - 48000.0).abs() < 48000.0 * 0.001);
+
+        // Past the kernel, a constant input stays constant.
+        for sample in &output[4 * HALF_TAPS..output.len() - 4 * HALF_TAPS] {
+            assert!((sample - 0.5).abs() < 0.005, "{}", sample);
+        }
+    }
+
+    #[test]
+    fn passes_matching_rates_through() {
+        let samples = (0..1000).map(|i| i as f32 / 1000.0).collect::<Vec<_>>();
+        let input = SamplesBuffer::new(2, 44100, samples.clone());
+        let output = Resampler::new(input, 44100, RateCorrection::new()).collect::<Vec<_>>();
+        assert_eq!(output, samples);
+    }
+
+    #[test]
+    fn corrects_the_rate() {
+        let correction = RateCorrection::new();
+        let input = SamplesBuffer::new(1, 44100, vec![0.5_f32; 44100]);
+        let mut resampler = Resampler::new(input, 44100, correction.clone());
+
+        // Passed through until corrected.
+        let before = resampler.by_ref().take(100).count();
+        correction.set(1.001);
+        let after = resampler.collect::<Vec<_>>();
+
+        let expected = expected_frames(44100 - before, 1.001);
+        assert!(
+            (after.len() as f64 - expected).abs() <= 1.0,
+            "{}",
+            after.len()
+        );
+
+        // The conversion continues from the passed through frames, a constant
+        // input stays constant across the switch.
+        for sample in &after {
+            assert!((sample - 0.5).abs() < 0.005, "{}", sample);
+        }
+    }
+}
@@ -42,12 +42,14 @@ impl ServerReceiver {
             match rtp_rs::RtpReader::new(&buf[..length]) {
                 Ok(reader) => {
                     let seq = reader.sequence_number();
+                    let timestamp = reader.timestamp();
                     let packet = reader.payload().to_vec();
 
                     self.player_tx
                         .send(Command::PutPacket {
-                            seq: seq,
-                            packet: packet,
+                            seq,
+                            timestamp,
+                            packet,
                         })
                         .await?
                 }
@@ -1,13 +1,21 @@
 use super::Command;
-use crate::{player::ntp::Time, shutdown::Shutdown};
+use crate::{metrics::Metrics, player::ntp::Time, shutdown::Shutdown};
 use std::sync::Arc;
-use tokio::{net::UdpSocket, sync::mpsc};
+use tokio::{
+    net::UdpSocket,
+    sync::{mpsc, watch},
+};
 use tracing::{debug, instrument, trace};
 
 #[derive(Debug)]
 pub(crate) struct TimingReceiver {
+    #[allow(dead_code)]
     pub(crate) player_tx: mpsc::Sender<Command>,
     pub(crate) socket: Arc<UdpSocket>,
+    /// Offset of the sender clock to ours in seconds, used by the `Player` to
+    /// synchronize the playback.
+    pub(crate) clock_offset: watch::Sender<f64>,
+    pub(crate) metrics: Arc<Metrics>,
 
     pub(crate) shutdown: Shutdown,
 }
@@ -40,6 +48,8 @@ impl TimingReceiver {
                 }
             };
 
+            let now = Time::now();
+
             // we expect message with exactly 32 bytes
             if length != 32 {
                 continue;
@@ -70,6 +80,20 @@ impl TimingReceiver {
                     };
 
                     trace!("{:?} - {:?}-{:?}-{:?}", seq, origin, receive, transmit);
+
+                    // Responses to requests without a transmit time carry no
+                    // usable origin time.
+                    if origin.is_zero() {
+                        continue;
+                    }
+
+                    let round_trip_time =
+                        now.seconds_since(origin) - transmit.seconds_since(receive);
+                    let clock_offset =
+                        (receive.seconds_since(origin) + transmit.seconds_since(now)) / 2.0;
+                    self.metrics.round_trip_time.set(round_trip_time);
+                    self.metrics.clock_offset.set(clock_offset);
+                    let _ = self.clock_offset.send(clock_offset);
                 }
                 Err(e) => {
                     debug!("{:?}", e);
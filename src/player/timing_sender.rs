@@ -1,11 +1,12 @@
 use super::Command;
-use crate::shutdown::Shutdown;
+use crate::{player::ntp::Time, shutdown::Shutdown};
 use std::{sync::Arc, time::Duration};
 use tokio::{net::UdpSocket, sync::mpsc, time};
 use tracing::instrument;
 
 #[derive(Debug)]
 pub(crate) struct TimingSender {
+    #[allow(dead_code)]
     pub(crate) player_tx: mpsc::Sender<Command>,
     pub(crate) socket: Arc<UdpSocket>,
 
@@ -18,11 +19,13 @@ impl TimingSender {
         while !self.shutdown.is_shutdown() {
             tokio::select! {
                 _ = time::sleep(Duration::from_secs(3)) => {
-                  let message = [0x80, 0xd2, 0x0, 0x07, 0x0, 0x0, 0x0, 0x0,
+                  let mut message = [0x80, 0xd2, 0x0, 0x07, 0x0, 0x0, 0x0, 0x0,
                                     0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
                                     0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
                                     0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0, 0x0,
                                 ];
+                  // The sender echoes the transmit time as origin time of its response.
+                  message[24..32].copy_from_slice(&Time::now().to_be_bytes());
 
                   let _ = self.socket.send(&message).await;
                 },
@@ -0,0 +1,147 @@
+use rodio::Source;
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// AirPlay volume signalling mute.
+const AIRPLAY_MUTE: f64 = -144.0;
+
+/// Lowest AirPlay volume (in dB) which is not muted.
+const AIRPLAY_MIN: f64 = -30.0;
+
+/// Converts an AirPlay volume (`-30.0..=0.0` dB or `-144.0` for mute) to a
+/// linear gain.
+pub(crate) fn airplay_volume_to_gain(volume: f64) -> f32 {
+    if volume <= AIRPLAY_MUTE {
+        0.0
+    } else {
+        10_f64.powf(volume.clamp(AIRPLAY_MIN, 0.0) / 20.0) as f32
+    }
+}
+
+/// Limits applied to the volume of the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct VolumePolicy {
+    /// Gain (`0.0..=1.0`) at full volume.
+    pub(crate) max: f32,
+    /// Play at full volume whatever volume the sender requests.
+    pub(crate) ignore_sender: bool,
+}
+
+impl Default for VolumePolicy {
+    fn default() -> VolumePolicy {
+        VolumePolicy {
+            max: 1.0,
+            ignore_sender: false,
+        }
+    }
+}
+
+/// Gain shared between the `Player` and the `Volume` stage of the output.
+#[derive(Debug, Clone)]
+pub(crate) struct Gain(Arc<AtomicU32>);
+
+impl Gain {
+    pub(crate) fn new(gain: f32) -> Gain {
+        Gain(Arc::new(AtomicU32::new(gain.to_bits())))
+    }
+
+    pub(crate) fn set(&self, gain: f32) {
+        self.0.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+/// Applies a software volume to a source.
+///
+/// Changes of the `Gain` are smoothed over a few milliseconds to avoid
+/// audible clicks.
+pub(crate) struct Volume<I>
+where
+    I: Source<Item = f32>,
+{
+    input: I,
+    gain: Gain,
+    current: f32,
+    /// Fraction of the remaining difference applied per sample.
+    smoothing: f32,
+}
+
+impl<I> Volume<I>
+where
+    I: Source<Item = f32>,
+{
+    /// Builds a new `Volume`.
+    pub(crate) fn new(input: I, gain: Gain) -> Volume<I> {
+        let samples_per_second = input.sample_rate() as f32 * input.channels() as f32;
+
+        Volume {
+            current: gain.get(),
+            input,
+            gain,
+            // reach the target gain within roughly 10 ms
+            smoothing: 1.0 / (0.002 * samples_per_second),
+        }
+    }
+}
+
+impl<I> Source for Volume<I>
+where
+    I: Source<Item = f32>,
+{
+    #[inline]
+    fn current_frame_len(&self) -> Option<usize> {
+        self.input.current_frame_len()
+    }
+
+    #[inline]
+    fn channels(&self) -> u16 {
+        self.input.channels()
+    }
+
+    #[inline]
+    fn sample_rate(&self) -> u32 {
+        self.input.sample_rate()
+    }
+
+    #[inline]
+    fn total_duration(&self) -> Option<Duration> {
+        self.input.total_duration()
+    }
+}
+
+impl<I> Iterator for Volume<I>
+where
+    I: Source<Item = f32>,
+{
+    type Item = f32;
+
+    #[inline]
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.input.next()?;
+
+        let target = self.gain.get();
+        if self.current != target {
+            let difference = target - self.current;
+            if difference.abs() < 1e-6 {
+                self.current = target;
+            } else {
+                self.current += difference * self.smoothing;
+            }
+        }
+
+        Some(sample * self.current)
+    }
+
+    #[inline]
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.input.size_hint()
+    }
+}
@@ -12,6 +12,7 @@ pub(crate) struct RtpInfo {
     /// Sequence number of the first packet that is a direct result of the request.
     pub seq: u16,
     /// RTP timestamp corresponding to the start time.
+    #[allow(dead_code)]
     pub rtptime: u32,
 }
 
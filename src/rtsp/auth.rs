@@ -0,0 +1,62 @@
+use md5::{Digest, Md5};
+use rand::Rng;
+
+/// Realm of the digest authentication, expected by AirPlay senders.
+const REALM: &str = "raop";
+
+/// Random nonce of a connection, senders have to include it in the
+/// `Authorization` header.
+pub(crate) fn nonce() -> String {
+    hex(&rand::thread_rng().gen::<[u8; 16]>())
+}
+
+/// Value of the `WWW-Authenticate` header asking for the password.
+pub(crate) fn challenge(nonce: &str) -> String {
+    format!("Digest realm=\"{}\", nonce=\"{}\"", REALM, nonce)
+}
+
+/// Checks the `Authorization` header of a request with the digest access
+/// authentication of RFC 2617 (without `qop`), the user name is ignored.
+pub(crate) fn verify(authorization: &str, method: &str, nonce: &str, password: &str) -> bool {
+    let params = match authorization.trim().strip_prefix("Digest ") {
+        Some(params) => params,
+        None => return false,
+    };
+
+    let (mut username, mut realm, mut request_nonce, mut uri, mut response) =
+        (None, None, None, None, None);
+    for param in params.split(',') {
+        let (key, value) = match param.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim().trim_matches('"')),
+            None => return false,
+        };
+        match key {
+            "username" => username = Some(value),
+            "realm" => realm = Some(value),
+            "nonce" => request_nonce = Some(value),
+            "uri" => uri = Some(value),
+            "response" => response = Some(value),
+            _ => {}
+        }
+    }
+
+    match (username, realm, request_nonce, uri, response) {
+        (Some(username), Some(realm), Some(request_nonce), Some(uri), Some(response))
+            if request_nonce == nonce =>
+        {
+            let ha1 = md5_hex(&format!("{}:{}:{}", username, realm, password));
+            let ha2 = md5_hex(&format!("{}:{}", method, uri));
+            let expected = md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2));
+            response.eq_ignore_ascii_case(&expected)
+        }
+        _ => false,
+    }
+}
+
+fn md5_hex(input: &str) -> String {
+    hex(&Md5::digest(input.as_bytes()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
@@ -1,4 +1,4 @@
-use crate::result::Result;
+use crate::{network, result::Result};
 use bytes::{Buf, BytesMut};
 use rtsp_types::{Message, ParseError, Response};
 use std::{fmt::Debug, net::SocketAddr};
@@ -38,15 +38,17 @@ impl Connection {
     /// Create a new `Connection`, backed by `socket`. Read and write buffers
     /// are initialized.
     pub fn new(socket: TcpStream) -> Result<Connection> {
-        let local_addr = socket.local_addr()?;
-        let peer_addr = socket.peer_addr()?;
+        // IPv4 connections accepted by dual-stack listeners use IPv4-mapped
+        // IPv6 addresses, the Apple-Challenge needs the plain IPv4 address.
+        let local_addr = network::canonical(socket.local_addr()?);
+        let peer_addr = network::canonical(socket.peer_addr()?);
 
         Ok(Connection {
             stream: BufWriter::new(socket),
             buffer: BytesMut::with_capacity(1024),
 
-            local_addr: local_addr,
-            peer_addr: peer_addr,
+            local_addr,
+            peer_addr,
         })
     }
 
@@ -1,24 +1,33 @@
-use super::{connection::Connection, handler::Handler};
-use crate::{player::Command, shutdown::Shutdown, Configuration};
-use std::{sync::Arc, time::Duration};
+use super::{auth, connection::Connection, handler::Handler};
+use crate::{
+    events::EventBus, metrics::Metrics, player::Command, shutdown::Shutdown, Configuration,
+};
+use std::{future, sync::Arc, task::Poll, time::Duration};
 use tokio::{
     net::{TcpListener, TcpStream},
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
     time,
 };
-use tracing::error;
+use tracing::{error, info_span, Instrument};
 
 #[derive(Debug)]
 pub(crate) struct Listener {
-    /// App configuration.
-    pub(crate) config: Arc<Configuration>,
+    /// App configuration, every connection keeps the one current when it got
+    /// accepted.
+    pub(crate) config: watch::Receiver<Arc<Configuration>>,
 
-    /// TCP listener supplied by the `run` caller.
-    pub(crate) listener: TcpListener,
+    /// TCP listeners supplied by the `run` caller, one per listening address.
+    pub(crate) listeners: Vec<TcpListener>,
 
     /// Used to control our Player
     pub(crate) player_tx: mpsc::Sender<Command>,
 
+    /// Handed to every connection handler.
+    pub(crate) events: EventBus,
+
+    /// Handed to every connection handler.
+    pub(crate) metrics: Arc<Metrics>,
+
     /// Broadcasts a shutdown signal to all active connections.
     ///
     /// The initial `shutdown` trigger is provided by the `run` caller. The
@@ -62,21 +71,36 @@ impl Listener {
     /// itself. One strategy for handling this is to implement a back off
     /// strategy, which is what we do here.
     pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        let mut next_id = 0;
+
         loop {
             // Accept a new socket. This will attempt to perform error handling.
             // The `accept` method internally attempts to recover errors, so an
             // error here is non-recoverable.
             let socket = self.accept().await?;
 
+            let (disconnect_tx, disconnect_rx) = mpsc::channel(1);
+            next_id += 1;
+
             // Create the necessary per-connection handler state.
             let mut handler = Handler {
-                config: self.config.clone(),
+                config: self.config.borrow().clone(),
+
+                id: next_id,
+
+                nonce: auth::nonce(),
 
                 // Initialize the connection state.
                 connection: Connection::new(socket)?,
 
                 player_tx: self.player_tx.clone(),
 
+                events: self.events.clone(),
+                metrics: self.metrics.clone(),
+
+                disconnect_tx,
+                disconnect_rx,
+
                 // Receive shutdown notifications.
                 shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
 
@@ -84,18 +108,29 @@ impl Listener {
                 _shutdown_complete: self.shutdown_complete_tx.clone(),
             };
 
+            // Everything logged on behalf of the connection, including its UDP
+            // tasks, carries the session id and client address.
+            let span = info_span!(
+                "session",
+                session_id = next_id,
+                client = %handler.connection.peer_addr
+            );
+
             // Spawn a new task to process the connections. Tokio tasks are like
             // asynchronous green threads and are executed concurrently.
-            tokio::spawn(async move {
-                // Process the connection. If an error is encountered, log it.
-                if let Err(err) = handler.run().await {
-                    error!(cause = ?err, "connection error");
+            tokio::spawn(
+                async move {
+                    // Process the connection. If an error is encountered, log it.
+                    if let Err(err) = handler.run().await {
+                        error!(cause = ?err, "connection error");
+                    }
                 }
-            });
+                .instrument(span),
+            );
         }
     }
 
-    /// Accept an inbound connection.
+    /// Accept an inbound connection on any of the listeners.
     ///
     /// Errors are handled by backing off and retrying. An exponential backoff
     /// strategy is used. After the first failure, the task waits for 1 second.
@@ -109,7 +144,14 @@ impl Listener {
         loop {
             // Perform the accept operation. If a socket is successfully
             // accepted, return it. Otherwise, save the error.
-            match self.listener.accept().await {
+            let accept = future::poll_fn(|cx| {
+                self.listeners
+                    .iter()
+                    .map(|listener| listener.poll_accept(cx))
+                    .find(Poll::is_ready)
+                    .unwrap_or(Poll::Pending)
+            });
+            match accept.await {
                 Ok((socket, _)) => return Ok(socket),
                 Err(err) => {
                     if backoff > 16 {
@@ -1,3 +1,4 @@
+mod auth;
 mod connection;
 mod handler;
 pub(crate) mod listener;
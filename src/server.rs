@@ -1,48 +1,356 @@
 use crate::{
-    mdns::Mdns, player::Player, rtsp::listener::Listener, shutdown::Shutdown, Configuration,
+    dacp::{Dacp, DacpCommand},
+    events::EventBus,
+    hooks::Hooks,
+    http::Http,
+    mdns,
+    mdns::Mdns,
+    metrics::Metrics,
+    mpris::Mpris,
+    mqtt::Mqtt,
+    now_playing::NowPlaying,
+    player::Player,
+    rtsp::listener::Listener,
+    shutdown::Shutdown,
+    stats::Stats,
+    systemd::Systemd,
+    Configuration,
 };
-use std::{future::Future, sync::Arc};
+use futures_util::future;
+use mdns_sd::{IfKind, ServiceDaemon};
+use std::{future::Future, net::SocketAddr, sync::Arc};
 use tokio::{
     net::TcpListener,
-    sync::{broadcast, mpsc},
+    sync::{broadcast, mpsc, watch},
 };
-use tracing::{error, info};
+use tracing::{error, info, info_span, Instrument, Span};
+
+/// Runs a server for each receiver with its configuration and listeners until
+/// the `shutdown` signal is received or one of them stops, shutting down all of
+/// them together. systemd is told once all of them opened their output, or when
+/// stopping.
+pub(crate) async fn run_receivers(
+    receivers: Vec<(watch::Receiver<Arc<Configuration>>, Vec<TcpListener>)>,
+    systemd: &Systemd,
+    shutdown: impl Future,
+) -> crate::result::Result<()> {
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (ready_tx, mut ready_rx) = mpsc::unbounded_channel();
+    let count = receivers.len();
+    // Only tell the logs of several receivers apart.
+    let several = count > 1;
+
+    // A single mDNS daemon announces the receivers and browses for other
+    // players and for the DACP servers of senders.
+    let announce = receivers
+        .iter()
+        .any(|(config_rx, _)| config_rx.borrow().mdns);
+    let browse_dacp = receivers.iter().any(|(config_rx, _)| {
+        let config = config_rx.borrow();
+        config.mdns && (config.http_address.is_some() || config.mpris || config.mqtt.is_some())
+    });
+    let daemon = match announce {
+        true => Some(ServiceDaemon::new()?),
+        false => None,
+    };
+
+    // All receivers are announced together.
+    let mut mdns = Mdns {
+        receivers: Vec::new(),
+        addresses: Vec::new(),
+        daemon: daemon.clone(),
+        shutdown: Shutdown::new(notify_shutdown.subscribe()),
+    };
+    let mut all_addresses = false;
+
+    // Senders of all receivers are remote controlled together.
+    let (dacp_tx, dacp_rx) = mpsc::channel(4);
+    let mut dacp = Dacp {
+        dacp_rx,
+        daemon: daemon.clone().filter(|_| browse_dacp),
+        shutdown: Shutdown::new(notify_shutdown.subscribe()),
+    };
+
+    let mut servers = Vec::new();
+    for (index, (config_rx, listeners)) in receivers.into_iter().enumerate() {
+        let local_addrs = listeners
+            .iter()
+            .map(TcpListener::local_addr)
+            .collect::<Result<Vec<_>, _>>()?;
+        // All listeners share the same port.
+        let port = local_addrs.first().ok_or("no address to listen on")?.port();
+        // Only the addresses listened on get announced, unless listening on all.
+        all_addresses |= local_addrs.iter().any(|addr| addr.ip().is_unspecified());
+        mdns.addresses
+            .extend(local_addrs.iter().map(SocketAddr::ip));
+
+        let (name_tx, name_rx) = watch::channel(config_rx.borrow().name.clone());
+        mdns.receivers.push(mdns::Receiver {
+            config: config_rx.clone(),
+            name_tx,
+            port,
+        });
+
+        let span = match several {
+            true => info_span!("receiver", name = %config_rx.borrow().name),
+            false => Span::none(),
+        };
+        let mut receiver_shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let notify_shutdown = notify_shutdown.clone();
+        let ready_tx = ready_tx.clone();
+        let dacp_tx = dacp_tx.clone();
+        servers.push(
+            async move {
+                let res = run(
+                    config_rx,
+                    listeners,
+                    name_rx,
+                    several.then_some(index),
+                    dacp_tx,
+                    ready_tx,
+                    async move { receiver_shutdown.recv().await },
+                )
+                .await;
+                // The other receivers stop as well.
+                let _ = notify_shutdown.send(());
+                res
+            }
+            .instrument(span),
+        );
+    }
+    drop(ready_tx);
+    drop(dacp_tx);
+    match all_addresses {
+        true => mdns.addresses.clear(),
+        false => {
+            mdns.addresses.sort();
+            mdns.addresses.dedup();
+            // Only answer on the interfaces listened on.
+            if let Some(ref daemon) = daemon {
+                let interfaces = mdns.addresses.iter().copied().map(IfKind::Addr);
+                daemon.disable_interface(IfKind::All)?;
+                daemon.enable_interface(interfaces.collect::<Vec<_>>())?;
+            }
+        }
+    }
+
+    let mdns = async {
+        // If an error is received here, something happend while sending mdns
+        // advertisements.
+        if let Err(err) = mdns.run().await {
+            error!(cause = %err, "mdns failed");
+        }
+        let _ = notify_shutdown.send(());
+    };
+    let dacp = async {
+        if let Err(err) = dacp.run().await {
+            error!(cause = %err, "dacp failed");
+        }
+    };
+    let servers = future::join3(future::join_all(servers), mdns, dacp);
+    tokio::pin!(servers);
+    let ready = async {
+        for _ in 0..count {
+            // A receiver failed to start, the others are stopped.
+            if ready_rx.recv().await.is_none() {
+                return;
+            }
+        }
+        systemd.notify("READY=1");
+    };
+    // Neither the watchdog nor being ready complete the future, it waits until
+    // shutting down, or one of the receivers stopping.
+    let mut stopping = notify_shutdown.subscribe();
+    let shutdown = async {
+        tokio::select! {
+            _ = shutdown => {}
+            _ = stopping.recv() => {}
+            _ = systemd.watchdog() => {}
+            _ = async { ready.await; future::pending().await } => {}
+        }
+    };
+
+    let (results, (), ()) = tokio::select! {
+        results = &mut servers => {
+            systemd.notify("STOPPING=1");
+            results
+        }
+        _ = shutdown => {
+            systemd.notify("STOPPING=1");
+            let _ = notify_shutdown.send(());
+            servers.await
+        }
+    };
+    if let Some(daemon) = daemon {
+        let _ = daemon.shutdown();
+    }
+    results.into_iter().collect()
+}
 
 pub(crate) async fn run(
-    config: Arc<Configuration>,
-    listener: TcpListener,
+    config_rx: watch::Receiver<Arc<Configuration>>,
+    listeners: Vec<TcpListener>,
+    name_rx: watch::Receiver<String>,
+    instance: Option<usize>,
+    dacp_tx: mpsc::Sender<DacpCommand>,
+    ready: mpsc::UnboundedSender<()>,
     shutdown: impl Future,
 ) -> crate::result::Result<()> {
-    let local_addr = listener.local_addr()?;
+    let local_addrs = listeners
+        .iter()
+        .map(TcpListener::local_addr)
+        .collect::<Result<Vec<_>, _>>()?;
+    // Settings used below require a restart to change.
+    let config = config_rx.borrow().clone();
 
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
     let (player_tx, player_rx) = mpsc::channel(4);
+    let events = EventBus::new();
+    let metrics = Arc::new(Metrics::new());
 
-    let mut mdns = Mdns {
-        config: config.clone(),
-        port: local_addr.port(),
+    let mut player = Player {
+        config: config_rx.clone(),
+        name: name_rx,
+        events: events.clone(),
+        metrics: metrics.clone(),
+        dacp_tx,
+        player_tx: player_tx.clone(),
+        player_rx,
+        ready,
         shutdown: Shutdown::new(notify_shutdown.subscribe()),
         _shutdown_complete: shutdown_complete_tx.clone(),
     };
 
-    let mut player = Player {
-        player_tx: player_tx.clone(),
-        player_rx: player_rx,
+    let http = match config.http_address {
+        Some(addr) => Some(Http {
+            listener: TcpListener::bind(addr).await?,
+            player_tx: player_tx.clone(),
+            events: events.clone(),
+            metrics: metrics.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx.clone(),
+        }),
+        None => None,
+    };
+
+    #[cfg(unix)]
+    if let Some(ref path) = config.metadata_pipe {
+        let mut metadata_pipe = crate::metadata_pipe::MetadataPipe {
+            path: path.clone(),
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx.clone(),
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = metadata_pipe.run().await {
+                error!(cause = %err, "metadata pipe failed");
+            }
+        });
+    }
+
+    if config.mpris {
+        let mut mpris = Mpris {
+            identity: config.name.clone(),
+            instance,
+            player_tx: player_tx.clone(),
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx.clone(),
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = mpris.run().await {
+                error!(cause = %err, "mpris failed");
+            }
+        });
+    }
+
+    if let Some(ref path) = config.now_playing_file {
+        let mut now_playing = NowPlaying {
+            path: path.clone(),
+            player_tx: player_tx.clone(),
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx.clone(),
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = now_playing.run().await {
+                error!(cause = %err, "now playing file failed");
+            }
+        });
+    }
+
+    let mut hooks = Hooks {
+        config: config_rx.clone(),
+        events: events.clone(),
         shutdown: Shutdown::new(notify_shutdown.subscribe()),
         _shutdown_complete: shutdown_complete_tx.clone(),
     };
 
+    tokio::spawn(async move {
+        if let Err(err) = hooks.run().await {
+            error!(cause = %err, "hooks failed");
+        }
+    });
+
+    if let Some(interval) = config.stats_interval {
+        let mut stats = Stats {
+            interval,
+            metrics: metrics.clone(),
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx.clone(),
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = stats.run().await {
+                error!(cause = %err, "stats failed");
+            }
+        });
+    }
+
+    if let Some(ref mqtt_config) = config.mqtt {
+        let mut mqtt = Mqtt {
+            config: mqtt_config.clone(),
+            player_tx: player_tx.clone(),
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx.clone(),
+        };
+
+        tokio::spawn(async move {
+            if let Err(err) = mqtt.run().await {
+                error!(cause = %err, "mqtt failed");
+            }
+        });
+    }
+
     let mut server = Listener {
-        config: config.clone(),
-        listener,
+        config: config_rx,
+        listeners,
         player_tx: player_tx.clone(),
+        events,
+        metrics,
         notify_shutdown,
         shutdown_complete_tx,
         shutdown_complete_rx,
     };
 
-    info!("Lets rock on {}!", local_addr);
+    for local_addr in local_addrs {
+        info!("Lets rock on {}!", local_addr);
+    }
+
+    if let Some(http) = http {
+        info!("HTTP API listening on {}", http.listener.local_addr()?);
+        tokio::spawn(async move {
+            if let Err(err) = http.run().await {
+                error!(cause = %err, "http api failed");
+            }
+        });
+    }
 
     tokio::select! {
       res = server.run() => {
@@ -56,13 +364,6 @@ pub(crate) async fn run(
             error!(cause = %err, "failed to accept");
           }
       },
-      res = mdns.run() => {
-        // If an error is received here, something happend while sending
-        // mdns advertisements
-        if let Err(err) = res {
-          error!(cause = %err, "mdns failed");
-        }
-      },
       res = player.run() => {
         // If an error is received here, something happend while playing
         if let Err(err) = res {
@@ -85,9 +386,8 @@ pub(crate) async fn run(
         ..
     } = server;
 
-    // Explicitly drop Mdns and Player allowing a clean exit.
+    // Explicitly drop Player allowing a clean exit.
     drop(player);
-    drop(mdns);
 
     // When `notify_shutdown` is dropped, all tasks which have `subscribe`d will
     // receive the shutdown signal and can exit
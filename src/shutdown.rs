@@ -2,9 +2,8 @@ use tokio::sync::broadcast;
 
 /// Listens for the server shutdown signal.
 ///
-/// Shutdown is signalled using a `broadcast::Receiver`. Only a single value is
-/// ever sent. Once a value has been sent via the broadcast channel, the server
-/// should shutdown.
+/// Shutdown is signalled using a `broadcast::Receiver`. Once a value has been
+/// sent via the broadcast channel, the server should shutdown.
 ///
 /// The `Shutdown` struct listens for the signal and tracks that the signal has
 /// been received. Callers may query for whether the shutdown signal has been
@@ -40,7 +39,8 @@ impl Shutdown {
             return;
         }
 
-        // Cannot receive a "lag error" as only one value is ever sent.
+        // A "lag error" means that values have been sent as well, e.g. by
+        // several stopping receivers.
         let _ = self.notify.recv().await;
 
         // Remember that the signal has been received.
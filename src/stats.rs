@@ -0,0 +1,295 @@
+use crate::{
+    events::{Event, EventBus},
+    metrics::Metrics,
+    shutdown::Shutdown,
+};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc},
+    time::{self, MissedTickBehavior},
+};
+use tracing::info;
+
+/// Target of the logged statistics, enabled whatever the log level.
+pub(crate) const LOG_TARGET: &str = "airguitar::stats";
+
+/// Logs playback statistics periodically while a session is active and a
+/// summary when it ends.
+///
+/// Counts are taken from `Metrics`, relative to the start of the session.
+pub(crate) struct Stats {
+    /// Time between two statistics lines.
+    pub(crate) interval: Duration,
+
+    /// Source of the reported numbers.
+    pub(crate) metrics: Arc<Metrics>,
+
+    /// Tells when sessions start and end.
+    pub(crate) events: EventBus,
+
+    /// Listen for shutdown notifications.
+    pub(crate) shutdown: Shutdown,
+
+    /// Not used directly. Instead, when `Stats` is dropped...
+    pub(crate) _shutdown_complete: mpsc::Sender<()>,
+}
+
+struct Session {
+    /// Logged like the span of the session's RTSP connection.
+    id: u64,
+    client: SocketAddr,
+    started: Instant,
+    /// Counters at the start of the session.
+    baseline: Counts,
+    /// Sync error at the last report, used to estimate the drift.
+    last_sync_error: Option<(f64, Instant)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Counts {
+    received: u64,
+    missing: u64,
+    resent: u64,
+    late: u64,
+    lost: u64,
+    underruns: u64,
+}
+
+impl Counts {
+    fn new(metrics: &Metrics) -> Counts {
+        Counts {
+            received: metrics.packets_received.get(),
+            missing: metrics.resend_requested_packets.get(),
+            resent: metrics.resend_fulfilled_packets.get(),
+            late: metrics.packets_late.get(),
+            lost: metrics.packets_lost.get(),
+            underruns: metrics.underruns.get(),
+        }
+    }
+
+    fn since(self, baseline: Counts) -> Counts {
+        Counts {
+            received: self.received - baseline.received,
+            missing: self.missing - baseline.missing,
+            resent: self.resent - baseline.resent,
+            late: self.late - baseline.late,
+            lost: self.lost - baseline.lost,
+            underruns: self.underruns - baseline.underruns,
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) async fn run(&mut self) -> crate::result::Result<()> {
+        let (_, mut rx) = self.events.subscribe(None);
+        let mut session: Option<Session> = None;
+
+        let mut interval = time::interval(self.interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(notification) => match notification.event {
+                        Event::SessionStarted { session_id, client, .. } => {
+                            if let Some(ended) = session.take() {
+                                self.summary(&ended);
+                            }
+                            session = Some(Session {
+                                id: session_id,
+                                client,
+                                started: Instant::now(),
+                                baseline: Counts::new(&self.metrics),
+                                last_sync_error: None,
+                            });
+                            interval.reset();
+                        }
+                        Event::SessionEnded { .. } => {
+                            if let Some(ended) = session.take() {
+                                self.summary(&ended);
+                            }
+                        }
+                        // Playback restarts at a new position.
+                        Event::Flush { .. } => {
+                            if let Some(ref mut session) = session {
+                                session.last_sync_error = None;
+                            }
+                        }
+                        _ => {}
+                    },
+                    Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => break,
+                },
+                _ = interval.tick() => {
+                    if let Some(ref mut session) = session {
+                        self.report(session);
+                    }
+                }
+                _ = self.shutdown.recv() => break,
+            }
+        }
+
+        if let Some(ended) = session.take() {
+            self.summary(&ended);
+        }
+
+        Ok(())
+    }
+
+    fn report(&self, session: &mut Session) {
+        let counts = Counts::new(&self.metrics).since(session.baseline);
+        let sync_error = self.metrics.sync_error.get();
+        let now = Instant::now();
+
+        // Change of the sync error since the last report in parts per million.
+        let drift = match session.last_sync_error {
+            Some((last, at)) => (sync_error - last) / (now - at).as_secs_f64() * 1e6,
+            None => f64::NAN,
+        };
+        session.last_sync_error = Some((sync_error, now)).filter(|_| !sync_error.is_nan());
+
+        info!(
+            target: LOG_TARGET,
+            session_id = session.id,
+            client = %session.client,
+            sync_error_ms = %format!("{:.1}", sync_error * 1e3),
+            drift_ppm = %format!("{:.1}", drift),
+            buffered_packets = self.metrics.buffered_packets.get(),
+            received = counts.received,
+            missing = counts.missing,
+            resent = counts.resent,
+            late = counts.late,
+            lost = counts.lost,
+            underruns = counts.underruns,
+            "playback statistics"
+        );
+    }
+
+    fn summary(&self, session: &Session) {
+        let counts = Counts::new(&self.metrics).since(session.baseline);
+
+        info!(
+            target: LOG_TARGET,
+            session_id = session.id,
+            client = %session.client,
+            duration_secs = session.started.elapsed().as_secs(),
+            received = counts.received,
+            missing = counts.missing,
+            resent = counts.resent,
+            late = counts.late,
+            lost = counts.lost,
+            underruns = counts.underruns,
+            "session ended"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::{io, sync::Mutex};
+    use tokio::sync::broadcast;
+
+    /// Collects the log lines written by the test subscriber.
+    #[derive(Clone, Default)]
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Capture {
+        /// Fields of the logged lines with the given message.
+        fn lines(&self, message: &str) -> Vec<Value> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<Value>(line).unwrap()["fields"].clone())
+                .filter(|fields| fields["message"] == message)
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_statistics_and_a_summary() {
+        let capture = Capture::default();
+        let writer = capture.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+        // The test runtime runs all tasks on this thread.
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let metrics = Arc::new(Metrics::new());
+        let events = EventBus::new();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _shutdown_complete_rx) = mpsc::channel(1);
+        let mut stats = Stats {
+            interval: Duration::from_millis(50),
+            metrics: metrics.clone(),
+            events: events.clone(),
+            shutdown: Shutdown::new(notify_shutdown.subscribe()),
+            _shutdown_complete: shutdown_complete_tx,
+        };
+        let task = tokio::spawn(async move { stats.run().await });
+        time::sleep(Duration::from_millis(10)).await;
+
+        // Counted before the session, not part of its statistics.
+        metrics.packets_received.add(5);
+        let client = "192.168.1.10:50000".parse().unwrap();
+        events.publish(Event::SessionStarted {
+            session_id: 7,
+            client,
+            client_name: None,
+            user_agent: None,
+            codec: "AppleLossless".into(),
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        metrics.packets_received.add(10);
+        metrics.packets_lost.add(2);
+        metrics.sync_error.set(0.0015);
+        time::sleep(Duration::from_millis(120)).await;
+
+        let reports = capture.lines("playback statistics");
+        assert!(!reports.is_empty());
+        assert_eq!(reports[0]["session_id"], 7);
+        assert_eq!(reports[0]["client"], "192.168.1.10:50000");
+        assert_eq!(reports[0]["received"], 10);
+        assert_eq!(reports[0]["lost"], 2);
+        assert_eq!(reports[0]["sync_error_ms"], "1.5");
+        assert!(capture.lines("session ended").is_empty());
+
+        events.publish(Event::SessionEnded { client });
+        time::sleep(Duration::from_millis(10)).await;
+
+        let summaries = capture.lines("session ended");
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0]["session_id"], 7);
+        assert_eq!(summaries[0]["client"], "192.168.1.10:50000");
+        assert_eq!(summaries[0]["received"], 10);
+        assert_eq!(summaries[0]["lost"], 2);
+
+        // No statistics without a session.
+        let reported = capture.lines("playback statistics").len();
+        time::sleep(Duration::from_millis(120)).await;
+        assert_eq!(capture.lines("playback statistics").len(), reported);
+
+        notify_shutdown.send(()).unwrap();
+        task.await.unwrap().unwrap();
+        assert_eq!(capture.lines("session ended").len(), 1);
+    }
+}
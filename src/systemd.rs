@@ -0,0 +1,226 @@
+use std::{env, ffi::OsString, future, process, time::Duration};
+use tokio::{net::TcpListener, time};
+use tracing::warn;
+
+/// The first passed socket, see `SD_LISTEN_FDS_START`.
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+/// Variables systemd passes to the service, child processes like hooks must
+/// not act on them as well.
+const VARS: [&str; 6] = [
+    "LISTEN_PID",
+    "LISTEN_FDS",
+    "LISTEN_FDNAMES",
+    "NOTIFY_SOCKET",
+    "WATCHDOG_PID",
+    "WATCHDOG_USEC",
+];
+
+/// What systemd asked for in the environment: sockets to take over, where to
+/// send state changes and how often to keep the watchdog happy.
+#[derive(Debug)]
+pub(crate) struct Systemd {
+    /// Sockets passed by a systemd socket unit (see `sd_listen_fds(3)`).
+    listen_fds: Vec<i32>,
+
+    /// Where to send state changes to (see `sd_notify(3)`).
+    notify_socket: Option<OsString>,
+
+    /// Timeout of the watchdog.
+    watchdog_timeout: Option<Duration>,
+}
+
+impl Systemd {
+    /// Reads and clears the variables passed by systemd. Modifying the
+    /// environment is not thread-safe, this has to happen before starting the
+    /// runtime.
+    pub(crate) fn from_env() -> Systemd {
+        let systemd = Systemd::from_vars(|var| env::var_os(var));
+        for var in VARS {
+            env::remove_var(var);
+        }
+        systemd
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<OsString>) -> Systemd {
+        let string = |name| var(name).and_then(|value| value.into_string().ok());
+        // Variables meant for a parent process are ignored.
+        let for_us = |name| {
+            string(name)
+                .map(|pid| pid.parse() == Ok(process::id()))
+                .unwrap_or(true)
+        };
+
+        let count = string("LISTEN_FDS")
+            .and_then(|count| count.parse::<i32>().ok())
+            .filter(|_| string("LISTEN_PID").is_some() && for_us("LISTEN_PID"))
+            .unwrap_or(0);
+        #[cfg(unix)]
+        let listen_fds = (LISTEN_FDS_START..LISTEN_FDS_START + count).collect();
+        #[cfg(not(unix))]
+        let listen_fds = {
+            let _ = count;
+            Vec::new()
+        };
+
+        let watchdog_timeout = string("WATCHDOG_USEC")
+            .and_then(|usec| usec.parse().ok())
+            .filter(|usec| *usec > 0 && for_us("WATCHDOG_PID"))
+            .map(Duration::from_micros);
+
+        Systemd {
+            listen_fds,
+            notify_socket: var("NOTIFY_SOCKET"),
+            watchdog_timeout,
+        }
+    }
+
+    /// Takes over the listening sockets passed by a systemd socket unit, none
+    /// without socket activation.
+    #[cfg(unix)]
+    pub(crate) fn listeners(&mut self) -> crate::result::Result<Vec<TcpListener>> {
+        use socket2::{Socket, Type};
+        use std::os::unix::io::FromRawFd;
+
+        std::mem::take(&mut self.listen_fds)
+            .into_iter()
+            .map(|fd| {
+                // SAFETY: systemd passes the sockets as open file descriptors
+                // starting at `LISTEN_FDS_START` and nothing else refers to
+                // them, taking them only once makes this process their sole
+                // owner.
+                let socket = unsafe { Socket::from_raw_fd(fd) };
+                if socket.r#type()? != Type::STREAM {
+                    return Err(
+                        format!("socket {} passed by systemd is not a TCP socket", fd).into(),
+                    );
+                }
+                socket.set_cloexec(true)?;
+                socket.set_nonblocking(true)?;
+                Ok(TcpListener::from_std(socket.into())?)
+            })
+            .collect()
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn listeners(&mut self) -> crate::result::Result<Vec<TcpListener>> {
+        Ok(Vec::new())
+    }
+
+    /// Sends a state change (e.g. `READY=1`) to systemd if it asked for it.
+    pub(crate) fn notify(&self, state: &str) {
+        if let Some(ref path) = self.notify_socket {
+            if let Err(err) = send(path, state) {
+                warn!(cause = %err, state, "failed to notify systemd");
+            }
+        }
+    }
+
+    /// Keeps the systemd watchdog happy by notifying it at half its timeout,
+    /// never completes.
+    pub(crate) async fn watchdog(&self) {
+        let timeout = match self.watchdog_timeout {
+            Some(timeout) => timeout,
+            None => return future::pending().await,
+        };
+
+        let mut interval = time::interval(timeout / 2);
+        loop {
+            interval.tick().await;
+            self.notify("WATCHDOG=1");
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &OsString, state: &str) -> std::io::Result<()> {
+    use std::os::unix::{ffi::OsStrExt, net::UnixDatagram};
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // Socket in the abstract namespace.
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send(_path: &OsString, _state: &str) -> std::io::Result<()> {
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, os::unix::net::UnixDatagram};
+
+    /// Reads the variables of a systemd service from `vars`.
+    fn from_vars(vars: &[(&str, String)]) -> Systemd {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect::<HashMap<_, _>>();
+        Systemd::from_vars(|name| vars.get(name).cloned())
+    }
+
+    #[test]
+    fn reads_the_variables() {
+        let pid = process::id().to_string();
+        let systemd = from_vars(&[
+            ("LISTEN_PID", pid.clone()),
+            ("LISTEN_FDS", "2".into()),
+            ("WATCHDOG_PID", pid),
+            ("WATCHDOG_USEC", "30000000".into()),
+        ]);
+        assert_eq!(systemd.listen_fds, [3, 4]);
+        assert_eq!(systemd.watchdog_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(systemd.notify_socket, None);
+
+        // Meant for another process.
+        let systemd = from_vars(&[
+            ("LISTEN_PID", "1".into()),
+            ("LISTEN_FDS", "2".into()),
+            ("WATCHDOG_PID", "1".into()),
+            ("WATCHDOG_USEC", "30000000".into()),
+        ]);
+        assert!(systemd.listen_fds.is_empty());
+        assert_eq!(systemd.watchdog_timeout, None);
+    }
+
+    #[test]
+    fn notifies_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notify");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        let systemd = from_vars(&[("NOTIFY_SOCKET", path.display().to_string())]);
+        systemd.notify("READY=1");
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn notifies_an_abstract_socket() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("airguitar-test-{}", process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+
+        let systemd = from_vars(&[("NOTIFY_SOCKET", format!("@{}", name))]);
+        systemd.notify("STOPPING=1");
+        let mut buf = [0; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"STOPPING=1");
+    }
+}